@@ -1,16 +1,59 @@
+CREATE TYPE "CalendarKind" AS ENUM ('CalDav', 'Ics', 'File', 'Graph');
+
+CREATE TYPE "OutsideHours" AS ENUM ('Send', 'Defer', 'Drop');
+
+
 CREATE TABLE calendars (
     calendar_id BIGSERIAL PRIMARY KEY,
     user_id bigint NOT NULL,
     name TEXT NOT NULL,
+    kind "CalendarKind" NOT NULL DEFAULT 'CalDav',
     url text NOT NULL,
     user_name text,
-    password text
+    password text,
+    sync_token text,
+    timezone text,
+    look_ahead_days bigint,
+    poll_interval_minutes bigint,
+    mirror_alarms boolean NOT NULL DEFAULT false,
+    alarm_room text,
+    alarm_outside_hours "OutsideHours" NOT NULL DEFAULT 'Send',
+    dedup_same_time_of_day boolean NOT NULL DEFAULT false,
+    dedup_same_weekday boolean NOT NULL DEFAULT false
+);
+
+
+CREATE TABLE calendar_resources (
+    calendar_id bigint NOT NULL,
+    href text NOT NULL,
+    etag text,
+    last_modified text,
+    calendar_data text NOT NULL
 );
 
+CREATE UNIQUE INDEX ON calendar_resources USING btree (calendar_id, href);
+
+
+CREATE TABLE calendar_status (
+    calendar_id bigint PRIMARY KEY,
+    last_attempt timestamp with time zone NOT NULL,
+    last_success timestamp with time zone,
+    http_status integer,
+    error text,
+    event_count bigint,
+    consecutive_failures bigint NOT NULL DEFAULT 0
+);
+
+
+CREATE TYPE "ParticipationStatus" AS ENUM ('NeedsAction', 'Accepted', 'Declined', 'Tentative', 'Delegated');
+
+CREATE TYPE "ParticipantRole" AS ENUM ('Chair', 'Required', 'Optional', 'NonParticipant');
 
 CREATE TYPE "Attendee" AS (
     email TEXT,
-    common_name TEXT
+    common_name TEXT,
+    status "ParticipationStatus",
+    role "ParticipantRole"
 );
 
 
@@ -20,8 +63,16 @@ CREATE TABLE events (
     summary text,
     description text,
     location text,
+    description_html text,
+    description_from_html boolean NOT NULL DEFAULT false,
     organizer "Attendee",
-    attendees "Attendee"[] NOT NULL
+    attendees "Attendee"[] NOT NULL,
+    all_day boolean NOT NULL DEFAULT false,
+    etag text,
+    join_url text,
+    categories text[] NOT NULL DEFAULT '{}',
+    raw_ics text,
+    schema_version integer NOT NULL DEFAULT 0
 );
 
 CREATE UNIQUE INDEX ON events USING btree (calendar_id, event_id);
@@ -31,12 +82,18 @@ CREATE TABLE next_dates (
     calendar_id bigint NOT NULL,
     event_id text NOT NULL,
     "timestamp" timestamp with time zone NOT NULL,
-    attendees "Attendee"[] NOT NULL
+    end_timestamp timestamp with time zone,
+    attendees "Attendee"[] NOT NULL,
+    cancelled boolean NOT NULL DEFAULT false,
+    join_url text
 );
 
 CREATE INDEX ON next_dates USING btree (calendar_id, event_id);
 
 
+CREATE TYPE "ReminderAnchor" AS ENUM ('Start', 'End');
+
+
 CREATE TABLE reminders (
     reminder_id BIGSERIAL PRIMARY KEY,
     user_id bigint NOT NULL,
@@ -44,11 +101,103 @@ CREATE TABLE reminders (
     event_id text NOT NULL,
     room text NOT NULL,
     minutes_before bigint NOT NULL,
+    anchor "ReminderAnchor" NOT NULL DEFAULT 'Start',
     template text,
-    attendee_editable boolean NOT NULL
+    attendee_editable boolean NOT NULL,
+    time_of_day time,
+    days_before bigint NOT NULL DEFAULT 0,
+    from_alarm boolean NOT NULL DEFAULT false,
+    rule_id bigint,
+    outside_hours "OutsideHours" NOT NULL DEFAULT 'Send'
 );
 
 CREATE INDEX ON reminders(event_id);
+CREATE UNIQUE INDEX ON reminders(rule_id, event_id) WHERE rule_id IS NOT NULL;
+
+
+CREATE TABLE reminder_offsets (
+    reminder_id bigint NOT NULL REFERENCES reminders ON DELETE CASCADE,
+    minutes_before bigint NOT NULL,
+    anchor "ReminderAnchor" NOT NULL DEFAULT 'Start',
+    template text
+);
+
+CREATE UNIQUE INDEX ON reminder_offsets(reminder_id, anchor, minutes_before);
+
+
+CREATE TABLE reminder_overrides (
+    reminder_id bigint NOT NULL REFERENCES reminders ON DELETE CASCADE,
+    instance_timestamp timestamp with time zone NOT NULL,
+    skip boolean NOT NULL DEFAULT false,
+    room text,
+    minutes_before bigint,
+    PRIMARY KEY (reminder_id, instance_timestamp)
+);
+
+
+CREATE TYPE "SendStatus" AS ENUM ('Sending', 'Sent', 'Failed', 'Interrupted');
+
+CREATE TABLE sent_reminders (
+    reminder_id bigint NOT NULL REFERENCES reminders ON DELETE CASCADE,
+    instance_timestamp timestamp with time zone NOT NULL,
+    is_primary boolean NOT NULL DEFAULT true,
+    minutes_before bigint NOT NULL,
+    anchor "ReminderAnchor" NOT NULL DEFAULT 'Start',
+    status "SendStatus" NOT NULL,
+    claimed_at timestamp with time zone NOT NULL DEFAULT now(),
+    finished_at timestamp with time zone,
+    error text
+);
+
+-- A reminder's own time is only sent once per instance, even if its offset is
+-- changed or overridden afterwards, while each of its additional times is
+-- recorded separately.
+CREATE UNIQUE INDEX ON sent_reminders(reminder_id, instance_timestamp) WHERE is_primary;
+CREATE UNIQUE INDEX ON sent_reminders(reminder_id, instance_timestamp, anchor, minutes_before)
+    WHERE NOT is_primary;
+
+
+CREATE TABLE reminder_rules (
+    rule_id BIGSERIAL PRIMARY KEY,
+    user_id bigint NOT NULL,
+    calendar_id bigint NOT NULL,
+    summary_regex text,
+    organizer text,
+    attendee text,
+    category text,
+    location text,
+    room text NOT NULL,
+    minutes_before bigint NOT NULL,
+    anchor "ReminderAnchor" NOT NULL DEFAULT 'Start',
+    template text,
+    outside_hours "OutsideHours" NOT NULL DEFAULT 'Send'
+);
+
+CREATE INDEX ON reminder_rules(calendar_id);
+
+
+CREATE TABLE reminder_ports (
+    port_id BIGSERIAL PRIMARY KEY,
+    calendar_id bigint NOT NULL,
+    from_event_id text NOT NULL,
+    to_event_id text NOT NULL,
+    source_reminder_id bigint NOT NULL,
+    reminder_id bigint NOT NULL,
+    ported_at timestamp with time zone NOT NULL DEFAULT now(),
+    undone_at timestamp with time zone
+);
+
+CREATE INDEX ON reminder_ports(calendar_id, to_event_id);
+
+
+CREATE TABLE room_policies (
+    room text PRIMARY KEY,
+    user_id bigint NOT NULL,
+    timezone text NOT NULL,
+    start_time time NOT NULL,
+    end_time time NOT NULL,
+    working_days bigint[] NOT NULL
+);
 
 
 CREATE TABLE users (
@@ -67,6 +216,12 @@ CREATE TABLE email_to_matrix_id (
 
 CREATE INDEX ON email_to_matrix_id(matrix_id);
 
+
+CREATE TABLE direct_rooms (
+    matrix_id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL
+);
+
 CREATE TABLE access_tokens (
     access_token_id BIGSERIAL PRIMARY KEY,
     user_id BIGINT NOT NULL,
@@ -91,3 +246,16 @@ CREATE TABLE sso_sessions (
 );
 
 CREATE UNIQUE INDEX ON sso_sessions(crsf_token);
+
+
+-- The credentials used to discover calendars on a CalDAV server, kept until
+-- the user picks which calendars to add.
+CREATE TABLE discovery_sessions (
+    token TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    user_name TEXT,
+    password TEXT,
+    expiry TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE UNIQUE INDEX ON discovery_sessions(token);
@@ -6,4 +6,18 @@ window.addEventListener('load', (event) => {
         .forEach(function(node) {
             node.innerText = new Date(node.innerText).toLocaleString(undefined, options);
         });
+
+    let date_options = { weekday: 'long', year: 'numeric', month: 'long', day: 'numeric'};
+
+    document.querySelectorAll(".date")
+        .forEach(function(node) {
+            node.innerText = new Date(node.innerText).toLocaleDateString(undefined, date_options);
+        });
+
+    let time_options = { hour: "numeric", minute: "numeric"};
+
+    document.querySelectorAll(".time")
+        .forEach(function(node) {
+            node.innerText = new Date(node.innerText).toLocaleTimeString(undefined, time_options);
+        });
   });
@@ -1,26 +1,42 @@
 //! The high level app.
 
 use std::{
-    collections::{BTreeMap, HashMap, VecDeque},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
     error::Error as StdError,
+    hash::{Hash, Hasher},
     ops::Deref,
     sync::{Arc, Mutex},
 };
 
 use crate::{
-    calendar::{fetch_calendars, parse_calendars_to_events},
+    calendar::{
+        decode_resources, parse_calendars_to_events, split_resources_by_event, HttpStatusError,
+        ParsedCalendar, SyncChanges,
+    },
     config::HiBobConfig,
-    database::ReminderInstance,
+    database::{ParticipationStatus, ReminderAnchor, ReminderInstance},
 };
 use crate::{config::Config, database::Database};
-use crate::{database::Calendar, DEFAULT_TEMPLATE};
+use crate::{
+    database::{poll_interval, Calendar, CalendarKind, CalendarResource},
+    dedup::{find_replaced_events, DedupCriteria},
+    graph::GraphClient,
+    html::{escape_markdown, sanitise_html},
+    rules::match_rules,
+    scheduler::Scheduler,
+    source::{
+        CalDavSource, CalendarSource, DisabledSource, FetchContext, Fetched, FileSource,
+        GraphSource, IcsFeedSource,
+    },
+    DEFAULT_TEMPLATE,
+};
 
 use anyhow::{bail, Context, Error};
 use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
 use comrak::{markdown_to_html, ComrakOptions};
-use futures::future;
+use futures::{future, stream::FuturesUnordered, StreamExt};
 use handlebars::Handlebars;
-use ics_parser::property::EndCondition;
 use itertools::Itertools;
 use openidconnect::{
     core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
@@ -40,6 +56,14 @@ use tracing::{error, info, instrument, warn, Span};
 use url::Url;
 use urlencoding::encode;
 
+/// How many days we keep the record of the reminders we've sent.
+const SENT_REMINDER_RETENTION_DAYS: i64 = 30;
+
+/// How long to reuse the events parsed from a calendar resource that hasn't
+/// changed. The expanded instances depend on the current time, so they need
+/// to be recomputed now and then.
+const PARSED_RESOURCE_MAX_AGE_MINUTES: i64 = 60;
+
 /// The type of the OpenID Connect client.
 type OpenIDClient = openidconnect::Client<
     openidconnect::EmptyAdditionalClaims,
@@ -155,8 +179,74 @@ impl Reminders {
     }
 }
 
+/// The settings a calendar resource was parsed with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ParseSettings {
+    timezone: Tz,
+    look_ahead: Duration,
+    keep_after: Duration,
+}
+
+/// The events parsed from a single calendar resource, kept so that a sync
+/// only needs to parse the resources that have changed.
+#[derive(Debug, Clone)]
+struct ParsedResource {
+    /// A hash of the resource's ETag and data.
+    data_hash: u64,
+    settings: ParseSettings,
+    parsed_at: DateTime<Utc>,
+    parsed: ParsedCalendar,
+}
+
+impl ParsedResource {
+    fn hash_resource(resource: &CalendarResource) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        resource.etag.hash(&mut hasher);
+        resource.calendar_data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Parse a single resource into events.
+    fn parse(
+        calendar_id: i64,
+        resource: &CalendarResource,
+        settings: ParseSettings,
+        now: DateTime<Utc>,
+    ) -> Result<Self, Error> {
+        let resources = std::slice::from_ref(resource);
+
+        let mut parsed = parse_calendars_to_events(
+            calendar_id,
+            &decode_resources(resources),
+            settings.timezone,
+            settings.look_ahead,
+            settings.keep_after,
+        )?;
+        parsed.sources = split_resources_by_event(resources);
+
+        Ok(ParsedResource {
+            data_hash: Self::hash_resource(resource),
+            settings,
+            parsed_at: now,
+            parsed,
+        })
+    }
+
+    /// Whether this is still the right parse of the given resource.
+    fn is_up_to_date(
+        &self,
+        resource: &CalendarResource,
+        settings: ParseSettings,
+        now: DateTime<Utc>,
+    ) -> bool {
+        self.data_hash == Self::hash_resource(resource)
+            && self.settings == settings
+            && now - self.parsed_at < Duration::minutes(PARSED_RESOURCE_MAX_AGE_MINUTES)
+    }
+}
+
 #[derive(Debug, Deserialize)]
-struct MatrixJoinResponse {
+struct MatrixRoomResponse {
     room_id: String,
 }
 
@@ -172,6 +262,10 @@ pub struct App {
     pub hibob_id_to_email: Arc<Mutex<BTreeMap<String, String>>>,
     pub templates: Tera,
     sso_client: Option<OpenIDClient>,
+    graph_client: GraphClient,
+    /// The events parsed from each calendar's resources, by calendar ID and
+    /// then href.
+    parsed_resources: Arc<Mutex<HashMap<i64, HashMap<String, ParsedResource>>>>,
 }
 
 impl App {
@@ -180,7 +274,9 @@ impl App {
         let reminders = Default::default();
         let email_to_matrix_id = Default::default();
         let hibob_id_to_email = Default::default();
-        let http_client = Default::default();
+        let parsed_resources = Default::default();
+        let http_client: reqwest::Client = Default::default();
+        let graph_client = GraphClient::new(http_client.clone(), config.graph.clone());
 
         // Set up SSO
         let sso_client = if let Some(sso_config) = &config.sso {
@@ -216,6 +312,8 @@ impl App {
             templates,
             sso_client,
             hibob_id_to_email,
+            graph_client,
+            parsed_resources,
         })
     }
 
@@ -229,164 +327,408 @@ impl App {
         );
     }
 
-    /// Fetches and stores updates for the stored calendars.
+    /// Update the given calendar we fetched from the DB, recording whether it
+    /// succeeded.
     #[instrument(skip(self))]
-    pub async fn update_calendars(&self) -> Result<(), Error> {
-        let db_calendars = self.database.get_calendars().await?;
+    pub async fn update_calendar(&self, db_calendar: Calendar) -> Result<(), Error> {
+        let source = self.calendar_source(db_calendar.kind);
 
-        for db_calendar in db_calendars {
-            let calendar_id = db_calendar.calendar_id;
-            if let Err(error) = self.update_calendar(db_calendar).await {
-                error!(
-                    error = error.deref() as &dyn StdError,
-                    calendar_id, "Failed to update calendar"
-                );
+        match self.fetch_and_store_calendar(&db_calendar, &*source).await {
+            Ok(event_count) => {
+                self.database
+                    .record_calendar_success(db_calendar.calendar_id, event_count as i64)
+                    .await?;
+
+                Ok(())
+            }
+            Err(error) => {
+                if let Err(record_error) = self.record_calendar_failure(&db_calendar, &error).await
+                {
+                    error!(
+                        error = record_error.deref() as &dyn StdError,
+                        calendar_id = db_calendar.calendar_id,
+                        "Failed to record calendar failure"
+                    );
+                }
+
+                Err(error)
             }
         }
+    }
+
+    /// Record that updating a calendar failed, notifying the owner if it has
+    /// now failed too many times in a row.
+    async fn record_calendar_failure(
+        &self,
+        db_calendar: &Calendar,
+        error: &Error,
+    ) -> Result<(), Error> {
+        let http_status = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<HttpStatusError>())
+            .map(|e| i32::from(e.status));
+
+        let consecutive_failures = self
+            .database
+            .record_calendar_failure(
+                db_calendar.calendar_id,
+                http_status,
+                &format!("{:#}", error),
+            )
+            .await?;
+
+        if Some(consecutive_failures) == self.config.app.notify_after_failures {
+            self.notify_calendar_failing(db_calendar, consecutive_failures, error)
+                .await?;
+        }
 
         Ok(())
     }
 
-    /// Update the given calendar we fetched from the DB.
-    #[instrument(skip(self))]
-    pub async fn update_calendar(&self, db_calendar: Calendar) -> Result<(), Error> {
-        let calendars = fetch_calendars(
-            &self.http_client,
-            &db_calendar.url,
-            db_calendar.user_name.as_deref(),
-            db_calendar.password.as_deref(),
-        )
-        .await?;
+    /// Send the owner of a calendar a DM saying that it keeps failing to
+    /// update.
+    async fn notify_calendar_failing(
+        &self,
+        db_calendar: &Calendar,
+        consecutive_failures: i64,
+        error: &Error,
+    ) -> Result<(), Error> {
+        let email = self
+            .database
+            .get_user_email(db_calendar.user_id)
+            .await?
+            .context("Unknown user")?;
 
-        let mut vevents_by_id = HashMap::new();
-        for calendar in &calendars {
-            vevents_by_id.extend(&calendar.events);
-        }
+        let matrix_id = self
+            .email_to_matrix_id
+            .lock()
+            .expect("poisoned")
+            .get(&email)
+            .cloned();
 
-        let (events, next_dates) = parse_calendars_to_events(db_calendar.calendar_id, &calendars)?;
+        let matrix_id = if let Some(matrix_id) = matrix_id {
+            matrix_id
+        } else {
+            info!(
+                calendar_id = db_calendar.calendar_id,
+                "Calendar owner has no known Matrix ID, not notifying"
+            );
+            return Ok(());
+        };
+
+        let room_id = self.get_direct_room(&matrix_id).await?;
+
+        let body = format!(
+            "Your calendar {} has failed to update {} times in a row, the last error was:\n\n{:#}",
+            db_calendar.name, consecutive_failures, error,
+        );
+        let markdown = format!(
+            "Your calendar **{}** has failed to update {} times in a row, the last error was:\n\n{}",
+            escape_markdown(&db_calendar.name),
+            consecutive_failures,
+            escape_markdown(&format!("{:#}", error)),
+        );
+
+        self.send_message(&room_id, &body, &markdown).await
+    }
+
+    /// Fetch the latest version of a calendar from the given source and store
+    /// its upcoming events, returning the number of events.
+    pub async fn fetch_and_store_calendar(
+        &self,
+        db_calendar: &Calendar,
+        source: &dyn CalendarSource,
+    ) -> Result<usize, Error> {
+        let sync_token = self
+            .database
+            .get_sync_token(db_calendar.calendar_id)
+            .await?;
+        let previous = self
+            .database
+            .get_calendar_resources(db_calendar.calendar_id)
+            .await?;
+
+        let keep_after = self.keep_after(db_calendar).await?;
+
+        let fetched = source
+            .fetch(FetchContext {
+                calendar: db_calendar,
+                sync_token: sync_token.as_deref(),
+                previous: &previous,
+                timezone: db_calendar.tz(),
+                look_ahead: self.look_ahead(db_calendar),
+                keep_after,
+            })
+            .await?;
+
+        let parsed = match fetched {
+            Fetched::Resources(changes) => {
+                self.store_resource_changes(db_calendar, changes, previous, keep_after)
+                    .await?
+            }
+            Fetched::Events(parsed) => parsed,
+        };
 
         // Some calendar systems (read: FastMail) create new events when people
         // edit the times for future events. Since we want the reminders to
-        // apply to the new event we add some heuristics to detect this case and
-        // copy across the reminders.
+        // apply to the new event we copy across the reminders of the events
+        // they replace.
         let previous_events = self
             .database
             .get_events_in_calendar(db_calendar.calendar_id)
             .await?;
 
-        let mut previous_events_by_id = HashMap::new();
-        for (previous_event, _) in &previous_events {
-            previous_events_by_id.insert(&previous_event.event_id, previous_event);
-        }
+        let replaced_events = find_replaced_events(
+            &previous_events,
+            &parsed,
+            DedupCriteria::for_calendar(db_calendar),
+            db_calendar.tz(),
+        );
+
+        for replaced in replaced_events {
+            let reminders = self
+                .database
+                .get_reminders_for_event(db_calendar.calendar_id, &replaced.previous_event_id)
+                .await?;
+
+            // We only want to apply this logic for reminders that this user
+            // owns. Reminders from alarms and rules get recreated for the
+            // new event anyway.
+            let reminders: Vec<_> = reminders
+                .into_iter()
+                .filter(|r| {
+                    r.user_id == db_calendar.user_id && !r.from_alarm && r.rule_id.is_none()
+                })
+                .collect();
+
+            info!(
+                calendar_id = db_calendar.calendar_id,
+                prev_event = replaced.previous_event_id.deref(),
+                new_event = replaced.new_event_id.deref(),
+                reminders = reminders.len(),
+                "Found event duplicate, porting reminders."
+            );
 
-        let mut events_by_summmary: HashMap<_, Vec<_>> = HashMap::new();
-        let mut events_by_id = HashMap::new();
-        for event in &events {
-            events_by_summmary
-                .entry((&event.summary, &event.organizer))
-                .or_default()
-                .push(event);
-            events_by_id.insert(&event.event_id, event);
+            self.database
+                .port_reminders(
+                    db_calendar.calendar_id,
+                    &replaced.previous_event_id,
+                    &replaced.new_event_id,
+                    reminders,
+                )
+                .await?;
         }
 
-        for (previous_event, _) in &previous_events {
-            // Figure out if we should attempt to deduplicated based on this
-            // event. We're either expecting it to not appear in the calendar or
-            // for it to be a recurring event that has an end date.
-            if let Some(existing_event) = vevents_by_id.get(&previous_event.event_id) {
-                if let Some(recur) = &existing_event.base_event.recur {
-                    match recur.end_condition {
-                        EndCondition::Count(_) | EndCondition::Infinite => {
-                            // The previous event hasn't been stopped, so we don't deduplicate.
-                            info!(
-                                calendar_id = db_calendar.calendar_id,
-                                event_id = previous_event.event_id.deref(),
-                                "Found existing event that hasn't expired."
-                            );
-                            continue;
-                        }
-                        EndCondition::Until(_) | EndCondition::UntilUtc(_) => {
-                            // The previous event has been stopped, so we deduplicate.
-                            info!(
-                                calendar_id = db_calendar.calendar_id,
-                                event_id = previous_event.event_id.deref(),
-                                "Existing event has an expiry time, checking if we should deduplicate."
-                            );
-                        }
-                    }
-                } else {
-                    // Not a recurring event, so don't need to deduplicate.
-                    info!(
-                        calendar_id = db_calendar.calendar_id,
-                        event_id = previous_event.event_id.deref(),
-                        "Found existing non-recurring event."
-                    );
-                    continue;
-                }
-            }
+        let event_count = parsed.events.len();
 
-            for new_event in events_by_summmary
-                .get(&(&previous_event.summary, &previous_event.organizer))
-                .map(|v| v.deref())
-                .unwrap_or_else(|| &[])
-            {
-                if previous_event.event_id == new_event.event_id {
-                    // This is just an event that we already have.
-                    continue;
-                }
+        let rules = self
+            .database
+            .get_reminder_rules(db_calendar.calendar_id)
+            .await?;
+        let rule_matches = match_rules(&rules, &parsed.events);
 
-                if previous_events_by_id.contains_key(&new_event.event_id) {
-                    // We've already processed the new event.
-                    info!(
-                        calendar_id = db_calendar.calendar_id,
-                        prev_event = previous_event.event_id.deref(),
-                        new_event = new_event.event_id.deref(),
-                        "Ignoring 'new_event' as its not new."
-                    );
-                    continue;
-                }
+        let changed_events = self
+            .database
+            .insert_events(
+                db_calendar.calendar_id,
+                parsed.events,
+                parsed.instances,
+                parsed.sources,
+            )
+            .await?;
 
-                let mut reminders = self
-                    .database
-                    .get_reminders_for_event(db_calendar.calendar_id, &previous_event.event_id)
-                    .await?;
+        info!(
+            calendar_id = db_calendar.calendar_id,
+            changed = changed_events.len(),
+            "Stored calendar events"
+        );
 
-                // We only want to apply this logic for reminders that this user owns.
-                reminders = reminders
+        let alarms = parsed
+            .alarms
+            .into_iter()
+            .flat_map(|(event_id, offsets)| {
+                offsets
                     .into_iter()
-                    .filter(|r| r.user_id == db_calendar.user_id)
-                    .collect();
+                    .map(move |minutes_before| (event_id.clone(), minutes_before))
+            })
+            .collect();
 
-                info!(
-                    calendar_id = db_calendar.calendar_id,
-                    prev_event = previous_event.event_id.deref(),
-                    new_event = new_event.event_id.deref(),
-                    reminders = reminders.len(),
-                    "Found event duplicate, porting reminders."
-                );
+        self.database
+            .sync_alarm_reminders(db_calendar, &alarms)
+            .await?;
+
+        self.database
+            .sync_rule_reminders(db_calendar, &rules, &rule_matches)
+            .await?;
+
+        self.update_reminders().await?;
+
+        Ok(event_count)
+    }
 
-                for mut reminder in reminders {
-                    reminder.reminder_id = -1;
-                    reminder.event_id = new_event.event_id.clone();
+    /// Re-apply the calendar's reminder rules to its stored events, e.g. after
+    /// a rule has been edited.
+    pub async fn apply_reminder_rules(&self, db_calendar: &Calendar) -> Result<(), Error> {
+        let rules = self
+            .database
+            .get_reminder_rules(db_calendar.calendar_id)
+            .await?;
+        let events = self
+            .database
+            .get_stored_events(db_calendar.calendar_id)
+            .await?;
+
+        let rule_matches = match_rules(&rules, &events);
+
+        self.database
+            .sync_rule_reminders(db_calendar, &rules, &rule_matches)
+            .await?;
+
+        self.update_reminders().await
+    }
 
-                    self.database.add_reminder(reminder).await?;
+    /// Store the changes to a calendar's ICS resources, and parse the full set
+    /// of resources into events.
+    ///
+    /// Only the resources that have changed since they were last parsed are
+    /// parsed again.
+    async fn store_resource_changes(
+        &self,
+        db_calendar: &Calendar,
+        changes: SyncChanges,
+        mut resources: Vec<CalendarResource>,
+        keep_after: Duration,
+    ) -> Result<ParsedCalendar, Error> {
+        info!(
+            calendar_id = db_calendar.calendar_id,
+            full = changes.full,
+            changed = changes.changed.len(),
+            deleted = changes.deleted.len(),
+            "Fetched calendar changes"
+        );
+
+        self.database
+            .apply_calendar_changes(
+                db_calendar.calendar_id,
+                changes.full,
+                &changes.changed,
+                &changes.deleted,
+                changes.sync_token.as_deref(),
+            )
+            .await?;
+
+        // We only fetch what has changed, so we need the full set of stored
+        // resources to figure out the upcoming events. If nothing has changed
+        // then the resources we had before are still up to date.
+        if changes.full || !changes.changed.is_empty() || !changes.deleted.is_empty() {
+            resources = self
+                .database
+                .get_calendar_resources(db_calendar.calendar_id)
+                .await?;
+        }
+
+        let settings = ParseSettings {
+            timezone: db_calendar.tz(),
+            look_ahead: self.look_ahead(db_calendar),
+            keep_after,
+        };
+        let now = Utc::now();
+
+        // Take the calendar's cached parses out while we work, rather than
+        // holding the lock while parsing. Resources that have been removed
+        // are dropped along with them.
+        let mut cached = self
+            .parsed_resources
+            .lock()
+            .expect("poisoned")
+            .remove(&db_calendar.calendar_id)
+            .unwrap_or_default();
+
+        let mut parsed = ParsedCalendar::default();
+        let mut parsed_resources = HashMap::with_capacity(resources.len());
+        for resource in &resources {
+            let parsed_resource = match cached.remove(&resource.href) {
+                Some(parsed_resource) if parsed_resource.is_up_to_date(resource, settings, now) => {
+                    parsed_resource
                 }
-            }
+                _ => ParsedResource::parse(db_calendar.calendar_id, resource, settings, now)?,
+            };
+
+            parsed.extend(parsed_resource.parsed.clone());
+            parsed_resources.insert(resource.href.clone(), parsed_resource);
         }
 
-        self.database
-            .insert_events(db_calendar.calendar_id, events, next_dates)
+        self.parsed_resources
+            .lock()
+            .expect("poisoned")
+            .insert(db_calendar.calendar_id, parsed_resources);
+
+        Ok(parsed)
+    }
+
+    /// How far ahead to expand the events in the given calendar.
+    fn look_ahead(&self, db_calendar: &Calendar) -> Duration {
+        let days = db_calendar
+            .look_ahead_days
+            .or(self.config.app.look_ahead_days)
+            .unwrap_or(30);
+
+        // The config isn't validated, and `Duration::days` panics on huge
+        // values.
+        Duration::days(days.clamp(1, 3650))
+    }
+
+    /// How long after they end to keep the instances of events in the given
+    /// calendar, so that reminders due after the end can still be sent.
+    async fn keep_after(&self, db_calendar: &Calendar) -> Result<Duration, Error> {
+        let minutes_after = self
+            .database
+            .get_max_minutes_after(db_calendar.calendar_id)
             .await?;
 
-        self.update_reminders().await?;
+        // As with the look ahead, `Duration::minutes` panics on huge values.
+        Ok(Duration::minutes(minutes_after.min(60 * 24 * 3650)) + self.catch_up())
+    }
 
-        Ok(())
+    /// How late we'll still send a reminder.
+    fn catch_up(&self) -> Duration {
+        let minutes = self.config.app.catch_up_minutes.unwrap_or(10);
+
+        // As with the look ahead, the config isn't validated. We don't go
+        // back more than a week.
+        Duration::minutes(minutes.clamp(0, 7 * 24 * 60))
+    }
+
+    /// The backend to fetch the given kind of calendar from.
+    pub fn calendar_source(&self, kind: CalendarKind) -> Box<dyn CalendarSource> {
+        match kind {
+            CalendarKind::CalDav => Box::new(CalDavSource {
+                http_client: self.http_client.clone(),
+            }),
+            CalendarKind::Ics => Box::new(IcsFeedSource {
+                http_client: self.http_client.clone(),
+            }),
+            CalendarKind::File => match &self.config.app.calendar_directory {
+                Some(root) => Box::new(FileSource { root: root.into() }),
+                None => Box::new(DisabledSource {
+                    reason: "Local calendars are not enabled",
+                }),
+            },
+            CalendarKind::Graph => Box::new(GraphSource {
+                client: self.graph_client.clone(),
+                database: self.database.clone(),
+            }),
+        }
     }
 
     /// Queries the DB and updates the reminders
     #[instrument(skip(self))]
     pub async fn update_reminders(&self) -> Result<(), Error> {
-        let reminders = self.database.get_next_reminders().await?;
+        let reminders = self.database.get_next_reminders(self.catch_up()).await?;
+
+        self.database
+            .prune_sent_reminders(Duration::days(SENT_REMINDER_RETENTION_DAYS))
+            .await?;
 
         info!(num = reminders.len(), "Updated reminders");
 
@@ -406,19 +748,85 @@ impl App {
         Ok(())
     }
 
-    /// An infinite loop that periodically triggers fetching updates for all
-    /// calendars.
+    /// An infinite loop that fetches updates for each calendar as they become
+    /// due, running a limited number of updates at once.
     async fn update_calendar_loop(&self) {
-        let mut interval = interval(Duration::minutes(5).to_std().expect("std duration"));
+        let default_interval = poll_interval(self.config.app.poll_interval_minutes.unwrap_or(5));
+        let max_concurrent_updates = self.config.app.max_concurrent_updates.unwrap_or(4).max(1);
+
+        let mut scheduler = Scheduler::new(default_interval);
+        let mut calendars = BTreeMap::new();
+        let mut next_refresh = Utc::now();
+        let mut in_progress = FuturesUnordered::new();
 
         loop {
-            interval.tick().await;
+            let now = Utc::now();
+
+            // Periodically pick up any added, removed or edited calendars.
+            if now >= next_refresh {
+                match self.database.get_calendars().await {
+                    Ok(db_calendars) => {
+                        scheduler.set_calendars(
+                            db_calendars
+                                .iter()
+                                .map(|c| (c.calendar_id, c.poll_interval())),
+                            now,
+                        );
+                        calendars = db_calendars
+                            .into_iter()
+                            .map(|c| (c.calendar_id, c))
+                            .collect();
+
+                        // Forget the parsed events of calendars that have
+                        // been removed.
+                        self.parsed_resources
+                            .lock()
+                            .expect("poisoned")
+                            .retain(|calendar_id, _| calendars.contains_key(calendar_id));
+                        next_refresh = now + Duration::minutes(1);
+                    }
+                    Err(error) => error!(
+                        error = error.deref() as &dyn StdError,
+                        "Failed to fetch calendars"
+                    ),
+                }
+            }
 
-            if let Err(error) = self.update_calendars().await {
-                error!(
-                    error = error.deref() as &dyn StdError,
-                    "Failed to update calendars"
-                );
+            let free_slots = max_concurrent_updates.saturating_sub(in_progress.len());
+            for calendar_id in scheduler.take_due(now, free_slots) {
+                if let Some(db_calendar) = calendars.get(&calendar_id).cloned() {
+                    in_progress.push(async move {
+                        (calendar_id, self.update_calendar(db_calendar).await)
+                    });
+                }
+            }
+
+            // If all the slots are full we just wait for an update to finish.
+            let next_wakeup = if in_progress.len() >= max_concurrent_updates {
+                Duration::minutes(1)
+            } else {
+                scheduler
+                    .time_to_next(now)
+                    .unwrap_or_else(|| Duration::minutes(1))
+                    .min(Duration::minutes(1))
+            };
+
+            tokio::select! {
+                Some((calendar_id, result)) = in_progress.next(), if !in_progress.is_empty() => {
+                    match result {
+                        Ok(()) => scheduler.record_success(calendar_id, Utc::now()),
+                        Err(error) => {
+                            let backoff = scheduler.record_failure(calendar_id, Utc::now());
+                            error!(
+                                error = error.deref() as &dyn StdError,
+                                calendar_id,
+                                backoff_minutes = backoff.num_minutes(),
+                                "Failed to update calendar"
+                            );
+                        }
+                    }
+                }
+                _ = sleep(next_wakeup.to_std().unwrap_or_default()) => {}
             }
         }
     }
@@ -442,6 +850,19 @@ impl App {
 
     /// Loop that handle sending the reminders.
     async fn reminder_loop(&self) {
+        // Anything still being sent was interrupted by a restart, so we can't
+        // tell if it went out.
+        match self.database.mark_interrupted_sends().await {
+            Ok(0) => {}
+            Ok(count) => warn!(count, "Reminders were interrupted while being sent"),
+            Err(err) => {
+                error!(
+                    error = err.deref() as &dyn StdError,
+                    "Failed to mark interrupted reminders"
+                );
+            }
+        }
+
         loop {
             let next_wakeup = self
                 .reminders
@@ -476,19 +897,70 @@ impl App {
             info!(count = reminders.len(), "Due reminders");
 
             for reminder in reminders {
+                let reminder_id = reminder.reminder_id;
+                let instance = reminder.start;
+                let anchor = reminder.anchor;
+                let minutes_before = reminder.minutes_before;
+                let is_primary = reminder.is_primary;
+
+                // We record each reminder before sending it so that we never
+                // send the same one twice, e.g. if the reminders get reloaded
+                // just as we're sending one.
+                match self
+                    .database
+                    .claim_reminder_send(reminder_id, instance, anchor, minutes_before, is_primary)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        info!(
+                            event_id = reminder.event_id.deref(),
+                            reminder_id, "Reminder already sent"
+                        );
+                        continue;
+                    }
+                    Err(err) => {
+                        error!(
+                            error = err.deref() as &dyn StdError,
+                            "Failed to record reminder, not sending"
+                        );
+                        continue;
+                    }
+                }
+
                 info!(event_id = reminder.event_id.deref(), "Sending reminder");
-                if let Err(err) = self.send_reminder(reminder).await {
+                let result = self.send_reminder(reminder).await;
+                if let Err(err) = &result {
                     error!(
                         error = err.deref() as &dyn StdError,
                         "Failed to send reminder"
                     );
                 }
+
+                let send_error = result.err().map(|err| format!("{:#}", err));
+                if let Err(err) = self
+                    .database
+                    .finish_reminder_send(
+                        reminder_id,
+                        instance,
+                        anchor,
+                        minutes_before,
+                        is_primary,
+                        send_error,
+                    )
+                    .await
+                {
+                    error!(
+                        error = err.deref() as &dyn StdError,
+                        "Failed to record reminder result"
+                    );
+                }
             }
         }
     }
 
     /// Send the reminder to the appropriate room.
-    #[instrument(skip(self), fields(status))]
+    #[instrument(skip(self))]
     async fn send_reminder(&self, reminder: ReminderInstance) -> Result<(), Error> {
         let join_url = format!(
             "{}/_matrix/client/r0/join/{}",
@@ -509,7 +981,7 @@ impl App {
             bail!("Got non-2xx from /join response: {}", resp.status());
         }
 
-        let body: MatrixJoinResponse = resp.json().await?;
+        let body: MatrixRoomResponse = resp.json().await?;
 
         let markdown_template = reminder.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
 
@@ -519,65 +991,163 @@ impl App {
         let out_today_emails = self.database.get_out_today_emails().await?;
         let out_today_matrix_ids = self.database.get_out_today_matrix_ids().await?;
 
-        let attendees = reminder
+        let formatted_attendees = reminder
             .attendees
             .iter()
             .filter(|attendee| !out_today_emails.contains(&attendee.email))
             .filter_map(|attendee| {
                 // Map attendee email to a markdown string, filtering out matrix
-                // IDs that we know are on holiday.
-                if let Some(matrix_id) = self
+                // IDs that we know are on holiday. We also keep the plain name
+                // for the message's plain text body.
+                let name = attendee.common_name.as_ref().unwrap_or(&attendee.email);
+
+                let formatted = if let Some(matrix_id) = self
                     .email_to_matrix_id
                     .lock()
                     .expect("poisoned")
                     .get(&attendee.email)
                 {
                     if out_today_matrix_ids.contains(matrix_id) {
-                        None
-                    } else {
-                        Some(format!(
-                            "[{}](https://matrix.to/#/{})",
-                            attendee.common_name.as_ref().unwrap_or(matrix_id),
-                            matrix_id,
-                        ))
+                        return None;
                     }
+
+                    let name = attendee.common_name.as_ref().unwrap_or(matrix_id);
+                    let link = format!(
+                        "[{}](https://matrix.to/#/{})",
+                        escape_markdown(name),
+                        escape_markdown(matrix_id),
+                    );
+                    (link, name.to_string())
                 } else {
-                    Some(
-                        attendee
-                            .common_name
-                            .as_ref()
-                            .unwrap_or(&attendee.email)
-                            .to_string(),
-                    )
-                }
+                    (escape_markdown(name), name.to_string())
+                };
+
+                Some((attendee.status, formatted))
             })
-            .join(", ");
+            .collect_vec();
+
+        // Lists of attendees by their response, so that templates can e.g.
+        // chase up people who haven't responded.
+        let attendees_with_status = |filter: fn(Option<ParticipationStatus>) -> bool,
+                                     markdown: bool| {
+            formatted_attendees
+                .iter()
+                .filter(|(status, _)| filter(*status))
+                .map(|(_, (link, name))| if markdown { link } else { name })
+                .join(", ")
+        };
 
-        let handlebars = Handlebars::new();
-        let markdown = handlebars
-            .render_template(
+        // Times are shown in the calendar's timezone, as we don't know where
+        // the people in the room are.
+        let start_time = reminder
+            .start
+            .with_timezone(&reminder.timezone)
+            .format("%H:%M")
+            .to_string();
+        let end_time = reminder.end.map(|end| {
+            end.with_timezone(&reminder.timezone)
+                .format("%H:%M")
+                .to_string()
+        });
+        let duration_minutes = reminder.end.map(|end| (end - reminder.start).num_minutes());
+        let when = reminder_when(&reminder, &start_time, end_time.as_deref());
+
+        // The template is rendered twice: once with the fields escaped for the
+        // formatted message, and once as is for the plain text body.
+        let render = |markdown: bool| {
+            let escape = |text: &Option<String>| {
+                text.as_deref().map(|text| {
+                    if markdown {
+                        escape_markdown(text)
+                    } else {
+                        text.to_string()
+                    }
+                })
+            };
+
+            // The fields come from whoever created the event, so we make sure
+            // they can't add their own formatting, links or HTML. Descriptions
+            // converted from HTML are already cleaned Markdown, so are
+            // included as is.
+            let description = if reminder.description_from_html {
+                reminder.description.clone()
+            } else {
+                escape(&reminder.description)
+            };
+            let event_id = Some(reminder.event_id.clone());
+
+            render_template(
                 markdown_template,
                 &json!({
-                    "event_id": &reminder.event_id,
-                    "summary": &reminder.summary,
-                    "description": &reminder.description,
-                    "location": &reminder.location,
+                    "event_id": escape(&event_id),
+                    "summary": escape(&reminder.summary),
+                    "description": description,
+                    "description_html": &reminder.description_html,
+                    "location": escape(&reminder.location),
+                    "join_url": escape(&reminder.join_url),
+                    "start_time": start_time,
+                    "end_time": end_time,
+                    "duration_minutes": duration_minutes,
                     "minutes_before": &reminder.minutes_before,
-                    "attendees": attendees,
+                    "minutes_after": -reminder.minutes_before,
+                    "anchor": reminder.anchor,
+                    "all_day": reminder.all_day,
+                    "days_before": reminder.days_before,
+                    "deferred": reminder.deferred,
+                    "when": when,
+                    "attendees": attendees_with_status(
+                        |status| status != Some(ParticipationStatus::Declined),
+                        markdown,
+                    ),
+                    "accepted": attendees_with_status(
+                        |status| status == Some(ParticipationStatus::Accepted),
+                        markdown,
+                    ),
+                    "tentative": attendees_with_status(
+                        |status| status == Some(ParticipationStatus::Tentative),
+                        markdown,
+                    ),
+                    "no_response": attendees_with_status(
+                        |status| status.is_none() || status == Some(ParticipationStatus::NeedsAction),
+                        markdown,
+                    ),
+                    "declined": attendees_with_status(
+                        |status| status == Some(ParticipationStatus::Declined),
+                        markdown,
+                    ),
                 }),
             )
-            .with_context(|| "Rendering body template")?;
+            .with_context(|| "Rendering body template")
+        };
+
+        let markdown = render(true)?;
+        let plain = render(false)?;
+
+        self.send_message(&body.room_id, &plain, &markdown).await?;
+
+        info!(
+            event_id = reminder.event_id.deref(),
+            room_id = body.room_id.deref(),
+            "Sent reminder"
+        );
 
+        Ok(())
+    }
+
+    /// Send a markdown formatted message to the given room, with the plain text
+    /// `body` for clients that don't show formatting.
+    #[instrument(skip(self, body, markdown), fields(status))]
+    async fn send_message(&self, room_id: &str, body: &str, markdown: &str) -> Result<(), Error> {
         let event_json = json!({
             "msgtype": "m.text",
-            "body": markdown,
+            "body": body,
             "format": "org.matrix.custom.html",
-            "formatted_body": markdown_to_html(&markdown, &ComrakOptions::default()),
+            "formatted_body": sanitise_html(&markdown_to_html(markdown, &ComrakOptions::default())),
         });
 
         let url = format!(
             "{}/_matrix/client/r0/rooms/{}/send/m.room.message",
-            self.config.matrix.homeserver_url, body.room_id
+            self.config.matrix.homeserver_url, room_id
         );
 
         let resp = self
@@ -591,13 +1161,6 @@ impl App {
 
         Span::current().record("status", &resp.status().as_u16());
 
-        info!(
-            status = resp.status().as_u16(),
-            event_id = reminder.event_id.deref(),
-            room_id = body.room_id.deref(),
-            "Sent reminder"
-        );
-
         if !resp.status().is_success() {
             bail!("Got non-2xx from /send response: {}", resp.status());
         }
@@ -605,6 +1168,46 @@ impl App {
         Ok(())
     }
 
+    /// Get the DM room with the given user, creating it if we haven't got one
+    /// yet.
+    async fn get_direct_room(&self, matrix_id: &str) -> Result<String, Error> {
+        if let Some(room_id) = self.database.get_direct_room(matrix_id).await? {
+            return Ok(room_id);
+        }
+
+        let url = format!(
+            "{}/_matrix/client/r0/createRoom",
+            self.config.matrix.homeserver_url
+        );
+
+        let resp = self
+            .http_client
+            .post(&url)
+            .bearer_auth(&self.config.matrix.access_token)
+            .json(&json!({
+                "preset": "trusted_private_chat",
+                "is_direct": true,
+                "invite": [matrix_id],
+            }))
+            .send()
+            .await
+            .with_context(|| "Sending HTTP /createRoom request")?;
+
+        if !resp.status().is_success() {
+            bail!("Got non-2xx from /createRoom response: {}", resp.status());
+        }
+
+        let body: MatrixRoomResponse = resp.json().await?;
+
+        info!(matrix_id, room_id = body.room_id.deref(), "Created DM room");
+
+        self.database
+            .set_direct_room(matrix_id, &body.room_id)
+            .await?;
+
+        Ok(body.room_id)
+    }
+
     /// An infinite loop that periodically pulls email to Matrix ID mappings and
     /// holidays from HiBob.
     async fn hibob_loop(&self) {
@@ -842,6 +1445,33 @@ impl App {
 
         Ok(token)
     }
+
+    /// Store the credentials used to discover calendars, returning a token
+    /// that can be used to fetch them when adding the picked calendars.
+    pub async fn add_discovery_session(
+        &self,
+        user_id: i64,
+        user_name: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<String, Error> {
+        let token: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        self.database
+            .add_discovery_session(
+                &token,
+                user_id,
+                user_name,
+                password,
+                Utc::now() + Duration::hours(1),
+            )
+            .await?;
+
+        Ok(token)
+    }
 }
 
 /// Checks if the string is likely a valid user ID.
@@ -884,3 +1514,154 @@ fn is_likely_a_valid_user_id(user_id: &str) -> bool {
 
     true
 }
+
+/// Describe when the event is relative to the reminder, e.g. "starts in 5
+/// minutes", for the default template. `None` if there's nothing to say.
+fn reminder_when(
+    reminder: &ReminderInstance,
+    start_time: &str,
+    end_time: Option<&str>,
+) -> Option<String> {
+    let minutes_before = reminder.minutes_before;
+
+    // Deferred reminders go out some time after they were due, so the
+    // relative time would be wrong.
+    if reminder.deferred && !reminder.all_day {
+        return Some(match reminder.anchor {
+            ReminderAnchor::Start => format!("starts at {}", start_time),
+            ReminderAnchor::End => format!("ends at {}", end_time?),
+        });
+    }
+
+    let when = match reminder.anchor {
+        ReminderAnchor::End if minutes_before > 0 => format!("ends in {} minutes", minutes_before),
+        ReminderAnchor::End if minutes_before < 0 => {
+            format!("ended {} minutes ago", -minutes_before)
+        }
+        ReminderAnchor::End => "is ending".to_string(),
+        ReminderAnchor::Start if reminder.all_day => match reminder.days_before {
+            days if days > 1 => format!("is in {} days", days),
+            1 => "is tomorrow".to_string(),
+            _ => "is today".to_string(),
+        },
+        ReminderAnchor::Start if minutes_before > 0 => {
+            format!("starts in {} minutes", minutes_before)
+        }
+        ReminderAnchor::Start if minutes_before < 0 => {
+            format!("started {} minutes ago", -minutes_before)
+        }
+        ReminderAnchor::Start => return None,
+    };
+
+    Some(when)
+}
+
+/// Render a reminder template. The fields are escaped before being passed in,
+/// so handlebars' own HTML escaping is turned off.
+fn render_template(template: &str, data: &serde_json::Value) -> Result<String, Error> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+
+    Ok(handlebars.render_template(template, data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn reminder(anchor: ReminderAnchor, minutes_before: i64) -> ReminderInstance {
+        let start = Utc.ymd(2021, 6, 1).and_hms(9, 0, 0);
+
+        ReminderInstance {
+            reminder_id: 1,
+            event_id: "event".to_string(),
+            summary: Some("Standup".to_string()),
+            description: None,
+            description_html: None,
+            description_from_html: false,
+            location: None,
+            join_url: None,
+            start,
+            end: Some(start + Duration::minutes(15)),
+            timezone: chrono_tz::UTC,
+            template: None,
+            minutes_before,
+            anchor,
+            is_primary: true,
+            room: "!room:example.com".to_string(),
+            attendees: Vec::new(),
+            all_day: false,
+            days_before: 0,
+            deferred: false,
+        }
+    }
+
+    /// Render the default template with just the summary and the "when"
+    /// phrase.
+    fn render_default(reminder: &ReminderInstance) -> String {
+        let when = reminder_when(reminder, "09:00", Some("09:15"));
+
+        render_template(
+            DEFAULT_TEMPLATE,
+            &json!({ "summary": reminder.summary, "when": when }),
+        )
+        .unwrap()
+        .trim()
+        .to_string()
+    }
+
+    #[test]
+    fn default_template_before_and_after_the_start() {
+        let before = reminder(ReminderAnchor::Start, 5);
+        assert_eq!(render_default(&before), "**Standup** starts in 5 minutes");
+
+        let after = reminder(ReminderAnchor::Start, -10);
+        assert_eq!(render_default(&after), "**Standup** started 10 minutes ago");
+
+        let at = reminder(ReminderAnchor::Start, 0);
+        assert_eq!(render_default(&at), "**Standup**");
+    }
+
+    #[test]
+    fn default_template_before_and_after_the_end() {
+        let before = reminder(ReminderAnchor::End, 5);
+        assert_eq!(render_default(&before), "**Standup** ends in 5 minutes");
+
+        let after = reminder(ReminderAnchor::End, -10);
+        assert_eq!(render_default(&after), "**Standup** ended 10 minutes ago");
+
+        let at = reminder(ReminderAnchor::End, 0);
+        assert_eq!(render_default(&at), "**Standup** is ending");
+    }
+
+    #[test]
+    fn default_template_all_day() {
+        let mut reminder = reminder(ReminderAnchor::Start, 0);
+        reminder.all_day = true;
+        assert_eq!(render_default(&reminder), "**Standup** is today");
+
+        reminder.days_before = 1;
+        assert_eq!(render_default(&reminder), "**Standup** is tomorrow");
+
+        reminder.days_before = 3;
+        assert_eq!(render_default(&reminder), "**Standup** is in 3 days");
+
+        // All-day events have no time to give, so being deferred changes
+        // nothing.
+        reminder.deferred = true;
+        assert_eq!(render_default(&reminder), "**Standup** is in 3 days");
+    }
+
+    #[test]
+    fn default_template_deferred() {
+        let mut start = reminder(ReminderAnchor::Start, 30);
+        start.deferred = true;
+        assert_eq!(render_default(&start), "**Standup** starts at 09:00");
+
+        let mut end = reminder(ReminderAnchor::End, 30);
+        end.deferred = true;
+        assert_eq!(render_default(&end), "**Standup** ends at 09:15");
+    }
+}
@@ -1,19 +1,26 @@
 //! Helper functions for parsing and dealing with ICS calendars.
 
 use anyhow::{anyhow, bail, Context, Error};
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, FixedOffset, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
 use ics_parser::{
     components::{VCalendar, VEvent},
     parser,
-    property::PropertyValue,
+    property::{EndCondition, Property, PropertyValue},
 };
 use reqwest::Method;
-use tracing::{error, info, instrument, Span};
+use serde::Serialize;
+use tracing::{error, info, instrument, warn, Span};
 use url::Url;
 
-use std::{convert::TryInto, ops::Deref, str::FromStr};
+use std::{collections::HashMap, convert::TryInto, ops::Deref, str::FromStr};
 
-use crate::database::{Attendee, Event, EventInstance};
+use crate::{
+    database::{
+        Attendee, CalendarResource, Event, EventInstance, ParticipantRole, ParticipationStatus,
+    },
+    html::{looks_like_html, Description},
+};
 
 /// Parse a ICS encoded calendar.
 fn decode_calendar(cal_body: &str) -> Result<Vec<VCalendar>, Error> {
@@ -26,27 +33,159 @@ fn decode_calendar(cal_body: &str) -> Result<Vec<VCalendar>, Error> {
         .collect()
 }
 
-/// Fetch a calendar from a CalDAV URL and parse the returned set of calendars.
-///
-/// Note that CalDAV returns a calendar per event, rather than one calendar with
-/// many events.
-#[instrument(skip(client, password), fields(status))]
-pub async fn fetch_calendars(
+/// A calendar server responded with an unsuccessful status code.
+#[derive(Debug, Clone)]
+pub struct HttpStatusError {
+    pub status: u16,
+    /// What we were talking to, e.g. "CalDAV".
+    pub server: &'static str,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Got {} result from {}", self.status, self.server)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// The changes to a CalDAV collection since we last synced it.
+#[derive(Debug, Clone, Default)]
+pub struct SyncChanges {
+    /// Whether `changed` is the full contents of the collection, rather than a
+    /// delta against what we have stored.
+    pub full: bool,
+    /// Resources that are new or have changed.
+    pub changed: Vec<CalendarResource>,
+    /// The hrefs of resources that have been deleted.
+    pub deleted: Vec<String>,
+    /// The token to use for the next sync, if the server supports
+    /// `sync-collection`.
+    pub sync_token: Option<String>,
+}
+
+/// A single `response` element of a WebDAV multistatus body.
+#[derive(Debug, Clone, Default)]
+struct MultistatusResponse {
+    href: String,
+    status: Option<u16>,
+    etag: Option<String>,
+    calendar_data: Option<String>,
+}
+
+/// A parsed WebDAV multistatus body.
+#[derive(Debug, Clone, Default)]
+struct Multistatus {
+    responses: Vec<MultistatusResponse>,
+    sync_token: Option<String>,
+}
+
+/// Parse the status code out of a `DAV:status` element, e.g. `HTTP/1.1 404
+/// Not Found`.
+fn parse_status_line(line: &str) -> Option<u16> {
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Parse a WebDAV multistatus body, as returned by `calendar-query` and
+/// `sync-collection` reports.
+fn parse_multistatus(body: &str) -> Result<Multistatus, Error> {
+    let doc = roxmltree::Document::parse(body)
+        .map_err(|e| anyhow!(e))
+        .with_context(|| "decoding xml")?;
+
+    let mut multistatus = Multistatus::default();
+
+    for node in doc.root_element().children() {
+        match node.tag_name().name() {
+            "sync-token" => multistatus.sync_token = node.text().map(|t| t.trim().to_string()),
+            "response" => {}
+            _ => continue,
+        }
+
+        let mut response = MultistatusResponse::default();
+
+        for child in node.descendants() {
+            match child.tag_name().name() {
+                "href" if child.parent() == Some(node) => {
+                    response.href = child.text().unwrap_or_default().trim().to_string();
+                }
+                // Only the status directly under the `response` refers to the
+                // resource itself, the others are per property.
+                "status" if child.parent() == Some(node) => {
+                    response.status = child.text().and_then(parse_status_line);
+                }
+                "getetag" => response.etag = child.text().map(|t| t.trim().to_string()),
+                "calendar-data" => response.calendar_data = child.text().map(str::to_string),
+                _ => {}
+            }
+        }
+
+        multistatus.responses.push(response);
+    }
+
+    Ok(multistatus)
+}
+
+/// Escape a string for inclusion in an XML body.
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Send a REPORT request to the CalDAV server, returning the status and body.
+async fn send_report(
     client: &reqwest::Client,
     url: &str,
     user_name: Option<&str>,
     password: Option<&str>,
-) -> Result<Vec<VCalendar>, Error> {
+    depth: &str,
+    body: String,
+) -> Result<(reqwest::StatusCode, String), Error> {
     let mut req = client
         .request(Method::from_str("REPORT").expect("method"), url)
-        .header("Content-Type", "application/xml");
+        .header("Content-Type", "application/xml")
+        .header("Depth", depth);
 
     if let Some(user) = user_name {
         req = req.basic_auth(user, password);
     }
 
-    let resp = req
-        .body(format!(
+    let resp = req.body(body).send().await?;
+
+    let status = resp.status();
+
+    let body = resp.text().await?;
+
+    info!(status = status.as_u16(), "Got result from CalDAV");
+    Span::current().record("status", &status.as_u16());
+
+    Ok((status, body))
+}
+
+/// Fetch all calendar resources from a CalDAV URL using a `calendar-query`
+/// report.
+///
+/// Note that CalDAV returns a calendar per event, rather than one calendar with
+/// many events.
+#[instrument(skip(client, password), fields(status))]
+pub async fn fetch_calendar_resources(
+    client: &reqwest::Client,
+    url: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+    look_ahead: Duration,
+    keep_after: Duration,
+) -> Result<Vec<CalendarResource>, Error> {
+    let now = Utc::now();
+
+    let (status, body) = send_report(
+        client,
+        url,
+        user_name,
+        password,
+        "1",
+        format!(
             r#"
         <c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
             <d:prop>
@@ -56,71 +195,699 @@ pub async fn fetch_calendars(
             <c:filter>
                 <c:comp-filter name="VCALENDAR">
                     <c:comp-filter name="VEVENT" >
-                    <c:time-range start="{start}" />
+                    <c:time-range start="{start}" end="{end}" />
                     </c:comp-filter>
                 </c:comp-filter>
             </c:filter>
         </c:calendar-query>
         "#,
-            start = Utc::now().format("%Y%m%dT%H%M%SZ")
-        ))
-        .send()
+            // The time range matches events that overlap it, so this includes
+            // ones that ended less than `keep_after` ago.
+            start = (now - keep_after).format("%Y%m%dT%H%M%SZ"),
+            end = (now + look_ahead).format("%Y%m%dT%H%M%SZ"),
+        ),
+    )
+    .await?;
+
+    if !status.is_success() {
+        return Err(HttpStatusError {
+            status: status.as_u16(),
+            server: "CalDAV",
+        }
+        .into());
+    }
+
+    let multistatus = parse_multistatus(&body)?;
+
+    Ok(multistatus
+        .responses
+        .into_iter()
+        .filter_map(|response| {
+            Some(CalendarResource {
+                calendar_data: response.calendar_data?,
+                href: response.href,
+                etag: response.etag,
+                last_modified: None,
+            })
+        })
+        .collect())
+}
+
+/// Fetch the changes to a CalDAV collection since `sync_token` using a
+/// `sync-collection` report (RFC 6578). An empty token fetches the full
+/// collection.
+///
+/// Returns `None` if the server has invalidated the sync token.
+#[instrument(skip(client, password), fields(status))]
+async fn sync_collection(
+    client: &reqwest::Client,
+    url: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+    sync_token: &str,
+) -> Result<Option<SyncChanges>, Error> {
+    let mut changes = SyncChanges {
+        full: sync_token.is_empty(),
+        ..Default::default()
+    };
+
+    let mut sync_token = sync_token.to_string();
+
+    // The server may truncate the results, in which case we need to keep
+    // asking for more using the new sync token.
+    loop {
+        let (status, body) = send_report(
+            client,
+            url,
+            user_name,
+            password,
+            "0",
+            format!(
+                r#"
+            <d:sync-collection xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                <d:sync-token>{sync_token}</d:sync-token>
+                <d:sync-level>1</d:sync-level>
+                <d:prop>
+                    <d:getetag />
+                    <c:calendar-data />
+                </d:prop>
+            </d:sync-collection>
+            "#,
+                sync_token = escape_xml(&sync_token),
+            ),
+        )
         .await?;
 
-    let status = resp.status();
+        if status.is_client_error() && body.contains("valid-sync-token") {
+            return Ok(None);
+        }
 
-    let body = resp.text().await?;
+        if !status.is_success() {
+            return Err(HttpStatusError {
+                status: status.as_u16(),
+                server: "CalDAV",
+            }
+            .into());
+        }
 
-    info!(status = status.as_u16(), "Got result from CalDAV");
+        let multistatus = parse_multistatus(&body)?;
+
+        let mut truncated = false;
+        for response in multistatus.responses {
+            match (response.status, response.calendar_data) {
+                (Some(404), _) => changes.deleted.push(response.href),
+                (Some(507), _) => truncated = true,
+                (_, Some(calendar_data)) => changes.changed.push(CalendarResource {
+                    href: response.href,
+                    etag: response.etag,
+                    last_modified: None,
+                    calendar_data,
+                }),
+                // This is probably the collection itself.
+                (_, None) => {}
+            }
+        }
+
+        let new_sync_token = multistatus
+            .sync_token
+            .context("Server didn't return a sync token")?;
+
+        if !truncated || new_sync_token == sync_token {
+            changes.sync_token = Some(new_sync_token);
+            return Ok(Some(changes));
+        }
+
+        sync_token = new_sync_token;
+    }
+}
+
+/// Fetch what has changed in a CalDAV collection.
+///
+/// Uses `sync-collection` if the server supports it, falling back to a full
+/// `calendar-query` otherwise.
+///
+/// `look_ahead` and `keep_after` only bound the `calendar-query` fallback, as
+/// `sync-collection` can't filter by time: in that case every resource in the
+/// collection is returned, and they're only applied when the events are
+/// expanded.
+#[instrument(skip(client, password, sync_token))]
+pub async fn sync_calendar(
+    client: &reqwest::Client,
+    url: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+    sync_token: Option<&str>,
+    look_ahead: Duration,
+    keep_after: Duration,
+) -> Result<SyncChanges, Error> {
+    if let Some(sync_token) = sync_token {
+        match sync_collection(client, url, user_name, password, sync_token).await? {
+            Some(changes) => return Ok(changes),
+            None => info!("Sync token was invalidated, doing a full sync"),
+        }
+    }
+
+    match sync_collection(client, url, user_name, password, "").await {
+        Ok(Some(changes)) => return Ok(changes),
+        Ok(None) => warn!("Server rejected empty sync token"),
+        Err(e) => warn!(
+            error = e.deref() as &dyn std::error::Error,
+            "Failed to do sync-collection, falling back to calendar-query"
+        ),
+    }
+
+    let resources =
+        fetch_calendar_resources(client, url, user_name, password, look_ahead, keep_after).await?;
+
+    Ok(SyncChanges {
+        full: true,
+        changed: resources,
+        deleted: Vec::new(),
+        sync_token: None,
+    })
+}
+
+/// Fetch a plain ICS feed, e.g. a webcal:// subscription or a published
+/// calendar link.
+///
+/// If we have fetched the feed before then `previous` is used to make a
+/// conditional request, returning `None` if the feed hasn't changed.
+#[instrument(skip(client, password, previous), fields(status))]
+pub async fn fetch_ics_feed(
+    client: &reqwest::Client,
+    url: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+    previous: Option<&CalendarResource>,
+) -> Result<Option<CalendarResource>, Error> {
+    // webcal:// is just a hint to open the URL in a calendar app, the feed
+    // itself is served over HTTP(S).
+    let fetch_url = if let Some(rest) = url.strip_prefix("webcals://") {
+        format!("https://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("webcal://") {
+        format!("https://{}", rest)
+    } else {
+        url.to_string()
+    };
+
+    let mut req = client.get(&fetch_url).header("Accept", "text/calendar");
+
+    if let Some(user) = user_name {
+        req = req.basic_auth(user, password);
+    }
+
+    if let Some(previous) = previous {
+        if let Some(etag) = &previous.etag {
+            req = req.header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &previous.last_modified {
+            req = req.header("If-Modified-Since", last_modified);
+        }
+    }
+
+    let resp = req.send().await?;
+
+    let status = resp.status();
+
+    info!(status = status.as_u16(), "Got result from ICS feed");
     Span::current().record("status", &status.as_u16());
 
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+
     if !status.is_success() {
-        bail!("Got {} result from CalDAV", status.as_u16());
+        return Err(HttpStatusError {
+            status: status.as_u16(),
+            server: "ICS feed",
+        }
+        .into());
     }
 
-    let doc = roxmltree::Document::parse(&body)
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header("ETag");
+    let last_modified = header("Last-Modified");
+
+    let calendar_data = resp.text().await?;
+
+    Ok(Some(CalendarResource {
+        href: url.to_string(),
+        etag,
+        last_modified,
+        calendar_data,
+    }))
+}
+
+/// A calendar collection found by [`discover_calendars`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredCalendar {
+    pub url: String,
+    pub display_name: Option<String>,
+}
+
+/// Send a PROPFIND request, returning the URL that answered and the body.
+///
+/// We follow redirects ourselves, as reqwest would turn the request into a
+/// GET.
+async fn propfind(
+    client: &reqwest::Client,
+    mut url: Url,
+    depth: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+    body: &str,
+) -> Result<(Url, String), Error> {
+    for _ in 0..5 {
+        let mut req = client
+            .request(Method::from_str("PROPFIND").expect("method"), url.clone())
+            .header("Content-Type", "application/xml")
+            .header("Depth", depth);
+
+        if let Some(user) = user_name {
+            req = req.basic_auth(user, password);
+        }
+
+        let resp = req.body(body.to_string()).send().await?;
+
+        let status = resp.status();
+
+        info!(
+            status = status.as_u16(),
+            url = url.as_str(),
+            "Got PROPFIND result"
+        );
+
+        if status.is_redirection() {
+            let location = resp
+                .headers()
+                .get("Location")
+                .and_then(|v| v.to_str().ok())
+                .context("Redirect without a Location header")?;
+            url = url.join(location)?;
+            continue;
+        }
+
+        if !status.is_success() {
+            bail!("Got {} result from PROPFIND of {}", status.as_u16(), url);
+        }
+
+        return Ok((url, resp.text().await?));
+    }
+
+    bail!("Too many redirects")
+}
+
+/// Find the `href` of the first property with the given name in a multistatus
+/// body.
+fn find_href_property(body: &str, property: &str) -> Result<Option<String>, Error> {
+    let doc = roxmltree::Document::parse(body)
+        .map_err(|e| anyhow!(e))
+        .with_context(|| "decoding xml")?;
+
+    let href = doc
+        .descendants()
+        .filter(|node| node.tag_name().name() == property)
+        .flat_map(|node| node.children())
+        .find(|node| node.tag_name().name() == "href")
+        .and_then(|node| node.text())
+        .map(|href| href.trim().to_string());
+
+    Ok(href)
+}
+
+/// Parse the listing of a calendar home set into the calendar collections that
+/// support events.
+fn parse_calendar_collections(
+    home_url: &Url,
+    body: &str,
+) -> Result<Vec<DiscoveredCalendar>, Error> {
+    let doc = roxmltree::Document::parse(body)
         .map_err(|e| anyhow!(e))
         .with_context(|| "decoding xml")?;
 
     let mut calendars = Vec::new();
 
-    for node in doc.descendants() {
-        if node.tag_name().name() != "calendar-data" {
-            continue;
+    for response in doc
+        .root_element()
+        .children()
+        .filter(|node| node.tag_name().name() == "response")
+    {
+        let mut href = None;
+        let mut display_name = None;
+        let mut is_calendar = false;
+        let mut supports_events = true;
+
+        for node in response.descendants() {
+            match node.tag_name().name() {
+                "href" if node.parent() == Some(response) => href = node.text(),
+                "displayname" => {
+                    display_name = node
+                        .text()
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                }
+                "resourcetype" => {
+                    is_calendar = node
+                        .children()
+                        .any(|child| child.tag_name().name() == "calendar")
+                }
+                // If the server doesn't tell us what components the collection
+                // supports we assume it supports events.
+                "supported-calendar-component-set" => {
+                    supports_events = node
+                        .children()
+                        .any(|child| child.attribute("name") == Some("VEVENT"))
+                }
+                _ => {}
+            }
         }
 
-        let cal_body = if let Some(t) = node.text() {
-            t
-        } else {
-            continue;
-        };
+        if let (Some(href), true, true) = (href, is_calendar, supports_events) {
+            calendars.push(DiscoveredCalendar {
+                url: home_url.join(href.trim())?.to_string(),
+                display_name,
+            });
+        }
+    }
+
+    Ok(calendars)
+}
+
+/// Find the calendar collections available to the user on a CalDAV server,
+/// given the server's base URL.
+///
+/// This follows RFC 6764 and RFC 4791: we look up the user's principal (trying
+/// `/.well-known/caldav` first), then the principal's calendar home set, and
+/// then list the calendars in the home set.
+#[instrument(skip(password))]
+pub async fn discover_calendars(
+    base_url: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+) -> Result<Vec<DiscoveredCalendar>, Error> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    let base_url = Url::parse(base_url).with_context(|| "parsing server URL")?;
+
+    let mut principal_url = None;
+    for candidate in [base_url.join("/.well-known/caldav")?, base_url.clone()] {
+        let result = propfind(
+            &client,
+            candidate,
+            "0",
+            user_name,
+            password,
+            r#"
+            <d:propfind xmlns:d="DAV:">
+                <d:prop>
+                    <d:current-user-principal />
+                </d:prop>
+            </d:propfind>
+            "#,
+        )
+        .await;
+
+        match result {
+            Ok((url, body)) => {
+                if let Some(href) = find_href_property(&body, "current-user-principal")? {
+                    principal_url = Some(url.join(&href)?);
+                    break;
+                }
+            }
+            Err(e) => info!(
+                error = e.deref() as &dyn std::error::Error,
+                "Failed to find principal"
+            ),
+        }
+    }
+
+    let principal_url = principal_url.context("Could not find the CalDAV principal")?;
 
-        match decode_calendar(cal_body) {
-            Ok(cals) => calendars.extend(cals),
+    let (url, body) = propfind(
+        &client,
+        principal_url,
+        "0",
+        user_name,
+        password,
+        r#"
+        <d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+            <d:prop>
+                <c:calendar-home-set />
+            </d:prop>
+        </d:propfind>
+        "#,
+    )
+    .await?;
+
+    let home_href =
+        find_href_property(&body, "calendar-home-set")?.context("No calendar home set found")?;
+    let home_url = url.join(&home_href)?;
+
+    let (url, body) = propfind(
+        &client,
+        home_url,
+        "1",
+        user_name,
+        password,
+        r#"
+        <d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+            <d:prop>
+                <d:resourcetype />
+                <d:displayname />
+                <c:supported-calendar-component-set />
+            </d:prop>
+        </d:propfind>
+        "#,
+    )
+    .await?;
+
+    parse_calendar_collections(&url, &body)
+}
+
+/// Decode the stored calendar resources, along with the ETag of the resource
+/// each came from, logging and skipping any that fail to parse.
+pub fn decode_resources(resources: &[CalendarResource]) -> Vec<(Option<String>, VCalendar)> {
+    let mut calendars = Vec::new();
+
+    for resource in resources {
+        match decode_calendar(&resource.calendar_data) {
+            Ok(cals) => calendars.extend(cals.into_iter().map(|cal| (resource.etag.clone(), cal))),
             Err(e) => error!(
                 error = e.deref() as &dyn std::error::Error,
+                href = resource.href.deref(),
                 "Failed to parse event"
             ),
         }
     }
 
-    Ok(calendars)
+    calendars
+}
+
+/// Split the stored calendar resources into the raw ICS of each event, by
+/// event ID.
+///
+/// Each event gets its own VCALENDAR containing its VEVENTs (including
+/// overridden occurrences), along with the calendar's properties and
+/// timezones, so that it can be inspected or downloaded on its own.
+pub fn split_resources_by_event(resources: &[CalendarResource]) -> HashMap<String, String> {
+    let mut sources = HashMap::new();
+
+    for resource in resources {
+        for (uid, ics) in split_calendar_by_event(&resource.calendar_data) {
+            sources.insert(uid, ics);
+        }
+    }
+
+    sources
+}
+
+/// Split a raw ICS calendar into a standalone VCALENDAR per event.
+fn split_calendar_by_event(cal_body: &str) -> Vec<(String, String)> {
+    let mut header = Vec::new();
+    let mut timezones = Vec::new();
+    let mut events: HashMap<String, Vec<&str>> = HashMap::new();
+
+    // The lines of the top level component we're currently in, if any.
+    let mut component: Vec<&str> = Vec::new();
+    let mut depth = 0;
+
+    for line in cal_body.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let upper = line.to_ascii_uppercase();
+        if upper.starts_with("BEGIN:") {
+            depth += 1;
+        }
+
+        match depth {
+            0 => {}
+            1 if component.is_empty() => {
+                if !upper.starts_with("BEGIN:VCALENDAR") && !upper.starts_with("END:VCALENDAR") {
+                    header.push(line);
+                }
+            }
+            _ => component.push(line),
+        }
+
+        if upper.starts_with("END:") {
+            depth -= 1;
+
+            if depth == 1 && !component.is_empty() {
+                let lines = std::mem::take(&mut component);
+                let kind = lines[0].to_ascii_uppercase();
+                if kind.starts_with("BEGIN:VTIMEZONE") {
+                    timezones.extend(lines);
+                } else if kind.starts_with("BEGIN:VEVENT") {
+                    if let Some(uid) = get_component_uid(&lines) {
+                        events.entry(uid).or_default().extend(lines);
+                    }
+                }
+            }
+        }
+    }
+
+    events
+        .into_iter()
+        .map(|(uid, lines)| {
+            let mut ics = String::from("BEGIN:VCALENDAR\r\n");
+            for line in header.iter().chain(&timezones).chain(&lines) {
+                ics.push_str(line);
+                ics.push_str("\r\n");
+            }
+            ics.push_str("END:VCALENDAR\r\n");
+
+            (uid, ics)
+        })
+        .collect()
+}
+
+/// Get the UID of a component from its raw lines, ignoring any
+/// subcomponents.
+fn get_component_uid(lines: &[&str]) -> Option<String> {
+    let mut depth = 0;
+
+    for (index, line) in lines.iter().enumerate() {
+        let upper = line.to_ascii_uppercase();
+        if upper.starts_with("BEGIN:") {
+            depth += 1;
+        } else if upper.starts_with("END:") {
+            depth -= 1;
+        } else if depth == 1 && (upper.starts_with("UID:") || upper.starts_with("UID;")) {
+            // Long lines are folded onto continuation lines starting with
+            // whitespace.
+            let mut unfolded = line.to_string();
+            for continuation in &lines[index + 1..] {
+                match continuation.strip_prefix(|c| c == ' ' || c == '\t') {
+                    Some(rest) => unfolded.push_str(rest),
+                    None => break,
+                }
+            }
+
+            let (_, uid) = unfolded.split_once(':')?;
+            return Some(uid.to_string());
+        }
+    }
+
+    None
+}
+
+/// Interpret the wall clock time of an all-day or floating event instance in
+/// the given timezone.
+///
+/// These don't have a timezone of their own, so the parser hands them to us as
+/// if they were in UTC.
+fn localize(date: DateTime<FixedOffset>, timezone: Tz) -> Option<DateTime<FixedOffset>> {
+    let local = timezone
+        .from_local_datetime(&date.naive_local())
+        .earliest()?;
+    let offset = local.offset().fix();
+
+    Some(local.with_timezone(&offset))
+}
+
+/// How an event recurs, used to spot events that have been replaced by new
+/// ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recurrence {
+    /// A one-off event.
+    Single,
+    /// A recurring event that hasn't been given an end date.
+    Ongoing,
+    /// A recurring event that has been given an end date.
+    Ended,
+}
+
+/// The events parsed out of a calendar.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedCalendar {
+    pub events: Vec<Event>,
+    pub instances: Vec<EventInstance>,
+    /// How each event in the calendar recurs, by event ID. This includes
+    /// events that have no upcoming instances.
+    pub recurrence: HashMap<String, Recurrence>,
+    /// How many minutes before the start of each event its alarms go off, by
+    /// event ID. Events without alarms are omitted.
+    pub alarms: HashMap<String, Vec<i64>>,
+    /// The raw ICS of each event, by event ID. Empty for sources that don't
+    /// use ICS.
+    pub sources: HashMap<String, String>,
+}
+
+impl ParsedCalendar {
+    /// Add the events parsed from another resource of the same calendar.
+    pub fn extend(&mut self, other: ParsedCalendar) {
+        self.events.extend(other.events);
+        self.instances.extend(other.instances);
+        self.recurrence.extend(other.recurrence);
+        self.alarms.extend(other.alarms);
+        self.sources.extend(other.sources);
+    }
 }
 
 /// Parse the calendars into events and event instances.
+///
+/// All-day and floating events are resolved using the given timezone.
+/// Instances that have already started are kept until `keep_after` past their
+/// end, so that reminders after the end can still be sent.
 pub fn parse_calendars_to_events(
     calendar_id: i64,
-    calendars: &[VCalendar],
-) -> Result<(Vec<Event>, Vec<EventInstance>), Error> {
+    calendars: &[(Option<String>, VCalendar)],
+    timezone: Tz,
+    look_ahead: Duration,
+    keep_after: Duration,
+) -> Result<ParsedCalendar, Error> {
     let now = Utc::now();
-    let mut events = Vec::new();
-    let mut next_dates = Vec::new();
-    for calendar in calendars {
+    let mut parsed = ParsedCalendar::default();
+    for (etag, calendar) in calendars {
         for (uid, event) in &calendar.events {
-            if event.base_event.is_full_day_event() || event.base_event.is_floating_event() {
-                continue;
+            let recurrence = match &event.base_event.recur {
+                None => Recurrence::Single,
+                Some(recur) => match recur.end_condition {
+                    EndCondition::Count(_) | EndCondition::Infinite => Recurrence::Ongoing,
+                    EndCondition::Until(_) | EndCondition::UntilUtc(_) => Recurrence::Ended,
+                },
+            };
+            parsed.recurrence.insert(uid.clone(), recurrence);
+
+            let alarms = get_alarm_offsets(&event.base_event, timezone);
+            if !alarms.is_empty() {
+                parsed.alarms.insert(uid.clone(), alarms);
             }
 
+            let all_day = event.base_event.is_full_day_event();
+            let event_cancelled = is_cancelled(&event.base_event);
+            let floating = event.base_event.is_floating_event();
+
             let mut organizer = None;
             for prop in &event.base_event.properties {
                 if let ics_parser::property::Property::Organizer(prop) = prop {
@@ -128,34 +895,367 @@ pub fn parse_calendars_to_events(
                 }
             }
 
-            events.push(Event {
+            let recur_iter = match event.recur_iter(calendar) {
+                Ok(recur_iter) => recur_iter,
+                Err(e) => {
+                    error!(
+                        error = e.deref() as &dyn std::error::Error,
+                        event_id = uid.deref(),
+                        "Failed to expand event"
+                    );
+                    continue;
+                }
+            };
+
+            let description = get_description(&event.base_event);
+
+            parsed.events.push(Event {
                 calendar_id,
                 event_id: uid.clone(),
                 summary: event.base_event.summary.clone(),
-                description: event.base_event.description.clone(),
+                description: description.text,
+                description_html: description.html,
+                description_from_html: description.from_html,
                 location: event.base_event.location.clone(),
                 organizer,
                 attendees: get_attendees(&event.base_event),
+                all_day,
+                etag: etag.clone(),
+                join_url: get_join_url(&event.base_event),
+                categories: get_categories(&event.base_event),
             });
 
+            // We want to include all-day events that are happening today.
+            let earliest = if all_day {
+                now - Duration::days(1)
+            } else {
+                now
+            };
+
             // Loop through all occurrences of the event in the next N days and
             // generate `EventInstance` for them.
-            for (date, recur_event) in event
-                .recur_iter(calendar)?
-                .skip_while(|(d, _)| *d < now)
-                .take_while(|(d, _)| *d < now + Duration::days(30))
-            {
-                // Loop over all the properties to pull out the attendee info.
+            for (date, recur_event) in recur_iter {
+                let end = get_duration(recur_event, all_day).map(|duration| date + duration);
+
+                let (date, end) = if all_day || floating {
+                    match localize(date, timezone) {
+                        Some(date) => (date, end.and_then(|end| localize(end, timezone))),
+                        None => continue,
+                    }
+                } else {
+                    (date, end)
+                };
+
+                if date < earliest && end.unwrap_or(date) + keep_after < now {
+                    continue;
+                }
 
-                next_dates.push(EventInstance {
+                if date >= now + look_ahead {
+                    break;
+                }
+
+                // Individual occurrences can be cancelled by overriding them,
+                // while excluded dates are already skipped by the iterator.
+                parsed.instances.push(EventInstance {
                     event_id: uid.into(),
                     date,
+                    end,
                     attendees: get_attendees(recur_event),
+                    cancelled: event_cancelled || is_cancelled(recur_event),
+                    join_url: get_join_url(recur_event),
                 });
             }
         }
     }
-    Ok((events, next_dates))
+    Ok(parsed)
+}
+
+/// The hosts of video-conferencing services whose links we pick out of
+/// events, including their subdomains.
+const CONFERENCE_HOSTS: &[&str] = &[
+    "zoom.us",
+    "teams.microsoft.com",
+    "teams.live.com",
+    "meet.google.com",
+    "meet.jit.si",
+    "whereby.com",
+    "webex.com",
+];
+
+/// Get the event's categories, which may be split across multiple CATEGORIES
+/// properties.
+fn get_categories(event: &VEvent) -> Vec<String> {
+    let mut categories = Vec::new();
+
+    for prop in &event.properties {
+        if let Property::Unknown(name, prop) = prop {
+            if name.eq_ignore_ascii_case("CATEGORIES") {
+                categories.extend(
+                    prop.value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|c| !c.is_empty())
+                        .map(String::from),
+                );
+            }
+        }
+    }
+
+    categories
+}
+
+/// Get the event's description, along with the original HTML if it had any.
+///
+/// The HTML either comes from an `X-ALT-DESC` property, as sent by Outlook, or
+/// from an HTML `DESCRIPTION`, as sent by Google. A plain `DESCRIPTION` is
+/// preferred over an `X-ALT-DESC`, and stays plain text.
+fn get_description(event: &VEvent) -> Description {
+    let mut alt_description = None;
+    for prop in &event.properties {
+        if let Property::Unknown(name, prop) = prop {
+            if !name.eq_ignore_ascii_case("X-ALT-DESC") {
+                continue;
+            }
+
+            let is_html = prop.parameters.parameters().any(|param| match param {
+                ics_parser::parameters::Parameter::Other(name, value) => {
+                    name.eq_ignore_ascii_case("FMTTYPE") && value.eq_ignore_ascii_case("text/html")
+                }
+                _ => false,
+            });
+            if is_html {
+                alt_description = Some(prop.value.clone());
+            }
+        }
+    }
+
+    let description = event.description.clone().filter(|d| !d.trim().is_empty());
+
+    match (description, alt_description) {
+        (Some(description), _) if looks_like_html(&description) => {
+            Description::from_html(description)
+        }
+        (Some(description), alt_description) => Description::plain(description, alt_description),
+        (None, Some(alt_description)) => Description::from_html(alt_description),
+        (None, None) => Description::default(),
+    }
+}
+
+/// Get the link to join the event's video call, if it has one.
+///
+/// We prefer the dedicated conference properties, then fall back to a
+/// conferencing link in the URL, location or description.
+fn get_join_url(event: &VEvent) -> Option<String> {
+    let mut url_prop = None;
+
+    for prop in &event.properties {
+        if let Property::Unknown(name, prop) = prop {
+            if name.eq_ignore_ascii_case("CONFERENCE")
+                || name.eq_ignore_ascii_case("X-GOOGLE-CONFERENCE")
+            {
+                if let Ok(url) = Url::parse(prop.value.trim()) {
+                    if url.scheme() == "https" || url.scheme() == "http" {
+                        return Some(url.into());
+                    }
+                }
+            } else if name.eq_ignore_ascii_case("URL") {
+                url_prop = Some(prop.value.as_str());
+            }
+        }
+    }
+
+    url_prop
+        .into_iter()
+        .chain(event.location.as_deref())
+        .chain(event.description.as_deref())
+        .find_map(find_join_url)
+}
+
+/// Find the first link to a known video-conferencing service in the text.
+pub fn find_join_url(text: &str) -> Option<String> {
+    text.split(|c: char| c.is_whitespace() || "<>\"'()[]".contains(c))
+        .filter_map(|word| Url::parse(word.trim_end_matches(&['.', ',', ';'][..])).ok())
+        .find(is_conference_url)
+        .map(String::from)
+}
+
+/// Whether the URL is for one of the known video-conferencing services.
+fn is_conference_url(url: &Url) -> bool {
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return false;
+    }
+
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => return false,
+    };
+
+    CONFERENCE_HOSTS
+        .iter()
+        .any(|h| host == *h || host.ends_with(&format!(".{}", h)))
+}
+
+/// Get how many minutes before the start of the event each of its alarms go
+/// off.
+///
+/// Alarms that go off after the event has started are ignored.
+fn get_alarm_offsets(event: &VEvent, timezone: Tz) -> Vec<i64> {
+    let all_day = event.is_full_day_event();
+
+    let mut start = None;
+    for prop in &event.properties {
+        if let Property::DateTimeStart(prop) = prop {
+            start = Some(prop.value);
+        }
+    }
+
+    // Absolute alarms on all-day and floating events are relative to midnight
+    // in the calendar's timezone.
+    if all_day || event.is_floating_event() {
+        start = start.and_then(|start| localize(start, timezone));
+    }
+
+    let mut offsets = Vec::new();
+    for alarm in &event.alarms {
+        for prop in &alarm.properties {
+            let (name, trigger) = match prop {
+                Property::Unknown(name, trigger) => (name, trigger),
+                _ => continue,
+            };
+
+            if !name.eq_ignore_ascii_case("TRIGGER") {
+                continue;
+            }
+
+            let mut related_end = false;
+            let mut absolute = false;
+            for param in trigger.parameters.parameters() {
+                if let ics_parser::parameters::Parameter::Other(name, value) = param {
+                    if name.eq_ignore_ascii_case("RELATED") {
+                        related_end = value.eq_ignore_ascii_case("END");
+                    } else if name.eq_ignore_ascii_case("VALUE") {
+                        absolute = value.eq_ignore_ascii_case("DATE-TIME");
+                    }
+                }
+            }
+
+            let minutes_before = if absolute {
+                let time = match parse_ics_date_time(&trigger.value) {
+                    Some(time) => time,
+                    None => continue,
+                };
+
+                match start {
+                    Some(start) => (start.with_timezone(&Utc) - time).num_minutes(),
+                    None => continue,
+                }
+            } else {
+                let mut offset = match parse_ics_duration(&trigger.value) {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+
+                if related_end {
+                    match get_duration(event, all_day) {
+                        Some(duration) => offset = offset + duration,
+                        None => continue,
+                    }
+                }
+
+                -offset.num_minutes()
+            };
+
+            if minutes_before >= 0 {
+                offsets.push(minutes_before);
+            }
+        }
+    }
+
+    offsets.sort_unstable();
+    offsets.dedup();
+
+    offsets
+}
+
+/// Parse a UTC DATE-TIME value, e.g. `19980101T050000Z`.
+fn parse_ics_date_time(value: &str) -> Option<DateTime<Utc>> {
+    Utc.datetime_from_str(value.trim(), "%Y%m%dT%H%M%SZ").ok()
+}
+
+/// Parse a DURATION value, e.g. `-PT15M` or `P1DT12H`.
+fn parse_ics_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+
+    let value = value.strip_prefix('P')?;
+
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if number.is_empty() => in_time = true,
+            _ => {
+                // Anything this large is nonsense, and would overflow.
+                let n: i64 = number.parse().ok().filter(|n| *n <= 1_000_000)?;
+                number.clear();
+
+                duration = duration
+                    + match (c, in_time) {
+                        ('W', false) => Duration::weeks(n),
+                        ('D', false) => Duration::days(n),
+                        ('H', true) => Duration::hours(n),
+                        ('M', true) => Duration::minutes(n),
+                        ('S', true) => Duration::seconds(n),
+                        _ => return None,
+                    };
+            }
+        }
+    }
+
+    if !number.is_empty() {
+        return None;
+    }
+
+    Some(if negative { -duration } else { duration })
+}
+
+/// Whether the event has been cancelled, i.e. has STATUS:CANCELLED.
+fn is_cancelled(event: &VEvent) -> bool {
+    event.properties.iter().any(|prop| match prop {
+        Property::Status(prop) => prop.value.eq_ignore_ascii_case("CANCELLED"),
+        _ => false,
+    })
+}
+
+/// Get how long the event lasts, from either its DURATION or the difference
+/// between its DTSTART and DTEND.
+///
+/// All-day events without either last a single day, while for other events we
+/// don't know the end.
+fn get_duration(event: &VEvent, all_day: bool) -> Option<Duration> {
+    let mut start = None;
+    let mut end = None;
+
+    for prop in &event.properties {
+        match prop {
+            Property::Duration(prop) => return Some(prop.value),
+            Property::DateTimeStart(prop) => start = Some(prop.value),
+            Property::DateTimeEnd(prop) => end = Some(prop.value),
+            _ => {}
+        }
+    }
+
+    match (start, end) {
+        (Some(start), Some(end)) if end >= start => Some(end - start),
+        _ if all_day => Some(Duration::days(1)),
+        _ => None,
+    }
 }
 
 /// Parse the attendees from the event.
@@ -182,19 +1282,281 @@ fn parse_to_attendee(prop: &PropertyValue<Url>) -> Option<Attendee> {
     let email = prop.value.path().to_string();
 
     let mut common_name = None;
+    let mut status = None;
+    let mut role = None;
     for param in prop.parameters.parameters() {
         match param {
             ics_parser::parameters::Parameter::CN(cn) => {
                 common_name = Some(cn.clone());
             }
-            ics_parser::parameters::Parameter::ParticipationStatus(status)
-                if status == "DECLINED" =>
-            {
-                return None
+            ics_parser::parameters::Parameter::ParticipationStatus(partstat) => {
+                status = parse_participation_status(partstat);
+            }
+            ics_parser::parameters::Parameter::Role(r) => {
+                role = parse_participant_role(r);
             }
             _ => {}
         }
     }
 
-    Some(Attendee { email, common_name })
+    Some(Attendee {
+        email,
+        common_name,
+        status,
+        role,
+    })
+}
+
+/// Parse a PARTSTAT parameter, ignoring unknown values.
+fn parse_participation_status(partstat: &str) -> Option<ParticipationStatus> {
+    match partstat.to_ascii_uppercase().as_str() {
+        "NEEDS-ACTION" => Some(ParticipationStatus::NeedsAction),
+        "ACCEPTED" => Some(ParticipationStatus::Accepted),
+        "DECLINED" => Some(ParticipationStatus::Declined),
+        "TENTATIVE" => Some(ParticipationStatus::Tentative),
+        "DELEGATED" => Some(ParticipationStatus::Delegated),
+        _ => None,
+    }
+}
+
+/// Parse a ROLE parameter, ignoring unknown values.
+fn parse_participant_role(role: &str) -> Option<ParticipantRole> {
+    match role.to_ascii_uppercase().as_str() {
+        "CHAIR" => Some(ParticipantRole::Chair),
+        "REQ-PARTICIPANT" => Some(ParticipantRole::Required),
+        "OPT-PARTICIPANT" => Some(ParticipantRole::Optional),
+        "NON-PARTICIPANT" => Some(ParticipantRole::NonParticipant),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Datelike;
+
+    use super::*;
+
+    /// The fixtures use `YYYY` for the year, which we set to next year so that
+    /// the events are always upcoming.
+    fn parse_fixture(fixture: &str, timezone: Tz) -> ParsedCalendar {
+        let year = (Utc::now().year() + 1).to_string();
+        let body = fixture.replace("YYYY", &year);
+
+        let calendars = decode_calendar(&body)
+            .unwrap()
+            .into_iter()
+            .map(|calendar| (Some("etag".to_string()), calendar))
+            .collect::<Vec<_>>();
+
+        parse_calendars_to_events(
+            1,
+            &calendars,
+            timezone,
+            Duration::days(800),
+            Duration::zero(),
+        )
+        .unwrap()
+    }
+
+    /// The instances of the event, as (local time, cancelled) pairs.
+    fn instances(parsed: &ParsedCalendar, event_id: &str) -> Vec<(String, bool)> {
+        parsed
+            .instances
+            .iter()
+            .filter(|instance| instance.event_id == event_id)
+            .map(|instance| (instance.date.to_rfc3339(), instance.cancelled))
+            .collect()
+    }
+
+    fn next_year() -> i32 {
+        Utc::now().year() + 1
+    }
+
+    #[test]
+    fn fastmail_cancelled_override_and_exdate() {
+        let parsed = parse_fixture(include_str!("../tests/fixtures/fastmail.ics"), Tz::UTC);
+        let event_id = "8d3e6f2a-5b1c-4f0e-9a7d-2c4b6e8f0a1b";
+        let y = next_year();
+
+        assert_eq!(parsed.events.len(), 1);
+        assert_eq!(parsed.recurrence[event_id], Recurrence::Ongoing);
+
+        // The 3rd is excluded, and the 4th is cancelled.
+        assert_eq!(
+            instances(&parsed, event_id),
+            vec![
+                (format!("{}-03-02T10:00:00+00:00", y), false),
+                (format!("{}-03-04T10:00:00+00:00", y), true),
+                (format!("{}-03-05T10:00:00+00:00", y), false),
+                (format!("{}-03-06T10:00:00+00:00", y), false),
+            ]
+        );
+
+        let instance = &parsed.instances[0];
+        assert_eq!(instance.end.unwrap() - instance.date, Duration::minutes(15));
+        assert_eq!(instance.attendees.len(), 2);
+        assert_eq!(
+            instance.attendees[1].status,
+            Some(ParticipationStatus::NeedsAction)
+        );
+    }
+
+    #[test]
+    fn google_cancelled_override_exdate_and_event() {
+        let parsed = parse_fixture(include_str!("../tests/fixtures/google.ics"), Tz::UTC);
+        let y = next_year();
+
+        let planning = "3q2tq8c4ek5n1v0j9d8m7l6k5j@google.com";
+        assert_eq!(
+            instances(&parsed, planning),
+            vec![
+                (format!("{}-03-02T09:00:00-05:00", y), false),
+                (format!("{}-03-03T09:00:00-05:00", y), true),
+                (format!("{}-03-04T09:00:00-05:00", y), false),
+                (format!("{}-03-06T09:00:00-05:00", y), false),
+            ]
+        );
+
+        let event = parsed
+            .events
+            .iter()
+            .find(|e| e.event_id == planning)
+            .unwrap();
+        assert_eq!(
+            event.join_url.as_deref(),
+            Some("https://meet.google.com/abc-defg-hij")
+        );
+        assert_eq!(event.attendees[1].role, Some(ParticipantRole::Optional));
+
+        // A cancelled one-off event has all its instances cancelled.
+        assert_eq!(
+            instances(&parsed, "7h6g5f4e3d2c1b0a9z8y7x6w5v@google.com"),
+            vec![(format!("{}-03-10T15:00:00+00:00", y), true)]
+        );
+    }
+
+    #[test]
+    fn nextcloud_all_day_and_utc_exdates() {
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let parsed = parse_fixture(include_str!("../tests/fixtures/nextcloud.ics"), timezone);
+        let y = next_year();
+
+        let review = "f0e1d2c3-b4a5-4968-8776-5a4b3c2d1e0f";
+        assert_eq!(parsed.recurrence[review], Recurrence::Ended);
+        assert_eq!(
+            instances(&parsed, review),
+            vec![
+                (format!("{}-03-02T14:00:00+00:00", y), false),
+                (format!("{}-03-04T14:00:00+00:00", y), false),
+                (format!("{}-03-06T14:00:00+00:00", y), false),
+            ]
+        );
+
+        let review_event = parsed.events.iter().find(|e| e.event_id == review).unwrap();
+        assert_eq!(
+            review_event.join_url.as_deref(),
+            Some("https://meet.jit.si/review")
+        );
+
+        // All-day events start at midnight in the calendar's timezone.
+        let conference = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+        let event = parsed
+            .events
+            .iter()
+            .find(|e| e.event_id == conference)
+            .unwrap();
+        assert!(event.all_day);
+        assert_eq!(event.categories, vec!["Travel", "Work"]);
+        assert_eq!(
+            instances(&parsed, conference),
+            vec![(format!("{}-03-10T00:00:00+00:00", y), false)]
+        );
+    }
+
+    #[test]
+    fn instances_kept_until_after_the_end() {
+        // A 60 minute meeting that started 70 minutes ago, so ended 10 minutes
+        // ago, with a reminder 15 minutes after the end.
+        let start = Utc::now() - Duration::minutes(70);
+        let body = format!(
+            "BEGIN:VCALENDAR\r\n\
+             VERSION:2.0\r\n\
+             PRODID:test\r\n\
+             BEGIN:VEVENT\r\n\
+             UID:retro\r\n\
+             DTSTAMP:{start}\r\n\
+             DTSTART:{start}\r\n\
+             DTEND:{end}\r\n\
+             SUMMARY:Retro\r\n\
+             END:VEVENT\r\n\
+             END:VCALENDAR\r\n",
+            start = start.format("%Y%m%dT%H%M%SZ"),
+            end = (start + Duration::hours(1)).format("%Y%m%dT%H%M%SZ"),
+        );
+
+        let calendars = decode_calendar(&body)
+            .unwrap()
+            .into_iter()
+            .map(|calendar| (None, calendar))
+            .collect::<Vec<_>>();
+
+        let parsed = parse_calendars_to_events(
+            1,
+            &calendars,
+            Tz::UTC,
+            Duration::days(30),
+            Duration::minutes(15),
+        )
+        .unwrap();
+        assert_eq!(instances(&parsed, "retro").len(), 1);
+
+        // Without any reminders after the end it's dropped.
+        let parsed =
+            parse_calendars_to_events(1, &calendars, Tz::UTC, Duration::days(30), Duration::zero())
+                .unwrap();
+        assert!(instances(&parsed, "retro").is_empty());
+    }
+
+    #[test]
+    fn plain_description_with_html_alternative_is_escaped() {
+        let body = format!(
+            "BEGIN:VCALENDAR\r\n\
+             VERSION:2.0\r\n\
+             PRODID:test\r\n\
+             BEGIN:VEVENT\r\n\
+             UID:invite\r\n\
+             DTSTAMP:{y}0301T100000Z\r\n\
+             DTSTART:{y}0301T100000Z\r\n\
+             SUMMARY:Invite\r\n\
+             DESCRIPTION:[click](javascript:alert(1)) <img src=x onerror=alert(1)>\r\n\
+             X-ALT-DESC;FMTTYPE=text/html:<p>Hello</p>\r\n\
+             END:VEVENT\r\n\
+             END:VCALENDAR\r\n",
+            y = next_year(),
+        );
+
+        let calendars = decode_calendar(&body)
+            .unwrap()
+            .into_iter()
+            .map(|calendar| (None, calendar))
+            .collect::<Vec<_>>();
+        let parsed = parse_calendars_to_events(
+            1,
+            &calendars,
+            Tz::UTC,
+            Duration::days(800),
+            Duration::zero(),
+        )
+        .unwrap();
+
+        // The plain description is kept as written, and so must be escaped
+        // when it's sent, even though the event has some HTML.
+        let event = &parsed.events[0];
+        assert!(!event.description_from_html);
+        assert_eq!(
+            event.description.as_deref(),
+            Some("[click](javascript:alert(1)) <img src=x onerror=alert(1)>")
+        );
+        assert_eq!(event.description_html.as_deref(), Some("<p>Hello</p>"));
+    }
 }
@@ -11,6 +11,8 @@ pub struct Config {
 
     pub sso: Option<SsoConfig>,
 
+    pub graph: Option<GraphConfig>,
+
     #[serde(default)]
     pub app: AppConfig,
 }
@@ -30,6 +32,24 @@ pub struct MatrixConfig {
 pub struct AppConfig {
     pub bind_addr: Option<String>,
     pub resource_directory: Option<String>,
+    /// How many days ahead to expand events, unless overridden by a
+    /// calendar. Defaults to 30, and is capped at 3650.
+    pub look_ahead_days: Option<i64>,
+    /// How often to poll calendars, unless overridden by a calendar.
+    /// Defaults to 5, and is kept between 1 and a week.
+    pub poll_interval_minutes: Option<i64>,
+    /// How many calendars to update at once. Defaults to 4.
+    pub max_concurrent_updates: Option<usize>,
+    /// Send the owner of a calendar a Matrix DM once it has failed to update
+    /// this many times in a row. Disabled by default.
+    pub notify_after_failures: Option<i64>,
+    /// The directory that local calendar files can be read from. Local
+    /// calendars are disabled if this isn't set.
+    pub calendar_directory: Option<String>,
+    /// How many minutes late we'll still send a reminder, e.g. if we were
+    /// down when it was due or sending it failed. Defaults to 10, and is
+    /// capped at a week.
+    pub catch_up_minutes: Option<i64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -37,6 +57,30 @@ pub struct HiBobConfig {
     pub token: String,
 }
 
+/// The app's credentials for reading calendars from Microsoft Graph.
+#[derive(Clone, Deserialize, Default)]
+pub struct GraphConfig {
+    pub tenant_id: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Overrides the Graph API endpoint, e.g. for testing.
+    pub base_url: Option<String>,
+    /// Overrides the OAuth2 token endpoint, e.g. for testing.
+    pub token_url: Option<String>,
+}
+
+// We implement this manually so we can stop `client_secret` from being printed.
+impl std::fmt::Debug for GraphConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GraphConfig")
+            .field("tenant_id", &self.tenant_id)
+            .field("client_id", &self.client_id)
+            .field("base_url", &self.base_url)
+            .field("token_url", &self.token_url)
+            .finish()
+    }
+}
+
 #[derive(Clone, Deserialize, Default)]
 pub struct SsoConfig {
     pub display_name: String,
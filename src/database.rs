@@ -1,25 +1,126 @@
 //! Module for talking to the database
 
-use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::ops::Deref;
 
 use anyhow::{Context, Error};
-use chrono::{DateTime, Duration, FixedOffset, Utc};
+use chrono::{DateTime, Duration, FixedOffset, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
 use postgres_types::{FromSql, ToSql};
 use serde::{Deserialize, Serialize};
-use tokio_postgres::NoTls;
+use tokio_postgres::{NoTls, Row};
 use tracing::debug;
 
+use crate::policy::apply_room_policy;
+use crate::rules::{plan_rule_reminders, RuleReminder, RuleReminderChange};
+
+/// The version of what we store for each event and its instances.
+///
+/// Events are only rewritten when their ETag changes, so this needs bumping
+/// whenever parsing starts storing something new (or differently) to make sure
+/// existing events get updated.
+const EVENT_SCHEMA_VERSION: i32 = 3;
+
 /// Async database pool for PostgreSQL.
 pub type PostgresPool = bb8::Pool<bb8_postgres::PostgresConnectionManager<NoTls>>;
 
 /// An attendee of the meeting.
 ///
-/// Includes people who haven't responded, or are tentative/confirmed.
+/// Includes people who have declined, so check their `status` before assuming
+/// they'll be there.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, ToSql, FromSql)]
 pub struct Attendee {
     pub email: String,
     pub common_name: Option<String>,
+    /// The attendee's response, if known.
+    pub status: Option<ParticipationStatus>,
+    /// The attendee's role in the meeting, if known.
+    pub role: Option<ParticipantRole>,
+}
+
+impl Attendee {
+    /// Whether the attendee has declined the meeting.
+    pub fn has_declined(&self) -> bool {
+        self.status == Some(ParticipationStatus::Declined)
+    }
+}
+
+/// An attendee's response to a meeting, i.e. its PARTSTAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ToSql, FromSql)]
+pub enum ParticipationStatus {
+    NeedsAction,
+    Accepted,
+    Declined,
+    Tentative,
+    Delegated,
+}
+
+/// How far we got sending a reminder for an event instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ToSql, FromSql, Serialize)]
+pub enum SendStatus {
+    /// We've started sending it.
+    Sending,
+    Sent,
+    /// Sending failed. These are retried while they're still within the
+    /// catch-up window.
+    Failed,
+    /// We were restarted part way through sending it, so don't know if it
+    /// was sent. These are never retried, but the user can mark them as sent
+    /// or failed.
+    Interrupted,
+}
+
+/// What a reminder's `minutes_before` is relative to.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, ToSql, FromSql, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ReminderAnchor {
+    /// The start of the event instance.
+    #[default]
+    Start,
+    /// The end of the event instance. Reminders anchored to the end are
+    /// skipped for instances without one.
+    End,
+}
+
+/// What to do with a reminder that is due outside of the working hours of the
+/// room it's sent to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ToSql, FromSql, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutsideHours {
+    /// Send it anyway.
+    #[default]
+    Send,
+    /// Send it at the start of the next working period instead.
+    Defer,
+    /// Don't send it.
+    Drop,
+}
+
+/// An attendee's role in a meeting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ToSql, FromSql)]
+pub enum ParticipantRole {
+    Chair,
+    Required,
+    Optional,
+    NonParticipant,
+}
+
+/// How a calendar is fetched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ToSql, FromSql, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarKind {
+    /// A CalDAV collection.
+    #[default]
+    CalDav,
+    /// A plain ICS feed, e.g. a webcal:// subscription URL.
+    Ics,
+    /// An ICS file or directory of them in the configured calendar directory.
+    File,
+    /// A Microsoft 365 / Exchange Online calendar, read via the Graph API.
+    Graph,
 }
 
 /// The URL and credentials of a calendar.
@@ -28,9 +129,80 @@ pub struct Calendar {
     pub user_id: i64,
     pub calendar_id: i64,
     pub name: String,
+    pub kind: CalendarKind,
     pub url: String,
     pub user_name: Option<String>,
     pub password: Option<String>,
+    /// The IANA timezone used for all-day and floating events.
+    pub timezone: Option<String>,
+    /// How many days ahead to expand events, overriding the app default.
+    pub look_ahead_days: Option<i64>,
+    /// How often to poll the calendar, overriding the app default.
+    pub poll_interval_minutes: Option<i64>,
+    /// Whether to turn the alarms on the calendar's events into reminders.
+    pub mirror_alarms: bool,
+    /// The room to send reminders created from alarms to.
+    pub alarm_room: Option<String>,
+    /// What to do with reminders created from alarms that are due outside of
+    /// the room's working hours.
+    pub alarm_outside_hours: OutsideHours,
+    /// Only treat a new event as replacing a previous one if they start at
+    /// the same time of day.
+    pub dedup_same_time_of_day: bool,
+    /// Only treat a new event as replacing a previous one if they start on
+    /// the same weekday.
+    pub dedup_same_weekday: bool,
+}
+
+impl Calendar {
+    /// Parse a calendar from a row of the `calendars` table.
+    fn from_row(row: &Row) -> Result<Calendar, Error> {
+        Ok(Calendar {
+            user_id: row.try_get("user_id")?,
+            calendar_id: row.try_get("calendar_id")?,
+            name: row.try_get("name")?,
+            kind: row.try_get("kind")?,
+            url: row.try_get("url")?,
+            user_name: row.try_get("user_name")?,
+            password: row.try_get("password")?,
+            timezone: row.try_get("timezone")?,
+            look_ahead_days: row.try_get("look_ahead_days")?,
+            poll_interval_minutes: row.try_get("poll_interval_minutes")?,
+            mirror_alarms: row.try_get("mirror_alarms")?,
+            alarm_room: row.try_get("alarm_room")?,
+            alarm_outside_hours: row.try_get("alarm_outside_hours")?,
+            dedup_same_time_of_day: row.try_get("dedup_same_time_of_day")?,
+            dedup_same_weekday: row.try_get("dedup_same_weekday")?,
+        })
+    }
+
+    /// The timezone to use for all-day and floating events, defaulting to UTC.
+    pub fn tz(&self) -> Tz {
+        parse_timezone(self.timezone.as_deref())
+    }
+
+    /// How often to poll the calendar, if it overrides the app default.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_minutes.map(poll_interval)
+    }
+}
+
+/// The longest time between polls of a calendar, which is a week.
+pub const MAX_POLL_INTERVAL_MINUTES: i64 = 7 * 24 * 60;
+
+/// Turn a poll interval in minutes into a duration.
+///
+/// Neither the config nor old rows are validated, so we clamp the value as
+/// `Duration::minutes` panics on huge values and zero would mean polling the
+/// server continuously.
+pub fn poll_interval(minutes: i64) -> Duration {
+    Duration::minutes(minutes.clamp(1, MAX_POLL_INTERVAL_MINUTES))
+}
+
+/// Parse an IANA timezone name, falling back to UTC if it is missing or
+/// invalid.
+fn parse_timezone(timezone: Option<&str>) -> Tz {
+    timezone.and_then(|tz| tz.parse().ok()).unwrap_or(Tz::UTC)
 }
 
 impl std::fmt::Debug for Calendar {
@@ -40,23 +212,88 @@ impl std::fmt::Debug for Calendar {
             .field("user_id", &self.user_id)
             .field("calendar_id", &self.calendar_id)
             .field("name", &self.name)
+            .field("kind", &self.kind)
             .field("url", &self.url)
             .field("user_name", &self.user_name)
             .field("password", &self.password.as_deref().map(|_| "xxxxxxxxx"))
+            .field("timezone", &self.timezone)
+            .field("look_ahead_days", &self.look_ahead_days)
+            .field("poll_interval_minutes", &self.poll_interval_minutes)
+            .field("mirror_alarms", &self.mirror_alarms)
+            .field("alarm_room", &self.alarm_room)
+            .field("alarm_outside_hours", &self.alarm_outside_hours)
+            .field("dedup_same_time_of_day", &self.dedup_same_time_of_day)
+            .field("dedup_same_weekday", &self.dedup_same_weekday)
             .finish()
     }
 }
 
+/// A calendar object resource as last fetched from the server, along with its
+/// raw ICS data.
+#[derive(Debug, Clone)]
+pub struct CalendarResource {
+    pub href: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub calendar_data: String,
+}
+
+/// The outcome of the recent attempts to update a calendar.
+#[derive(Debug, Clone, Serialize)]
+pub struct CalendarStatus {
+    pub calendar_id: i64,
+    pub last_attempt: DateTime<Utc>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// The HTTP status code of the last failure, if it was due to an
+    /// unsuccessful response.
+    pub http_status: Option<i32>,
+    /// The error from the last attempt, if it failed.
+    pub error: Option<String>,
+    /// The number of events found the last time the update succeeded.
+    pub event_count: Option<i64>,
+    pub consecutive_failures: i64,
+}
+
+impl CalendarStatus {
+    /// Parse a status from a row of the `calendar_status` table.
+    fn from_row(row: &Row) -> Result<CalendarStatus, Error> {
+        Ok(CalendarStatus {
+            calendar_id: row.try_get("calendar_id")?,
+            last_attempt: row.try_get("last_attempt")?,
+            last_success: row.try_get("last_success")?,
+            http_status: row.try_get("http_status")?,
+            error: row.try_get("error")?,
+            event_count: row.try_get("event_count")?,
+            consecutive_failures: row.try_get("consecutive_failures")?,
+        })
+    }
+}
+
 /// Basic info for an event.
 #[derive(Debug, Clone)]
 pub struct Event {
     pub calendar_id: i64,
     pub event_id: String,
     pub summary: Option<String>,
+    /// The description as Markdown, converted from HTML if needed.
     pub description: Option<String>,
+    /// The original description if it was HTML, or the HTML alternative of a
+    /// plain text description.
+    pub description_html: Option<String>,
+    /// Whether `description` was converted from `description_html`, and so is
+    /// already cleaned Markdown rather than text that needs escaping.
+    pub description_from_html: bool,
     pub location: Option<String>,
     pub organizer: Option<Attendee>,
     pub attendees: Vec<Attendee>,
+    /// Whether this is an all-day event, in which case its instances start at
+    /// midnight in the calendar's timezone.
+    pub all_day: bool,
+    /// The ETag of the calendar resource the event came from, if known.
+    pub etag: Option<String>,
+    /// The link to join the event's video call, if any.
+    pub join_url: Option<String>,
+    pub categories: Vec<String>,
 }
 
 /// A particular instance of an event, with date/time and attendees.
@@ -64,20 +301,56 @@ pub struct Event {
 pub struct EventInstance {
     pub event_id: String,
     pub date: DateTime<FixedOffset>,
+    /// When the instance ends, if known.
+    pub end: Option<DateTime<FixedOffset>>,
     pub attendees: Vec<Attendee>,
+    /// Whether this instance has been cancelled, in which case no reminders
+    /// are sent for it.
+    pub cancelled: bool,
+    /// The link to join the instance's video call, which may differ from the
+    /// event's if the instance has been moved.
+    pub join_url: Option<String>,
+}
+
+impl EventInstance {
+    /// How long the instance lasts, if known.
+    pub fn duration(&self) -> Option<Duration> {
+        self.end.map(|end| end - self.date)
+    }
 }
 
 /// A reminder for a particular [`EventInstance`]
 #[derive(Debug, Clone)]
 pub struct ReminderInstance {
+    pub reminder_id: i64,
     pub event_id: String,
     pub summary: Option<String>,
     pub description: Option<String>,
+    pub description_html: Option<String>,
+    /// Whether `description` was converted from HTML, see
+    /// [`Event::description_from_html`].
+    pub description_from_html: bool,
     pub location: Option<String>,
+    pub join_url: Option<String>,
+    /// When the event instance starts and ends.
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    /// The calendar's timezone, used to format times.
+    pub timezone: Tz,
     pub template: Option<String>,
     pub minutes_before: i64,
+    pub anchor: ReminderAnchor,
+    /// Whether this is the reminder's own time, rather than one of its
+    /// additional ones.
+    pub is_primary: bool,
     pub room: String,
     pub attendees: Vec<Attendee>,
+    pub all_day: bool,
+    /// For all-day events, how many days before the event the reminder is
+    /// sent.
+    pub days_before: i64,
+    /// Whether the reminder was put off until the room's working hours.
+    pub deferred: bool,
 }
 
 /// A configured reminder
@@ -88,9 +361,157 @@ pub struct Reminder {
     pub user_id: i64,
     pub event_id: String,
     pub template: Option<String>,
+    /// How many minutes before the `anchor` to send the reminder, negative
+    /// for after it.
     pub minutes_before: i64,
+    #[serde(default)]
+    pub anchor: ReminderAnchor,
     pub room: String,
     pub attendee_editable: bool,
+    /// For all-day events, send the reminder at this local time instead of
+    /// `minutes_before` the start of the day. Ignored if anchored to the end.
+    pub time_of_day: Option<NaiveTime>,
+    /// For all-day events with a `time_of_day`, the number of days before the
+    /// event to send the reminder.
+    pub days_before: i64,
+    /// Whether the reminder was created from one of the event's alarms, in
+    /// which case it is managed by the bot rather than the user.
+    #[serde(default)]
+    pub from_alarm: bool,
+    /// The rule that created the reminder, if any, in which case it is
+    /// managed by the bot rather than the user.
+    #[serde(default)]
+    pub rule_id: Option<i64>,
+    /// What to do if the reminder is due outside the room's working hours.
+    #[serde(default)]
+    pub outside_hours: OutsideHours,
+}
+
+/// An additional time to send a reminder at, on top of the reminder's own
+/// `minutes_before`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReminderOffset {
+    pub reminder_id: i64,
+    /// As with [`Reminder::minutes_before`], negative for after the anchor.
+    pub minutes_before: i64,
+    #[serde(default)]
+    pub anchor: ReminderAnchor,
+    /// The template to use instead of the reminder's, if any.
+    pub template: Option<String>,
+}
+
+/// A change to a reminder for a single instance of its event.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReminderOverride {
+    pub reminder_id: i64,
+    /// The start of the instance the override applies to.
+    pub instance: DateTime<Utc>,
+    /// Don't send the reminder (or any of its additional offsets) at all.
+    pub skip: bool,
+    /// The room to send to instead of the reminder's.
+    pub room: Option<String>,
+    /// Replaces the reminder's own `minutes_before`, relative to the same
+    /// anchor. Additional offsets are unaffected.
+    pub minutes_before: Option<i64>,
+}
+
+/// A reminder we've sent, or tried to send, for an event instance.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReminderSend {
+    pub reminder_id: i64,
+    /// The start of the instance the reminder was for.
+    pub instance: DateTime<Utc>,
+    /// Whether this was the reminder's own time, rather than one of its
+    /// additional ones.
+    pub is_primary: bool,
+    pub anchor: ReminderAnchor,
+    pub minutes_before: i64,
+    pub status: SendStatus,
+    pub claimed_at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+/// The working hours of a room, outside of which reminders to it are sent,
+/// deferred or dropped depending on [`Reminder::outside_hours`].
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomPolicy {
+    pub room: String,
+    /// The user who set the policy, who is the only one who can change it.
+    pub user_id: i64,
+    pub timezone: String,
+    /// The local time working hours start each working day.
+    pub start_time: NaiveTime,
+    /// The local time working hours end, which must be after `start_time`.
+    pub end_time: NaiveTime,
+    /// The ISO weekday numbers of the working days, where Monday is 1.
+    pub working_days: Vec<i64>,
+}
+
+impl RoomPolicy {
+    /// Get the policy's timezone, defaulting to UTC.
+    pub fn tz(&self) -> Tz {
+        parse_timezone(Some(&self.timezone))
+    }
+}
+
+/// A rule that adds a reminder to every event in a calendar that matches it.
+///
+/// Events must match all of the criteria that are set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReminderRule {
+    pub rule_id: i64,
+    pub user_id: i64,
+    pub calendar_id: i64,
+    /// A case-insensitive regex that the event's summary must match.
+    pub summary_regex: Option<String>,
+    /// The email of the event's organizer.
+    pub organizer: Option<String>,
+    /// The email of one of the event's attendees.
+    pub attendee: Option<String>,
+    /// One of the event's categories.
+    pub category: Option<String>,
+    /// Text that the event's location must contain.
+    pub location: Option<String>,
+    pub room: String,
+    pub minutes_before: i64,
+    #[serde(default)]
+    pub anchor: ReminderAnchor,
+    pub template: Option<String>,
+    #[serde(default)]
+    pub outside_hours: OutsideHours,
+}
+
+impl ReminderRule {
+    /// Parse a rule from a row of the `reminder_rules` table.
+    fn from_row(row: &Row) -> Result<ReminderRule, Error> {
+        Ok(ReminderRule {
+            rule_id: row.try_get("rule_id")?,
+            user_id: row.try_get("user_id")?,
+            calendar_id: row.try_get("calendar_id")?,
+            summary_regex: row.try_get("summary_regex")?,
+            organizer: row.try_get("organizer")?,
+            attendee: row.try_get("attendee")?,
+            category: row.try_get("category")?,
+            location: row.try_get("location")?,
+            room: row.try_get("room")?,
+            minutes_before: row.try_get("minutes_before")?,
+            anchor: row.try_get("anchor")?,
+            template: row.try_get("template")?,
+            outside_hours: row.try_get("outside_hours")?,
+        })
+    }
+}
+
+/// Reminders that were copied to an event from an event it replaced.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReminderPort {
+    pub from_event_id: String,
+    /// The summary of the event the reminders were copied from, if we still
+    /// have it.
+    pub from_summary: Option<String>,
+    pub ported_at: DateTime<Utc>,
+    /// The number of reminders that were copied.
+    pub reminders: i64,
 }
 
 /// Allows talking to the database.
@@ -111,28 +532,16 @@ impl Database {
 
         let rows = db_conn
             .query(
-                "SELECT user_id, calendar_id, name, url, user_name, password FROM calendars",
+                "SELECT user_id, calendar_id, name, kind, url, user_name, password, timezone, look_ahead_days, poll_interval_minutes,
+                        mirror_alarms, alarm_room, alarm_outside_hours, dedup_same_time_of_day, dedup_same_weekday
+                    FROM calendars",
                 &[],
             )
             .await?;
 
         let mut calendars = Vec::with_capacity(rows.len());
         for row in rows {
-            let user_id = row.try_get("user_id")?;
-            let calendar_id = row.try_get("calendar_id")?;
-            let name = row.try_get("name")?;
-            let url = row.try_get("url")?;
-            let user_name = row.try_get("user_name")?;
-            let password = row.try_get("password")?;
-
-            calendars.push(Calendar {
-                user_id,
-                calendar_id,
-                name,
-                url,
-                user_name,
-                password,
-            })
+            calendars.push(Calendar::from_row(&row)?)
         }
 
         Ok(calendars)
@@ -145,7 +554,9 @@ impl Database {
         let rows = db_conn
             .query(
                 r#"
-                    SELECT calendar_id, name, url, user_name, password FROM calendars
+                    SELECT user_id, calendar_id, name, kind, url, user_name, password, timezone, look_ahead_days, poll_interval_minutes,
+                        mirror_alarms, alarm_room, alarm_outside_hours, dedup_same_time_of_day, dedup_same_weekday
+                    FROM calendars
                     WHERE user_id = $1
                 "#,
                 &[&user_id],
@@ -154,20 +565,7 @@ impl Database {
 
         let mut calendars = Vec::with_capacity(rows.len());
         for row in rows {
-            let calendar_id = row.try_get("calendar_id")?;
-            let name = row.try_get("name")?;
-            let url = row.try_get("url")?;
-            let user_name = row.try_get("user_name")?;
-            let password = row.try_get("password")?;
-
-            calendars.push(Calendar {
-                user_id,
-                calendar_id,
-                name,
-                url,
-                user_name,
-                password,
-            })
+            calendars.push(Calendar::from_row(&row)?)
         }
 
         Ok(calendars)
@@ -180,7 +578,9 @@ impl Database {
         let row = db_conn
             .query_opt(
                 r#"
-                    SELECT user_id, calendar_id, name, url, user_name, password FROM calendars
+                    SELECT user_id, calendar_id, name, kind, url, user_name, password, timezone, look_ahead_days, poll_interval_minutes,
+                        mirror_alarms, alarm_room, alarm_outside_hours, dedup_same_time_of_day, dedup_same_weekday
+                    FROM calendars
                     WHERE calendar_id = $1
                 "#,
                 &[&calendar_id],
@@ -188,45 +588,42 @@ impl Database {
             .await?;
 
         if let Some(row) = row {
-            let user_id = row.try_get("user_id")?;
-            let calendar_id = row.try_get("calendar_id")?;
-            let name = row.try_get("name")?;
-            let url = row.try_get("url")?;
-            let user_name = row.try_get("user_name")?;
-            let password = row.try_get("password")?;
-
-            Ok(Some(Calendar {
-                user_id,
-                calendar_id,
-                name,
-                url,
-                user_name,
-                password,
-            }))
+            Ok(Some(Calendar::from_row(&row)?))
         } else {
             Ok(None)
         }
     }
 
     /// Update a calendar's config.
-    pub async fn update_calendar(
-        &self,
-        calendar_id: i64,
-        name: String,
-        url: String,
-        user_name: Option<String>,
-        password: Option<String>,
-    ) -> Result<(), Error> {
+    pub async fn update_calendar(&self, calendar: &Calendar) -> Result<(), Error> {
         let db_conn = self.db_pool.get().await?;
 
         db_conn
             .execute(
                 r#"
                     UPDATE calendars
-                    SET name = $2, url = $3, user_name = $4, password = $5
+                    SET name = $2, kind = $3, url = $4, user_name = $5, password = $6,
+                        timezone = $7, look_ahead_days = $8, poll_interval_minutes = $9,
+                        mirror_alarms = $10, alarm_room = $11, dedup_same_time_of_day = $12,
+                        dedup_same_weekday = $13, alarm_outside_hours = $14, sync_token = NULL
                     WHERE calendar_id = $1
                 "#,
-                &[&calendar_id, &name, &url, &user_name, &password],
+                &[
+                    &calendar.calendar_id,
+                    &calendar.name,
+                    &calendar.kind,
+                    &calendar.url,
+                    &calendar.user_name,
+                    &calendar.password,
+                    &calendar.timezone,
+                    &calendar.look_ahead_days,
+                    &calendar.poll_interval_minutes,
+                    &calendar.mirror_alarms,
+                    &calendar.alarm_room,
+                    &calendar.dedup_same_time_of_day,
+                    &calendar.dedup_same_weekday,
+                    &calendar.alarm_outside_hours,
+                ],
             )
             .await?;
 
@@ -235,166 +632,1537 @@ impl Database {
 
     /// Delete a calendar.
     pub async fn delete_calendar(&self, calendar_id: i64) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        txn.execute(
+            r#"
+                DELETE FROM calendars
+                WHERE calendar_id = $1
+            "#,
+            &[&calendar_id],
+        )
+        .await?;
+
+        txn.execute(
+            "DELETE FROM calendar_resources WHERE calendar_id = $1",
+            &[&calendar_id],
+        )
+        .await?;
+
+        txn.execute(
+            "DELETE FROM calendar_status WHERE calendar_id = $1",
+            &[&calendar_id],
+        )
+        .await?;
+
+        txn.execute(
+            "DELETE FROM reminders WHERE calendar_id = $1",
+            &[&calendar_id],
+        )
+        .await?;
+
+        txn.execute(
+            "DELETE FROM reminder_rules WHERE calendar_id = $1",
+            &[&calendar_id],
+        )
+        .await?;
+
+        txn.execute(
+            "DELETE FROM reminder_ports WHERE calendar_id = $1",
+            &[&calendar_id],
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Add a new calendar, returning its ID.
+    ///
+    /// The `calendar_id` of the given calendar is ignored.
+    pub async fn add_calendar(&self, calendar: &Calendar) -> Result<i64, Error> {
         let db_conn = self.db_pool.get().await?;
 
-        db_conn
-            .execute(
+        let row = db_conn
+            .query_one(
                 r#"
-                    DELETE FROM calendars
-                    WHERE calendar_id = $1
+                    INSERT INTO calendars (
+                        user_id, name, kind, url, user_name, password, timezone, look_ahead_days,
+                        poll_interval_minutes, mirror_alarms, alarm_room, dedup_same_time_of_day,
+                        dedup_same_weekday, alarm_outside_hours
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+                    RETURNING calendar_id
                 "#,
+                &[
+                    &calendar.user_id,
+                    &calendar.name,
+                    &calendar.kind,
+                    &calendar.url,
+                    &calendar.user_name,
+                    &calendar.password,
+                    &calendar.timezone,
+                    &calendar.look_ahead_days,
+                    &calendar.poll_interval_minutes,
+                    &calendar.mirror_alarms,
+                    &calendar.alarm_room,
+                    &calendar.dedup_same_time_of_day,
+                    &calendar.dedup_same_weekday,
+                    &calendar.alarm_outside_hours,
+                ],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    /// Get the sync token from the last time we synced the calendar, if any.
+    pub async fn get_sync_token(&self, calendar_id: i64) -> Result<Option<String>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_opt(
+                "SELECT sync_token FROM calendars WHERE calendar_id = $1",
                 &[&calendar_id],
             )
             .await?;
 
-        Ok(())
+        if let Some(row) = row {
+            Ok(row.try_get("sync_token")?)
+        } else {
+            Ok(None)
+        }
     }
 
-    /// Add a new calendar.
-    pub async fn add_calendar(
+    /// Get all the stored calendar resources for the calendar.
+    pub async fn get_calendar_resources(
         &self,
-        user_id: i64,
-        name: String,
-        url: String,
-        user_name: Option<String>,
-        password: Option<String>,
-    ) -> Result<i64, Error> {
+        calendar_id: i64,
+    ) -> Result<Vec<CalendarResource>, Error> {
         let db_conn = self.db_pool.get().await?;
 
-        let row = db_conn
-            .query_one(
+        let rows = db_conn
+            .query(
                 r#"
-                    INSERT INTO calendars (user_id, name, url, user_name, password)
-                    VALUES ($1, $2, $3, $4, $5)
-                    RETURNING calendar_id
+                    SELECT href, etag, last_modified, calendar_data FROM calendar_resources
+                    WHERE calendar_id = $1
                 "#,
-                &[&user_id, &name, &url, &user_name, &password],
+                &[&calendar_id],
             )
             .await?;
 
-        Ok(row.try_get(0)?)
+        let mut resources = Vec::with_capacity(rows.len());
+        for row in rows {
+            resources.push(CalendarResource {
+                href: row.try_get("href")?,
+                etag: row.try_get("etag")?,
+                last_modified: row.try_get("last_modified")?,
+                calendar_data: row.try_get("calendar_data")?,
+            });
+        }
+
+        Ok(resources)
     }
 
-    /// Insert events and the next instances of the event.
+    /// Apply the changes from a sync to the stored calendar resources, and
+    /// store the new sync token.
     ///
-    /// Not all event instances are stored (since they might be infinite),
-    /// instead only the instances in the next, say, month are typically stored.
-    pub async fn insert_events(
+    /// If `full` is set then `changed` is the complete set of resources, and
+    /// any other stored resources are removed.
+    pub async fn apply_calendar_changes(
         &self,
         calendar_id: i64,
-        events: Vec<Event>,
-        instances: Vec<EventInstance>,
+        full: bool,
+        changed: &[CalendarResource],
+        deleted: &[String],
+        sync_token: Option<&str>,
     ) -> Result<(), Error> {
         let mut db_conn = self.db_pool.get().await?;
         let txn = db_conn.transaction().await?;
 
-        futures::future::try_join_all(events.iter().map(|event| {
+        if full {
+            txn.execute(
+                "DELETE FROM calendar_resources WHERE calendar_id = $1",
+                &[&calendar_id],
+            )
+            .await?;
+        }
+
+        futures::future::try_join_all(deleted.iter().map(|href| {
+            txn.execute_raw(
+                "DELETE FROM calendar_resources WHERE calendar_id = $1 AND href = $2",
+                vec![&calendar_id as &dyn ToSql, href],
+            )
+        }))
+        .await?;
+
+        futures::future::try_join_all(changed.iter().map(|resource| {
             txn.execute_raw(
                 r#"
-                    INSERT INTO events (calendar_id, event_id, summary, description, location, organizer, attendees)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7)
-                    ON CONFLICT (calendar_id, event_id)
+                    INSERT INTO calendar_resources (calendar_id, href, etag, last_modified, calendar_data)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (calendar_id, href)
                     DO UPDATE SET
-                        summary = EXCLUDED.summary,
-                        description = EXCLUDED.description,
-                        location = EXCLUDED.location,
-                        attendees = EXCLUDED.attendees
+                        etag = EXCLUDED.etag,
+                        last_modified = EXCLUDED.last_modified,
+                        calendar_data = EXCLUDED.calendar_data
                 "#,
                 vec![
                     &calendar_id as &dyn ToSql,
-                    &event.event_id,
-                    &event.summary,
-                    &event.description,
-                    &event.location,
-                    &event.organizer,
-                    &event.attendees,
+                    &resource.href,
+                    &resource.etag,
+                    &resource.last_modified,
+                    &resource.calendar_data,
                 ],
             )
         }))
         .await?;
 
         txn.execute(
-            "DELETE FROM next_dates WHERE calendar_id = $1",
-            &[&calendar_id],
+            "UPDATE calendars SET sync_token = $2 WHERE calendar_id = $1",
+            &[&calendar_id, &sync_token],
         )
         .await?;
 
-        futures::future::try_join_all(instances.iter().map(|instance| {
-            txn.execute_raw(
-                r#"
-                            INSERT INTO next_dates (calendar_id, event_id, timestamp, attendees)
-                            VALUES ($1, $2, $3, $4)
-                        "#,
-                vec![
-                    &calendar_id as &dyn ToSql,
-                    &instance.event_id,
-                    &instance.date,
-                    &instance.attendees,
-                ],
-            )
-        }))
-        .await?;
-
         txn.commit().await?;
 
         Ok(())
     }
 
-    /// Persist a new reminder.
-    pub async fn add_reminder(&self, reminder: Reminder) -> Result<(), Error> {
+    /// Record that a calendar was successfully updated.
+    pub async fn record_calendar_success(
+        &self,
+        calendar_id: i64,
+        event_count: i64,
+    ) -> Result<(), Error> {
         let db_conn = self.db_pool.get().await?;
 
         db_conn
             .execute(
                 r#"
-                    INSERT INTO reminders (
-                        user_id, calendar_id, event_id, room,
-                        minutes_before, template, attendee_editable
+                    INSERT INTO calendar_status (
+                        calendar_id, last_attempt, last_success, event_count, consecutive_failures
                     )
-                    VALUES ($1, $2, $3, $4, $5, $6, $7)
-            "#,
-                &[
-                    &reminder.user_id,
-                    &reminder.calendar_id,
-                    &reminder.event_id,
-                    &reminder.room,
-                    &reminder.minutes_before,
-                    &reminder.template,
-                    &reminder.attendee_editable,
-                ],
+                    VALUES ($1, $2, $2, $3, 0)
+                    ON CONFLICT (calendar_id)
+                    DO UPDATE SET
+                        last_attempt = EXCLUDED.last_attempt,
+                        last_success = EXCLUDED.last_success,
+                        http_status = NULL,
+                        error = NULL,
+                        event_count = EXCLUDED.event_count,
+                        consecutive_failures = 0
+                "#,
+                &[&calendar_id, &Utc::now(), &event_count],
             )
             .await?;
 
         Ok(())
     }
 
-    /// Update an existing reminder.
-    pub async fn update_reminder(
+    /// Record that updating a calendar failed, returning how many times in a
+    /// row it has now failed.
+    pub async fn record_calendar_failure(
         &self,
         calendar_id: i64,
-        reminder_id: i64,
-        room: &'_ str,
-        minutes_before: i64,
-        template: Option<&'_ str>,
-        attendee_editable: bool,
-    ) -> Result<(), Error> {
+        http_status: Option<i32>,
+        error: &str,
+    ) -> Result<i64, Error> {
         let db_conn = self.db_pool.get().await?;
 
+        let row = db_conn
+            .query_one(
+                r#"
+                    INSERT INTO calendar_status (
+                        calendar_id, last_attempt, http_status, error, consecutive_failures
+                    )
+                    VALUES ($1, $2, $3, $4, 1)
+                    ON CONFLICT (calendar_id)
+                    DO UPDATE SET
+                        last_attempt = EXCLUDED.last_attempt,
+                        http_status = EXCLUDED.http_status,
+                        error = EXCLUDED.error,
+                        consecutive_failures = calendar_status.consecutive_failures + 1
+                    RETURNING consecutive_failures
+                "#,
+                &[&calendar_id, &Utc::now(), &http_status, &error],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    /// Get the status of the last update of a calendar, if it has been updated.
+    pub async fn get_calendar_status(
+        &self,
+        calendar_id: i64,
+    ) -> Result<Option<CalendarStatus>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_opt(
+                r#"
+                    SELECT calendar_id, last_attempt, last_success, http_status, error,
+                        event_count, consecutive_failures
+                    FROM calendar_status
+                    WHERE calendar_id = $1
+                "#,
+                &[&calendar_id],
+            )
+            .await?;
+
+        row.as_ref().map(CalendarStatus::from_row).transpose()
+    }
+
+    /// Get the status of the last update of each of the user's calendars,
+    /// keyed by calendar ID.
+    pub async fn get_calendar_statuses_for_user(
+        &self,
+        user_id: i64,
+    ) -> Result<BTreeMap<i64, CalendarStatus>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT calendar_id, last_attempt, last_success, http_status, error,
+                        event_count, consecutive_failures
+                    FROM calendar_status
+                    INNER JOIN calendars USING (calendar_id)
+                    WHERE user_id = $1
+                "#,
+                &[&user_id],
+            )
+            .await?;
+
+        let mut statuses = BTreeMap::new();
+        for row in rows {
+            let status = CalendarStatus::from_row(&row)?;
+            statuses.insert(status.calendar_id, status);
+        }
+
+        Ok(statuses)
+    }
+
+    /// Insert events and the next instances of the event.
+    ///
+    /// Not all event instances are stored (since they might be infinite),
+    /// instead only the instances in the next, say, month are typically stored.
+    ///
+    /// Events whose ETag hasn't changed are left alone, other than adding and
+    /// removing instances as they move in and out of that window. Returns the
+    /// IDs of the events that are new or have changed.
+    pub async fn insert_events(
+        &self,
+        calendar_id: i64,
+        events: Vec<Event>,
+        instances: Vec<EventInstance>,
+        sources: HashMap<String, String>,
+    ) -> Result<Vec<String>, Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        let rows = txn
+            .query(
+                "SELECT event_id, etag, schema_version FROM events WHERE calendar_id = $1",
+                &[&calendar_id],
+            )
+            .await?;
+
+        let mut previous_etags = BTreeMap::new();
+        for row in rows {
+            let event_id: String = row.try_get("event_id")?;
+            let etag: Option<String> = row.try_get("etag")?;
+            let schema_version: i32 = row.try_get("schema_version")?;
+
+            // Events stored by an older version may be missing fields, so we
+            // treat them as changed.
+            if schema_version == EVENT_SCHEMA_VERSION {
+                previous_etags.insert(event_id, etag);
+            }
+        }
+
+        // If we don't have an ETag we can't tell if the event has changed, so
+        // we assume it has.
+        let changed_events = events
+            .iter()
+            .filter(|event| {
+                event.etag.is_none() || previous_etags.get(&event.event_id) != Some(&event.etag)
+            })
+            .collect_vec();
+
+        let changed_event_ids = changed_events
+            .iter()
+            .map(|event| event.event_id.as_str())
+            .collect_vec();
+
+        let changed_sources = changed_events
+            .iter()
+            .map(|event| sources.get(&event.event_id))
+            .collect_vec();
+
+        let changed = changed_events.iter().zip(&changed_sources);
+        futures::future::try_join_all(changed.map(|(event, raw_ics)| {
+            txn.execute_raw(
+                r#"
+                    INSERT INTO events (calendar_id, event_id, summary, description, location, organizer, attendees, all_day, etag, join_url, categories, raw_ics, description_html, schema_version, description_from_html)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+                    ON CONFLICT (calendar_id, event_id)
+                    DO UPDATE SET
+                        summary = EXCLUDED.summary,
+                        description = EXCLUDED.description,
+                        location = EXCLUDED.location,
+                        organizer = EXCLUDED.organizer,
+                        attendees = EXCLUDED.attendees,
+                        all_day = EXCLUDED.all_day,
+                        etag = EXCLUDED.etag,
+                        join_url = EXCLUDED.join_url,
+                        categories = EXCLUDED.categories,
+                        raw_ics = EXCLUDED.raw_ics,
+                        description_html = EXCLUDED.description_html,
+                        schema_version = EXCLUDED.schema_version,
+                        description_from_html = EXCLUDED.description_from_html
+                "#,
+                vec![
+                    &calendar_id as &dyn ToSql,
+                    &event.event_id,
+                    &event.summary,
+                    &event.description,
+                    &event.location,
+                    &event.organizer,
+                    &event.attendees,
+                    &event.all_day,
+                    &event.etag,
+                    &event.join_url,
+                    &event.categories,
+                    raw_ics,
+                    &event.description_html,
+                    &EVENT_SCHEMA_VERSION,
+                    &event.description_from_html,
+                ],
+            )
+        }))
+        .await?;
+
+        // The instances of changed events may have changed in ways other than
+        // their time (e.g. attendees), so we replace them wholesale.
+        txn.execute(
+            "DELETE FROM next_dates WHERE calendar_id = $1 AND event_id = ANY($2)",
+            &[&calendar_id, &changed_event_ids],
+        )
+        .await?;
+
+        let rows = txn
+            .query(
+                "SELECT event_id, timestamp FROM next_dates WHERE calendar_id = $1",
+                &[&calendar_id],
+            )
+            .await?;
+
+        let mut previous_instances = BTreeSet::new();
+        for row in rows {
+            let event_id: String = row.try_get("event_id")?;
+            let timestamp: DateTime<Utc> = row.try_get("timestamp")?;
+            previous_instances.insert((event_id, timestamp));
+        }
+
+        let current_instances: BTreeSet<_> = instances
+            .iter()
+            .map(|instance| (instance.event_id.clone(), instance.date.with_timezone(&Utc)))
+            .collect();
+
+        futures::future::try_join_all(previous_instances.difference(&current_instances).map(
+            |(event_id, timestamp)| {
+                txn.execute_raw(
+                    r#"
+                        DELETE FROM next_dates
+                        WHERE calendar_id = $1 AND event_id = $2 AND timestamp = $3
+                    "#,
+                    vec![&calendar_id as &dyn ToSql, event_id, timestamp],
+                )
+            },
+        ))
+        .await?;
+
+        futures::future::try_join_all(
+            instances
+                .iter()
+                .filter(|instance| {
+                    !previous_instances
+                        .contains(&(instance.event_id.clone(), instance.date.with_timezone(&Utc)))
+                })
+                .map(|instance| {
+                    txn.execute_raw(
+                        r#"
+                            INSERT INTO next_dates (calendar_id, event_id, timestamp, end_timestamp, attendees, cancelled, join_url)
+                            VALUES ($1, $2, $3, $4, $5, $6, $7)
+                        "#,
+                        vec![
+                            &calendar_id as &dyn ToSql,
+                            &instance.event_id,
+                            &instance.date,
+                            &instance.end,
+                            &instance.attendees,
+                            &instance.cancelled,
+                            &instance.join_url,
+                        ],
+                    )
+                }),
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(changed_event_ids
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    /// Make the calendar's reminders that were created from alarms match the
+    /// given alarms, which are pairs of event ID and minutes before.
+    ///
+    /// If the calendar doesn't mirror alarms all such reminders are removed.
+    pub async fn sync_alarm_reminders(
+        &self,
+        calendar: &Calendar,
+        alarms: &BTreeSet<(String, i64)>,
+    ) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        let room = match (&calendar.alarm_room, calendar.mirror_alarms) {
+            (Some(room), true) => Some(room),
+            _ => None,
+        };
+
+        let rows = txn
+            .query(
+                r#"
+                    SELECT reminder_id, event_id, minutes_before, room, outside_hours
+                    FROM reminders
+                    WHERE calendar_id = $1 AND from_alarm
+                "#,
+                &[&calendar.calendar_id],
+            )
+            .await?;
+
+        let mut existing = BTreeSet::new();
+        for row in rows {
+            let reminder_id: i64 = row.try_get("reminder_id")?;
+            let event_id: String = row.try_get("event_id")?;
+            let minutes_before: i64 = row.try_get("minutes_before")?;
+            let reminder_room: String = row.try_get("room")?;
+            let outside_hours: OutsideHours = row.try_get("outside_hours")?;
+
+            // Reminders are updated in place if the alarm settings change, as
+            // sends are recorded against the reminder and replacing them
+            // would send them again.
+            let key = (event_id, minutes_before);
+            if let (Some(room), true) = (room, alarms.contains(&key)) {
+                if *room != reminder_room || outside_hours != calendar.alarm_outside_hours {
+                    txn.execute(
+                        "UPDATE reminders SET room = $2, outside_hours = $3 WHERE reminder_id = $1",
+                        &[&reminder_id, room, &calendar.alarm_outside_hours],
+                    )
+                    .await?;
+                }
+                existing.insert(key);
+            } else {
+                txn.execute(
+                    "DELETE FROM reminders WHERE reminder_id = $1",
+                    &[&reminder_id],
+                )
+                .await?;
+            }
+        }
+
+        if let Some(room) = room {
+            for (event_id, minutes_before) in alarms.difference(&existing) {
+                txn.execute(
+                    r#"
+                        INSERT INTO reminders (
+                            user_id, calendar_id, event_id, room, minutes_before,
+                            attendee_editable, from_alarm, outside_hours
+                        )
+                        VALUES ($1, $2, $3, $4, $5, false, true, $6)
+                    "#,
+                    &[
+                        &calendar.user_id,
+                        &calendar.calendar_id,
+                        event_id,
+                        room,
+                        minutes_before,
+                        &calendar.alarm_outside_hours,
+                    ],
+                )
+                .await?;
+            }
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Make the reminders created by the calendar's rules match the given
+    /// pairs of rule ID and event ID, using the current settings of each rule.
+    pub async fn sync_rule_reminders(
+        &self,
+        calendar: &Calendar,
+        rules: &[ReminderRule],
+        matches: &BTreeSet<(i64, String)>,
+    ) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        let rows = txn
+            .query(
+                r#"
+                    SELECT reminder_id, rule_id, event_id, room, minutes_before, anchor, template,
+                        outside_hours
+                    FROM reminders
+                    WHERE calendar_id = $1 AND rule_id IS NOT NULL
+                "#,
+                &[&calendar.calendar_id],
+            )
+            .await?;
+
+        let mut existing = Vec::with_capacity(rows.len());
+        for row in rows {
+            existing.push(RuleReminder {
+                reminder_id: row.try_get("reminder_id")?,
+                rule_id: row.try_get("rule_id")?,
+                event_id: row.try_get("event_id")?,
+                room: row.try_get("room")?,
+                minutes_before: row.try_get("minutes_before")?,
+                anchor: row.try_get("anchor")?,
+                template: row.try_get("template")?,
+                outside_hours: row.try_get("outside_hours")?,
+            });
+        }
+
+        for change in plan_rule_reminders(rules, &existing, matches) {
+            match change {
+                RuleReminderChange::Add(rule, event_id) => {
+                    txn.execute(
+                        r#"
+                            INSERT INTO reminders (
+                                user_id, calendar_id, event_id, room, minutes_before,
+                                template, attendee_editable, rule_id, anchor, outside_hours
+                            )
+                            VALUES ($1, $2, $3, $4, $5, $6, false, $7, $8, $9)
+                            ON CONFLICT (rule_id, event_id) WHERE rule_id IS NOT NULL DO NOTHING
+                        "#,
+                        &[
+                            &calendar.user_id,
+                            &calendar.calendar_id,
+                            &event_id,
+                            &rule.room,
+                            &rule.minutes_before,
+                            &rule.template,
+                            &rule.rule_id,
+                            &rule.anchor,
+                            &rule.outside_hours,
+                        ],
+                    )
+                    .await?;
+                }
+                RuleReminderChange::Update(reminder_id, rule) => {
+                    txn.execute(
+                        r#"
+                            UPDATE reminders
+                            SET room = $2, minutes_before = $3, template = $4, anchor = $5,
+                                outside_hours = $6
+                            WHERE reminder_id = $1
+                        "#,
+                        &[
+                            &reminder_id,
+                            &rule.room,
+                            &rule.minutes_before,
+                            &rule.template,
+                            &rule.anchor,
+                            &rule.outside_hours,
+                        ],
+                    )
+                    .await?;
+                }
+                RuleReminderChange::Delete(reminder_id) => {
+                    txn.execute(
+                        "DELETE FROM reminders WHERE reminder_id = $1",
+                        &[&reminder_id],
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get the reminder rules of a calendar.
+    pub async fn get_reminder_rules(&self, calendar_id: i64) -> Result<Vec<ReminderRule>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT rule_id, user_id, calendar_id, summary_regex, organizer, attendee,
+                        category, location, room, minutes_before, anchor, template, outside_hours
+                    FROM reminder_rules
+                    WHERE calendar_id = $1
+                    ORDER BY rule_id
+                "#,
+                &[&calendar_id],
+            )
+            .await?;
+
+        let mut rules = Vec::with_capacity(rows.len());
+        for row in rows {
+            rules.push(ReminderRule::from_row(&row)?);
+        }
+
+        Ok(rules)
+    }
+
+    /// Get a reminder rule in a calendar.
+    pub async fn get_reminder_rule(
+        &self,
+        calendar_id: i64,
+        rule_id: i64,
+    ) -> Result<Option<ReminderRule>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_opt(
+                r#"
+                    SELECT rule_id, user_id, calendar_id, summary_regex, organizer, attendee,
+                        category, location, room, minutes_before, anchor, template, outside_hours
+                    FROM reminder_rules
+                    WHERE calendar_id = $1 AND rule_id = $2
+                "#,
+                &[&calendar_id, &rule_id],
+            )
+            .await?;
+
+        row.as_ref().map(ReminderRule::from_row).transpose()
+    }
+
+    /// Add a new reminder rule, returning its ID.
+    ///
+    /// The `rule_id` of the given rule is ignored.
+    pub async fn add_reminder_rule(&self, rule: &ReminderRule) -> Result<i64, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_one(
+                r#"
+                    INSERT INTO reminder_rules (
+                        user_id, calendar_id, summary_regex, organizer, attendee,
+                        category, location, room, minutes_before, template, anchor, outside_hours
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                    RETURNING rule_id
+                "#,
+                &[
+                    &rule.user_id,
+                    &rule.calendar_id,
+                    &rule.summary_regex,
+                    &rule.organizer,
+                    &rule.attendee,
+                    &rule.category,
+                    &rule.location,
+                    &rule.room,
+                    &rule.minutes_before,
+                    &rule.template,
+                    &rule.anchor,
+                    &rule.outside_hours,
+                ],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    /// Update an existing reminder rule.
+    pub async fn update_reminder_rule(&self, rule: &ReminderRule) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        db_conn
+            .execute(
+                r#"
+                    UPDATE reminder_rules
+                    SET summary_regex = $1, organizer = $2, attendee = $3, category = $4,
+                        location = $5, room = $6, minutes_before = $7, template = $8,
+                        anchor = $11, outside_hours = $12
+                    WHERE calendar_id = $9 AND rule_id = $10
+                "#,
+                &[
+                    &rule.summary_regex,
+                    &rule.organizer,
+                    &rule.attendee,
+                    &rule.category,
+                    &rule.location,
+                    &rule.room,
+                    &rule.minutes_before,
+                    &rule.template,
+                    &rule.calendar_id,
+                    &rule.rule_id,
+                    &rule.anchor,
+                    &rule.outside_hours,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete a reminder rule, along with the reminders it created.
+    pub async fn delete_reminder_rule(&self, calendar_id: i64, rule_id: i64) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        txn.execute(
+            "DELETE FROM reminder_rules WHERE calendar_id = $1 AND rule_id = $2",
+            &[&calendar_id, &rule_id],
+        )
+        .await?;
+
+        txn.execute(
+            "DELETE FROM reminders WHERE calendar_id = $1 AND rule_id = $2",
+            &[&calendar_id, &rule_id],
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get the IDs and summaries of the events that a rule has added
+    /// reminders to.
+    pub async fn get_events_matching_rule(
+        &self,
+        calendar_id: i64,
+        rule_id: i64,
+    ) -> Result<Vec<(String, Option<String>)>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT event_id, summary
+                    FROM reminders
+                    INNER JOIN events USING (calendar_id, event_id)
+                    WHERE calendar_id = $1 AND rule_id = $2
+                    ORDER BY summary, event_id
+                "#,
+                &[&calendar_id, &rule_id],
+            )
+            .await?;
+
+        let mut events = Vec::with_capacity(rows.len());
+        for row in rows {
+            events.push((row.try_get("event_id")?, row.try_get("summary")?));
+        }
+
+        Ok(events)
+    }
+
+    /// Persist a new reminder, returning its ID.
+    pub async fn add_reminder(&self, reminder: Reminder) -> Result<i64, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_one(
+                r#"
+                    INSERT INTO reminders (
+                        user_id, calendar_id, event_id, room,
+                        minutes_before, template, attendee_editable,
+                        time_of_day, days_before, from_alarm, rule_id, anchor, outside_hours
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                    RETURNING reminder_id
+            "#,
+                &[
+                    &reminder.user_id,
+                    &reminder.calendar_id,
+                    &reminder.event_id,
+                    &reminder.room,
+                    &reminder.minutes_before,
+                    &reminder.template,
+                    &reminder.attendee_editable,
+                    &reminder.time_of_day,
+                    &reminder.days_before,
+                    &reminder.from_alarm,
+                    &reminder.rule_id,
+                    &reminder.anchor,
+                    &reminder.outside_hours,
+                ],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    /// Copy the reminders of an event to the event that replaced it,
+    /// recording each copy so that it can be undone.
+    pub async fn port_reminders(
+        &self,
+        calendar_id: i64,
+        from_event_id: &str,
+        to_event_id: &str,
+        reminders: Vec<Reminder>,
+    ) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        for reminder in reminders {
+            let row = txn
+                .query_one(
+                    r#"
+                        INSERT INTO reminders (
+                            user_id, calendar_id, event_id, room,
+                            minutes_before, template, attendee_editable,
+                            time_of_day, days_before, from_alarm, rule_id, anchor,
+                            outside_hours
+                        )
+                        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                        RETURNING reminder_id
+                    "#,
+                    &[
+                        &reminder.user_id,
+                        &calendar_id,
+                        &to_event_id,
+                        &reminder.room,
+                        &reminder.minutes_before,
+                        &reminder.template,
+                        &reminder.attendee_editable,
+                        &reminder.time_of_day,
+                        &reminder.days_before,
+                        &reminder.from_alarm,
+                        &reminder.rule_id,
+                        &reminder.anchor,
+                        &reminder.outside_hours,
+                    ],
+                )
+                .await?;
+
+            let reminder_id: i64 = row.try_get(0)?;
+
+            txn.execute(
+                r#"
+                    INSERT INTO reminder_offsets (reminder_id, minutes_before, anchor, template)
+                    SELECT $1, minutes_before, anchor, template FROM reminder_offsets
+                    WHERE reminder_id = $2
+                "#,
+                &[&reminder_id, &reminder.reminder_id],
+            )
+            .await?;
+
+            txn.execute(
+                r#"
+                    INSERT INTO reminder_ports (
+                        calendar_id, from_event_id, to_event_id, source_reminder_id, reminder_id
+                    )
+                    VALUES ($1, $2, $3, $4, $5)
+                "#,
+                &[
+                    &calendar_id,
+                    &from_event_id,
+                    &to_event_id,
+                    &reminder.reminder_id,
+                    &reminder_id,
+                ],
+            )
+            .await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get the reminders that were copied to the event from the events it
+    /// replaced, and that haven't been undone.
+    pub async fn get_reminder_ports_to_event(
+        &self,
+        calendar_id: i64,
+        event_id: &str,
+    ) -> Result<Vec<ReminderPort>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT from_event_id, summary AS from_summary,
+                        MIN(ported_at) AS ported_at, COUNT(*) AS reminders
+                    FROM reminder_ports AS p
+                    LEFT JOIN events AS e
+                        ON e.calendar_id = p.calendar_id AND e.event_id = p.from_event_id
+                    WHERE p.calendar_id = $1 AND to_event_id = $2 AND undone_at IS NULL
+                    GROUP BY from_event_id, summary
+                    ORDER BY MIN(ported_at)
+                "#,
+                &[&calendar_id, &event_id],
+            )
+            .await?;
+
+        let mut ports = Vec::with_capacity(rows.len());
+        for row in rows {
+            ports.push(ReminderPort {
+                from_event_id: row.try_get("from_event_id")?,
+                from_summary: row.try_get("from_summary")?,
+                ported_at: row.try_get("ported_at")?,
+                reminders: row.try_get("reminders")?,
+            });
+        }
+
+        Ok(ports)
+    }
+
+    /// Undo copying reminders from one event to another, deleting the copies.
+    ///
+    /// Reminders that have been deleted since they were copied are ignored.
+    pub async fn undo_reminder_ports(
+        &self,
+        calendar_id: i64,
+        from_event_id: &str,
+        to_event_id: &str,
+    ) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        txn.execute(
+            r#"
+                DELETE FROM reminders
+                WHERE calendar_id = $1 AND reminder_id IN (
+                    SELECT reminder_id FROM reminder_ports
+                    WHERE calendar_id = $1 AND from_event_id = $2 AND to_event_id = $3
+                        AND undone_at IS NULL
+                )
+            "#,
+            &[&calendar_id, &from_event_id, &to_event_id],
+        )
+        .await?;
+
+        txn.execute(
+            r#"
+                UPDATE reminder_ports SET undone_at = now()
+                WHERE calendar_id = $1 AND from_event_id = $2 AND to_event_id = $3
+                    AND undone_at IS NULL
+            "#,
+            &[&calendar_id, &from_event_id, &to_event_id],
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Update an existing reminder.
+    ///
+    /// Only the reminder's settings are updated, not its owner or event.
+    pub async fn update_reminder(&self, reminder: &Reminder) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        db_conn
+            .execute(
+                r#"
+                    UPDATE reminders
+                    SET room = $1, minutes_before = $2, template = $3,
+                    attendee_editable = $4, time_of_day = $5, days_before = $6, anchor = $9,
+                    outside_hours = $10
+                    WHERE calendar_id = $7 AND reminder_id = $8
+            "#,
+                &[
+                    &reminder.room,
+                    &reminder.minutes_before,
+                    &reminder.template,
+                    &reminder.attendee_editable,
+                    &reminder.time_of_day,
+                    &reminder.days_before,
+                    &reminder.calendar_id,
+                    &reminder.reminder_id,
+                    &reminder.anchor,
+                    &reminder.outside_hours,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete a specific reminder.
+    pub async fn delete_reminder_in_calendar(
+        &self,
+        calendar_id: i64,
+        reminder_id: i64,
+    ) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        // The reminder's offsets, overrides and sends are deleted with it.
+        db_conn
+            .execute(
+                r#"
+                    DELETE FROM reminders
+                    WHERE calendar_id = $1 AND reminder_id = $2
+            "#,
+                &[&calendar_id, &reminder_id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the additional times to send a reminder at.
+    pub async fn get_reminder_offsets(
+        &self,
+        reminder_id: i64,
+    ) -> Result<Vec<ReminderOffset>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT reminder_id, minutes_before, anchor, template FROM reminder_offsets
+                    WHERE reminder_id = $1
+                    ORDER BY anchor, minutes_before DESC
+                "#,
+                &[&reminder_id],
+            )
+            .await?;
+
+        let mut offsets = Vec::with_capacity(rows.len());
+        for row in rows {
+            offsets.push(ReminderOffset {
+                reminder_id: row.try_get("reminder_id")?,
+                minutes_before: row.try_get("minutes_before")?,
+                anchor: row.try_get("anchor")?,
+                template: row.try_get("template")?,
+            });
+        }
+
+        Ok(offsets)
+    }
+
+    /// Get the furthest any of the calendar's reminders is sent after its
+    /// anchor, in minutes.
+    pub async fn get_max_minutes_after(&self, calendar_id: i64) -> Result<i64, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_one(
+                r#"
+                    SELECT COALESCE(MAX(-minutes_before), 0) FROM (
+                        SELECT minutes_before FROM reminders WHERE calendar_id = $1
+                        UNION ALL
+                        SELECT o.minutes_before FROM reminder_offsets AS o
+                        INNER JOIN reminders AS r USING (reminder_id)
+                        WHERE r.calendar_id = $1
+                        UNION ALL
+                        SELECT ov.minutes_before FROM reminder_overrides AS ov
+                        INNER JOIN reminders AS r USING (reminder_id)
+                        WHERE r.calendar_id = $1 AND ov.minutes_before IS NOT NULL
+                    ) AS offsets
+                "#,
+                &[&calendar_id],
+            )
+            .await?;
+
+        let minutes_after: i64 = row.try_get(0)?;
+
+        Ok(minutes_after.max(0))
+    }
+
+    /// Replace the additional times to send a reminder at.
+    pub async fn set_reminder_offsets(
+        &self,
+        reminder_id: i64,
+        offsets: &[ReminderOffset],
+    ) -> Result<(), Error> {
+        let mut db_conn = self.db_pool.get().await?;
+        let txn = db_conn.transaction().await?;
+
+        txn.execute(
+            "DELETE FROM reminder_offsets WHERE reminder_id = $1",
+            &[&reminder_id],
+        )
+        .await?;
+
+        for offset in offsets {
+            txn.execute(
+                r#"
+                    INSERT INTO reminder_offsets (reminder_id, minutes_before, anchor, template)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (reminder_id, anchor, minutes_before)
+                    DO UPDATE SET template = EXCLUDED.template
+                "#,
+                &[
+                    &reminder_id,
+                    &offset.minutes_before,
+                    &offset.anchor,
+                    &offset.template,
+                ],
+            )
+            .await?;
+        }
+
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get the per-instance overrides of the given reminders.
+    pub async fn get_reminder_overrides(
+        &self,
+        reminder_ids: &[i64],
+    ) -> Result<Vec<ReminderOverride>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT reminder_id, instance_timestamp, skip, room, minutes_before
+                    FROM reminder_overrides
+                    WHERE reminder_id = ANY($1)
+                    ORDER BY instance_timestamp, reminder_id
+                "#,
+                &[&reminder_ids],
+            )
+            .await?;
+
+        let mut overrides = Vec::with_capacity(rows.len());
+        for row in rows {
+            overrides.push(ReminderOverride {
+                reminder_id: row.try_get("reminder_id")?,
+                instance: row.try_get("instance_timestamp")?,
+                skip: row.try_get("skip")?,
+                room: row.try_get("room")?,
+                minutes_before: row.try_get("minutes_before")?,
+            });
+        }
+
+        Ok(overrides)
+    }
+
+    /// Add or replace the override of a reminder for a single instance.
+    pub async fn upsert_reminder_override(
+        &self,
+        reminder_override: &ReminderOverride,
+    ) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        db_conn
+            .execute(
+                r#"
+                    INSERT INTO reminder_overrides (
+                        reminder_id, instance_timestamp, skip, room, minutes_before
+                    )
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (reminder_id, instance_timestamp)
+                    DO UPDATE SET skip = EXCLUDED.skip, room = EXCLUDED.room,
+                        minutes_before = EXCLUDED.minutes_before
+                "#,
+                &[
+                    &reminder_override.reminder_id,
+                    &reminder_override.instance,
+                    &reminder_override.skip,
+                    &reminder_override.room,
+                    &reminder_override.minutes_before,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove the override of a reminder for a single instance.
+    pub async fn delete_reminder_override(
+        &self,
+        reminder_id: i64,
+        instance: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        db_conn
+            .execute(
+                "DELETE FROM reminder_overrides WHERE reminder_id = $1 AND instance_timestamp = $2",
+                &[&reminder_id, &instance],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the reminders needed to be sent out, including any that were due
+    /// less than `catch_up` ago but haven't been sent.
+    pub async fn get_next_reminders(
+        &self,
+        catch_up: Duration,
+    ) -> Result<VecDeque<(DateTime<Utc>, ReminderInstance)>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    WITH offsets AS (
+                        SELECT reminder_id, minutes_before, anchor, NULL::text AS template,
+                            true AS is_primary
+                        FROM reminders
+                        UNION ALL
+                        SELECT reminder_id, minutes_before, anchor, template, false AS is_primary
+                        FROM reminder_offsets
+                    )
+                    SELECT event_id, summary, description, location, timestamp,
+                        COALESCE(ov.room, r.room) AS room,
+                        CASE WHEN o.is_primary THEN COALESCE(ov.minutes_before, o.minutes_before)
+                            ELSE o.minutes_before END AS minutes_before,
+                        COALESCE(o.template, r.template) AS template, i.attendees,
+                        all_day, time_of_day, days_before, timezone, end_timestamp,
+                        COALESCE(i.join_url, e.join_url) AS join_url, description_html, r.reminder_id,
+                        o.is_primary, o.anchor, ov.minutes_before IS NOT NULL AS overridden,
+                        p.user_id AS policy_user_id, r.outside_hours, p.timezone AS policy_timezone,
+                        p.start_time, p.end_time, p.working_days, description_from_html
+                    FROM reminders AS r
+                    INNER JOIN offsets AS o ON o.reminder_id = r.reminder_id
+                    INNER JOIN events AS e USING (calendar_id, event_id)
+                    INNER JOIN next_dates AS i USING (calendar_id, event_id)
+                    INNER JOIN calendars USING (calendar_id)
+                    LEFT JOIN reminder_overrides AS ov
+                        ON ov.reminder_id = r.reminder_id AND ov.instance_timestamp = i.timestamp
+                    LEFT JOIN room_policies AS p
+                        ON p.room = COALESCE(ov.room, r.room)
+                    LEFT JOIN sent_reminders AS s
+                        ON s.reminder_id = r.reminder_id AND s.instance_timestamp = i.timestamp
+                            AND s.is_primary = o.is_primary
+                            AND (o.is_primary OR (s.anchor = o.anchor AND s.minutes_before = o.minutes_before))
+                    WHERE NOT i.cancelled AND NOT COALESCE(ov.skip, false)
+                        AND (s.reminder_id IS NULL OR s.status = 'Failed')
+                    ORDER BY timestamp
+                "#,
+                &[],
+            )
+            .await?;
+
+        let mut reminders = VecDeque::with_capacity(rows.len());
+        let now = Utc::now();
+
+        for row in rows {
+            let event_id: String = row.get(0);
+            let summary: Option<String> = row.get(1);
+            let description: Option<String> = row.get(2);
+            let location: Option<String> = row.get(3);
+            let timestamp: DateTime<Utc> = row.get(4);
+            let room: String = row.get(5);
+            let minutes_before: i64 = row.get(6);
+            let template: Option<String> = row.get(7);
+            let attendees: Vec<Attendee> = row.get(8);
+            let all_day: bool = row.get(9);
+            let time_of_day: Option<NaiveTime> = row.get(10);
+            let days_before: i64 = row.get(11);
+            let timezone = parse_timezone(row.get(12));
+            let end: Option<DateTime<Utc>> = row.get(13);
+            let join_url: Option<String> = row.get(14);
+            let description_html: Option<String> = row.get(15);
+            let description_from_html: bool = row.get(26);
+            let reminder_id: i64 = row.get(16);
+            let is_primary: bool = row.get(17);
+            let anchor: ReminderAnchor = row.get(18);
+            let overridden: bool = row.get(19);
+            let outside_hours: OutsideHours = row.get(21);
+            let policy_timezone: Option<String> = row.get(22);
+
+            let anchor_time = match (anchor, end) {
+                (ReminderAnchor::Start, _) => timestamp,
+                (ReminderAnchor::End, Some(end)) => end,
+                (ReminderAnchor::End, None) => continue,
+            };
+
+            // Only the reminder's own time can be at a time of day, the
+            // additional ones (and overridden ones) are always relative to
+            // the anchor.
+            let reminder_time = match (all_day, time_of_day, anchor) {
+                (true, Some(time_of_day), ReminderAnchor::Start) if is_primary && !overridden => {
+                    match all_day_reminder_time(timestamp, timezone, days_before, time_of_day) {
+                        Some(reminder_time) => reminder_time,
+                        None => continue,
+                    }
+                }
+                _ => anchor_time - Duration::minutes(minutes_before),
+            };
+
+            let policy = policy_timezone.map(|timezone| RoomPolicy {
+                room: room.clone(),
+                user_id: row.get(20),
+                timezone,
+                start_time: row.get(23),
+                end_time: row.get(24),
+                working_days: row.get(25),
+            });
+
+            // This needs to happen before we check whether the reminder is
+            // too old, so that deferred reminders don't get ignored. Reminders
+            // can't be deferred past the time they're anchored to, e.g. so
+            // that "starts in 30 minutes" isn't sent after the start.
+            let latest = anchor_time.max(reminder_time);
+            let (reminder_time, deferred) = match &policy {
+                Some(policy) => {
+                    match apply_room_policy(policy, outside_hours, reminder_time, latest) {
+                        Some(time) => (time, time != reminder_time),
+                        None => {
+                            debug!(reminder_time = ?reminder_time, event_id = event_id.deref(), "Dropping reminder outside working hours");
+                            continue;
+                        }
+                    }
+                }
+                None => (reminder_time, false),
+            };
+
+            // How many days before the event the reminder actually goes out,
+            // so that templates can say e.g. "tomorrow".
+            let days_before = (timestamp.with_timezone(&timezone).date()
+                - reminder_time.with_timezone(&timezone).date())
+            .num_days();
+            if reminder_time < now - catch_up {
+                debug!(now = ?now, reminder_time =?reminder_time, event_id = event_id.deref(), "Ignoring reminder");
+                continue;
+            }
+
+            let reminder = ReminderInstance {
+                reminder_id,
+                event_id,
+                summary,
+                description,
+                description_html,
+                description_from_html,
+                location,
+                join_url,
+                start: timestamp,
+                end,
+                timezone,
+                template,
+                minutes_before,
+                anchor,
+                is_primary,
+                room,
+                attendees,
+                all_day,
+                days_before,
+                deferred,
+            };
+
+            reminders.push_back((reminder_time, reminder));
+        }
+
+        reminders.make_contiguous().sort_by_key(|(t, _)| *t);
+
+        Ok(reminders)
+    }
+
+    /// Record that we're about to send the reminder for the given event
+    /// instance, returning false if it has already been sent (or is being
+    /// sent). Failed sends can be claimed again to retry them.
+    ///
+    /// The reminder's own time is matched regardless of its offset, so that
+    /// changing or overriding the offset doesn't send it again.
+    pub async fn claim_reminder_send(
+        &self,
+        reminder_id: i64,
+        instance: DateTime<Utc>,
+        anchor: ReminderAnchor,
+        minutes_before: i64,
+        is_primary: bool,
+    ) -> Result<bool, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let retried = db_conn
+            .execute(
+                r#"
+                    UPDATE sent_reminders
+                    SET status = 'Sending', claimed_at = now(), finished_at = NULL, error = NULL,
+                        anchor = $3, minutes_before = $4
+                    WHERE reminder_id = $1 AND instance_timestamp = $2 AND is_primary = $5
+                        AND (is_primary OR (anchor = $3 AND minutes_before = $4))
+                        AND status = 'Failed'
+                "#,
+                &[
+                    &reminder_id,
+                    &instance,
+                    &anchor,
+                    &minutes_before,
+                    &is_primary,
+                ],
+            )
+            .await?;
+
+        if retried > 0 {
+            return Ok(true);
+        }
+
+        let inserted = db_conn
+            .execute(
+                r#"
+                    INSERT INTO sent_reminders (
+                        reminder_id, instance_timestamp, minutes_before, status, anchor, is_primary
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT DO NOTHING
+                "#,
+                &[
+                    &reminder_id,
+                    &instance,
+                    &minutes_before,
+                    &SendStatus::Sending,
+                    &anchor,
+                    &is_primary,
+                ],
+            )
+            .await?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Record whether sending the reminder for the given event instance
+    /// succeeded.
+    pub async fn finish_reminder_send(
+        &self,
+        reminder_id: i64,
+        instance: DateTime<Utc>,
+        anchor: ReminderAnchor,
+        minutes_before: i64,
+        is_primary: bool,
+        error: Option<String>,
+    ) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let status = if error.is_some() {
+            SendStatus::Failed
+        } else {
+            SendStatus::Sent
+        };
+
         db_conn
             .execute(
                 r#"
-                    UPDATE reminders
-                    SET room = $1, minutes_before = $2, template = $3,
-                    attendee_editable = $4
-                    WHERE calendar_id = $5 AND reminder_id = $6
-            "#,
+                    UPDATE sent_reminders
+                    SET status = $4, finished_at = now(), error = $5
+                    WHERE reminder_id = $1 AND instance_timestamp = $2 AND is_primary = $7
+                        AND (is_primary OR (anchor = $6 AND minutes_before = $3))
+                "#,
                 &[
-                    &room,
-                    &minutes_before,
-                    &template,
-                    &attendee_editable,
-                    &calendar_id,
                     &reminder_id,
+                    &instance,
+                    &minutes_before,
+                    &status,
+                    &error,
+                    &anchor,
+                    &is_primary,
                 ],
             )
             .await?;
@@ -402,85 +2170,117 @@ impl Database {
         Ok(())
     }
 
-    /// Delete a specific reminder.
-    pub async fn delete_reminder_in_calendar(
-        &self,
-        calendar_id: i64,
-        reminder_id: i64,
-    ) -> Result<(), Error> {
+    /// Mark any sends that were still in progress as interrupted, as we've
+    /// restarted since and can't tell whether they went out.
+    pub async fn mark_interrupted_sends(&self) -> Result<u64, Error> {
         let db_conn = self.db_pool.get().await?;
 
-        db_conn
+        let updated = db_conn
             .execute(
                 r#"
-                    DELETE FROM reminders
-                    WHERE calendar_id = $1 AND reminder_id = $2
-            "#,
-                &[&calendar_id, &reminder_id],
+                    UPDATE sent_reminders
+                    SET status = 'Interrupted', finished_at = now()
+                    WHERE status = 'Sending'
+                "#,
+                &[],
             )
             .await?;
 
-        Ok(())
+        Ok(updated)
     }
 
-    /// Get the reminders needed to be sent out.
-    pub async fn get_next_reminders(
+    /// Delete the record of sends that were claimed more than `keep` ago.
+    pub async fn prune_sent_reminders(&self, keep: Duration) -> Result<u64, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let deleted = db_conn
+            .execute(
+                "DELETE FROM sent_reminders WHERE claimed_at < $1",
+                &[&(Utc::now() - keep)],
+            )
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// Get the sends for the given reminders, most recent first.
+    pub async fn get_reminder_sends(
         &self,
-    ) -> Result<VecDeque<(DateTime<Utc>, ReminderInstance)>, Error> {
+        reminder_ids: &[i64],
+    ) -> Result<Vec<ReminderSend>, Error> {
         let db_conn = self.db_pool.get().await?;
 
         let rows = db_conn
             .query(
                 r#"
-                    SELECT event_id, summary, description, location, timestamp, room, minutes_before, template, i.attendees
-                    FROM reminders
-                    INNER JOIN events USING (calendar_id, event_id)
-                    INNER JOIN next_dates AS i USING (calendar_id, event_id)
-                    ORDER BY timestamp
+                    SELECT reminder_id, instance_timestamp, is_primary, anchor, minutes_before,
+                        status, claimed_at, error
+                    FROM sent_reminders
+                    WHERE reminder_id = ANY($1)
+                    ORDER BY claimed_at DESC
                 "#,
-                &[],
+                &[&reminder_ids],
             )
             .await?;
 
-        let mut reminders = VecDeque::with_capacity(rows.len());
-        let now = Utc::now();
-
+        let mut sends = Vec::with_capacity(rows.len());
         for row in rows {
-            let event_id: String = row.get(0);
-            let summary: Option<String> = row.get(1);
-            let description: Option<String> = row.get(2);
-            let location: Option<String> = row.get(3);
-            let timestamp: DateTime<Utc> = row.get(4);
-            let room: String = row.get(5);
-            let minutes_before: i64 = row.get(6);
-            let template: Option<String> = row.get(7);
-            let attendees: Vec<Attendee> = row.get(8);
+            sends.push(ReminderSend {
+                reminder_id: row.try_get("reminder_id")?,
+                instance: row.try_get("instance_timestamp")?,
+                is_primary: row.try_get("is_primary")?,
+                anchor: row.try_get("anchor")?,
+                minutes_before: row.try_get("minutes_before")?,
+                status: row.try_get("status")?,
+                claimed_at: row.try_get("claimed_at")?,
+                error: row.try_get("error")?,
+            });
+        }
 
-            let reminder_time = timestamp - Duration::minutes(minutes_before);
-            if reminder_time < now {
-                // XXX: There's technically a race here if we reload the
-                // reminders just as we're about to send out a reminder.
-                debug!(now = ?now, reminder_time =?reminder_time, event_id = event_id.deref(), "Ignoring reminder");
-                continue;
-            }
+        Ok(sends)
+    }
 
-            let reminder = ReminderInstance {
-                event_id,
-                summary,
-                description,
-                location,
-                template,
-                minutes_before,
-                room,
-                attendees,
-            };
+    /// Resolve an interrupted send, by recording whether it was actually sent.
+    /// Sends marked as failed are retried if they're still within the
+    /// catch-up window.
+    pub async fn resolve_interrupted_send(
+        &self,
+        reminder_id: i64,
+        instance: DateTime<Utc>,
+        anchor: ReminderAnchor,
+        minutes_before: i64,
+        is_primary: bool,
+        sent: bool,
+    ) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
 
-            reminders.push_back((reminder_time, reminder));
-        }
+        let status = if sent {
+            SendStatus::Sent
+        } else {
+            SendStatus::Failed
+        };
 
-        reminders.make_contiguous().sort_by_key(|(t, _)| *t);
+        db_conn
+            .execute(
+                r#"
+                    UPDATE sent_reminders
+                    SET status = $5
+                    WHERE reminder_id = $1 AND instance_timestamp = $2 AND is_primary = $6
+                        AND (is_primary OR (anchor = $3 AND minutes_before = $4))
+                        AND status = 'Interrupted'
+                "#,
+                &[
+                    &reminder_id,
+                    &instance,
+                    &anchor,
+                    &minutes_before,
+                    &status,
+                    &is_primary,
+                ],
+            )
+            .await?;
 
-        Ok(reminders)
+        Ok(())
     }
 
     /// Get all events in a calendar
@@ -493,11 +2293,12 @@ impl Database {
         let rows = db_conn
             .query(
                 r#"
-                    SELECT DISTINCT ON (event_id) event_id, summary, description, location, timestamp,
-                        organizer, e.attendees AS event_attendees, i.attendees AS instance_attendees
+                    SELECT DISTINCT ON (event_id) event_id, summary, description, description_html, description_from_html, location, timestamp, end_timestamp, cancelled,
+                        organizer, e.attendees AS event_attendees, i.attendees AS instance_attendees, all_day, etag,
+                        e.join_url AS event_join_url, i.join_url AS instance_join_url, categories
                     FROM events AS e
                     INNER JOIN next_dates AS i USING (calendar_id, event_id)
-                    WHERE calendar_id = $1
+                    WHERE calendar_id = $1 AND timestamp >= now()
                     ORDER BY event_id, timestamp
                 "#,
                 &[&calendar_id],
@@ -510,21 +2311,28 @@ impl Database {
             let event_id: String = row.try_get("event_id")?;
             let summary = row.try_get("summary")?;
             let description = row.try_get("description")?;
+            let description_html = row.try_get("description_html")?;
+            let description_from_html = row.try_get("description_from_html")?;
             let location = row.try_get("location")?;
             let date = row.try_get("timestamp")?;
+            let end = row.try_get("end_timestamp")?;
+            let cancelled = row.try_get("cancelled")?;
+            let instance_join_url = row.try_get("instance_join_url")?;
+            let event_join_url = row.try_get("event_join_url")?;
+            let categories = row.try_get("categories")?;
             let organizer = row.try_get("organizer")?;
             let instance_attendees = row.try_get("instance_attendees")?;
             let event_attendees = row.try_get("event_attendees")?;
-
-            if date < Utc::now() {
-                // ignore events in the past
-                continue;
-            }
+            let all_day = row.try_get("all_day")?;
+            let etag = row.try_get("etag")?;
 
             let instance = EventInstance {
                 event_id: event_id.clone(),
                 date,
+                end,
                 attendees: instance_attendees,
+                cancelled,
+                join_url: instance_join_url,
             };
 
             if let Some((event, instances)) = events.last_mut() {
@@ -539,9 +2347,15 @@ impl Database {
                 event_id,
                 summary,
                 description,
+                description_html,
+                description_from_html,
                 location,
                 organizer,
                 attendees: event_attendees,
+                all_day,
+                etag,
+                join_url: event_join_url,
+                categories,
             };
             events.push((event, vec![instance]));
         }
@@ -551,6 +2365,45 @@ impl Database {
         Ok(events)
     }
 
+    /// Get all the events stored for a calendar, including those without any
+    /// upcoming instances.
+    pub async fn get_stored_events(&self, calendar_id: i64) -> Result<Vec<Event>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT event_id, summary, description, description_html, description_from_html, location, organizer,
+                        attendees, all_day, etag, join_url, categories
+                    FROM events
+                    WHERE calendar_id = $1
+                "#,
+                &[&calendar_id],
+            )
+            .await?;
+
+        let mut events = Vec::with_capacity(rows.len());
+        for row in rows {
+            events.push(Event {
+                calendar_id,
+                event_id: row.try_get("event_id")?,
+                summary: row.try_get("summary")?,
+                description: row.try_get("description")?,
+                description_html: row.try_get("description_html")?,
+                description_from_html: row.try_get("description_from_html")?,
+                location: row.try_get("location")?,
+                organizer: row.try_get("organizer")?,
+                attendees: row.try_get("attendees")?,
+                all_day: row.try_get("all_day")?,
+                etag: row.try_get("etag")?,
+                join_url: row.try_get("join_url")?,
+                categories: row.try_get("categories")?,
+            });
+        }
+
+        Ok(events)
+    }
+
     /// Get all events from all a given user's calendars.
     pub async fn get_events_for_user(
         &self,
@@ -561,12 +2414,13 @@ impl Database {
         let rows = db_conn
             .query(
                 r#"
-                    SELECT DISTINCT ON (calendar_id, event_id) calendar_id, event_id, summary, description, location, timestamp,
-                        organizer, e.attendees AS event_attendees, i.attendees AS instance_attendees
+                    SELECT DISTINCT ON (calendar_id, event_id) calendar_id, event_id, summary, description, description_html, description_from_html, location, timestamp, end_timestamp, cancelled,
+                        organizer, e.attendees AS event_attendees, i.attendees AS instance_attendees, all_day, etag,
+                        e.join_url AS event_join_url, i.join_url AS instance_join_url, categories
                     FROM calendars
                     INNER JOIN events AS e USING (calendar_id)
                     INNER JOIN next_dates AS i USING (calendar_id, event_id)
-                    WHERE user_id = $1
+                    WHERE user_id = $1 AND timestamp >= now()
                     ORDER BY calendar_id, event_id, timestamp
                 "#,
                 &[&user_id],
@@ -580,21 +2434,28 @@ impl Database {
             let event_id: String = row.try_get("event_id")?;
             let summary = row.try_get("summary")?;
             let description = row.try_get("description")?;
+            let description_html = row.try_get("description_html")?;
+            let description_from_html = row.try_get("description_from_html")?;
             let location = row.try_get("location")?;
             let date = row.try_get("timestamp")?;
+            let end = row.try_get("end_timestamp")?;
+            let cancelled = row.try_get("cancelled")?;
+            let instance_join_url = row.try_get("instance_join_url")?;
+            let event_join_url = row.try_get("event_join_url")?;
+            let categories = row.try_get("categories")?;
             let organizer = row.try_get("organizer")?;
             let instance_attendees = row.try_get("instance_attendees")?;
             let event_attendees = row.try_get("event_attendees")?;
-
-            if date < Utc::now() {
-                // ignore events in the past
-                continue;
-            }
+            let all_day = row.try_get("all_day")?;
+            let etag = row.try_get("etag")?;
 
             let instance = EventInstance {
                 event_id: event_id.clone(),
                 date,
+                end,
                 attendees: instance_attendees,
+                cancelled,
+                join_url: instance_join_url,
             };
 
             if let Some((event, instances)) = events.last_mut() {
@@ -609,9 +2470,15 @@ impl Database {
                 event_id,
                 summary,
                 description,
+                description_html,
+                description_from_html,
                 location,
                 organizer,
                 attendees: event_attendees,
+                all_day,
+                etag,
+                join_url: event_join_url,
+                categories,
             };
             events.push((event, vec![instance]));
         }
@@ -642,6 +2509,54 @@ impl Database {
         Ok(filtered_events)
     }
 
+    /// Whether we have the raw ICS of the specified event, and the user owns
+    /// the calendar it's in (and so can view it).
+    pub async fn has_event_ics(
+        &self,
+        user_id: i64,
+        calendar_id: i64,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_one(
+                r#"
+                    SELECT EXISTS (
+                        SELECT 1 FROM events
+                        INNER JOIN calendars USING (calendar_id)
+                        WHERE calendar_id = $1 AND event_id = $2 AND user_id = $3
+                            AND raw_ics IS NOT NULL
+                    )
+                "#,
+                &[&calendar_id, &event_id, &user_id],
+            )
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    /// Get the raw ICS the specified event was parsed from, if any.
+    pub async fn get_event_ics(
+        &self,
+        calendar_id: i64,
+        event_id: &str,
+    ) -> Result<Option<String>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_opt(
+                "SELECT raw_ics FROM events WHERE calendar_id = $1 AND event_id = $2",
+                &[&calendar_id, &event_id],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(row.try_get("raw_ics")?),
+            None => Ok(None),
+        }
+    }
+
     /// Get the specified event
     pub async fn get_event_in_calendar(
         &self,
@@ -653,8 +2568,8 @@ impl Database {
         let row = db_conn
             .query_opt(
                 r#"
-                    SELECT DISTINCT ON (event_id) event_id, summary, description, location,
-                        organizer, attendees
+                    SELECT DISTINCT ON (event_id) event_id, summary, description, description_html, description_from_html, location,
+                        organizer, attendees, all_day, etag, join_url, categories
                     FROM events
                     WHERE calendar_id = $1 AND event_id = $2
                 "#,
@@ -671,18 +2586,30 @@ impl Database {
         let event_id: String = row.try_get("event_id")?;
         let summary = row.try_get("summary")?;
         let description = row.try_get("description")?;
+        let description_html = row.try_get("description_html")?;
+        let description_from_html = row.try_get("description_from_html")?;
         let location = row.try_get("location")?;
         let attendees = row.try_get("attendees")?;
         let organizer = row.try_get("organizer")?;
+        let all_day = row.try_get("all_day")?;
+        let etag = row.try_get("etag")?;
+        let join_url = row.try_get("join_url")?;
+        let categories = row.try_get("categories")?;
 
         let event = Event {
             calendar_id,
             event_id: event_id.clone(),
             summary,
             description,
+            description_html,
+            description_from_html,
             location,
             attendees,
             organizer,
+            all_day,
+            etag,
+            join_url,
+            categories,
         };
 
         let mut instances = Vec::new();
@@ -690,9 +2617,9 @@ impl Database {
         let rows = db_conn
             .query(
                 r#"
-                    SELECT timestamp, attendees
+                    SELECT timestamp, end_timestamp, attendees, cancelled, join_url
                     FROM next_dates
-                    WHERE calendar_id = $1 AND event_id = $2
+                    WHERE calendar_id = $1 AND event_id = $2 AND timestamp >= now()
                     ORDER BY timestamp
                 "#,
                 &[&calendar_id, &event_id],
@@ -701,17 +2628,18 @@ impl Database {
 
         for row in rows {
             let date: DateTime<FixedOffset> = row.get("timestamp");
+            let end: Option<DateTime<FixedOffset>> = row.get("end_timestamp");
             let attendees: Vec<Attendee> = row.get("attendees");
-
-            if date < Utc::now() {
-                // ignore events in the past
-                continue;
-            }
+            let cancelled: bool = row.get("cancelled");
+            let join_url: Option<String> = row.get("join_url");
 
             let instance = EventInstance {
                 event_id: event_id.clone(),
                 date,
+                end,
                 attendees,
+                cancelled,
+                join_url,
             };
 
             instances.push(instance);
@@ -735,7 +2663,8 @@ impl Database {
             .query(
                 r#"
                     SELECT DISTINCT ON (reminder_id) reminders.calendar_id, reminders.user_id, reminder_id, room,
-                        minutes_before, attendee_editable, template
+                        minutes_before, anchor, attendee_editable, template, time_of_day, days_before, from_alarm,
+                        rule_id, outside_hours
                     FROM (
                         SELECT user_id, calendar_id, event_id, attendees
                         FROM events
@@ -752,6 +2681,7 @@ impl Database {
                             --- Or the reminder is attendee editable and they are an attendee
                             OR (attendee_editable AND users.email IN (
                                 SELECT email FROM UNNEST(attendees)
+                                WHERE status IS DISTINCT FROM 'Declined'
                             ))
                         )
                     "#,
@@ -766,8 +2696,14 @@ impl Database {
             let reminder_id = row.try_get("reminder_id")?;
             let room = row.try_get("room")?;
             let minutes_before = row.try_get("minutes_before")?;
+            let anchor = row.try_get("anchor")?;
             let template = row.try_get("template")?;
             let attendee_editable = row.try_get("attendee_editable")?;
+            let time_of_day = row.try_get("time_of_day")?;
+            let days_before = row.try_get("days_before")?;
+            let from_alarm = row.try_get("from_alarm")?;
+            let rule_id = row.try_get("rule_id")?;
+            let outside_hours = row.try_get("outside_hours")?;
 
             let reminder = Reminder {
                 reminder_id,
@@ -776,8 +2712,14 @@ impl Database {
                 event_id: event_id.to_string(),
                 room,
                 minutes_before,
+                anchor,
                 template,
                 attendee_editable,
+                time_of_day,
+                days_before,
+                from_alarm,
+                rule_id,
+                outside_hours,
             };
             reminders.push(reminder)
         }
@@ -812,6 +2754,7 @@ impl Database {
                             --- Or the reminder is attendee editable and they are an attendee
                             OR (attendee_editable AND users.email IN (
                                 SELECT email FROM UNNEST(attendees)
+                                WHERE status IS DISTINCT FROM 'Declined'
                             ))
                         )
                     "#,
@@ -841,7 +2784,8 @@ impl Database {
             .query_opt(
                 r#"
                     SELECT calendar_id, event_id, user_id, reminder_id, room, minutes_before,
-                        template, attendee_editable
+                        anchor, template, attendee_editable, time_of_day, days_before, from_alarm,
+                        rule_id, outside_hours
                     FROM reminders
                     WHERE calendar_id = $1 AND reminder_id = $2
                 "#,
@@ -861,8 +2805,14 @@ impl Database {
         let event_id = row.try_get("event_id")?;
         let room = row.try_get("room")?;
         let minutes_before = row.try_get("minutes_before")?;
+        let anchor = row.try_get("anchor")?;
         let template = row.try_get("template")?;
         let attendee_editable = row.try_get("attendee_editable")?;
+        let time_of_day = row.try_get("time_of_day")?;
+        let days_before = row.try_get("days_before")?;
+        let from_alarm = row.try_get("from_alarm")?;
+        let rule_id = row.try_get("rule_id")?;
+        let outside_hours = row.try_get("outside_hours")?;
 
         let reminder = Reminder {
             reminder_id,
@@ -871,13 +2821,106 @@ impl Database {
             event_id,
             template,
             minutes_before,
+            anchor,
             room,
             attendee_editable,
+            time_of_day,
+            days_before,
+            from_alarm,
+            rule_id,
+            outside_hours,
         };
 
         Ok(Some(reminder))
     }
 
+    /// Get the working hours of the rooms a user sends reminders to, along
+    /// with any they set themselves.
+    pub async fn get_room_policies(&self, user_id: i64) -> Result<Vec<RoomPolicy>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let rows = db_conn
+            .query(
+                r#"
+                    SELECT room, user_id, timezone, start_time, end_time, working_days
+                    FROM room_policies
+                    WHERE user_id = $1 OR room IN (
+                        SELECT room FROM reminders WHERE user_id = $1
+                        UNION SELECT room FROM reminder_rules WHERE user_id = $1
+                        UNION SELECT alarm_room FROM calendars WHERE user_id = $1
+                    )
+                    ORDER BY room
+                "#,
+                &[&user_id],
+            )
+            .await?;
+
+        let mut policies = Vec::with_capacity(rows.len());
+        for row in rows {
+            policies.push(RoomPolicy {
+                room: row.try_get("room")?,
+                user_id: row.try_get("user_id")?,
+                timezone: row.try_get("timezone")?,
+                start_time: row.try_get("start_time")?,
+                end_time: row.try_get("end_time")?,
+                working_days: row.try_get("working_days")?,
+            });
+        }
+
+        Ok(policies)
+    }
+
+    /// Add or replace the working hours of a room, which apply to everyone's
+    /// reminders to it.
+    ///
+    /// Returns false if the room's working hours were set by another user, in
+    /// which case they're left unchanged.
+    pub async fn upsert_room_policy(&self, policy: &RoomPolicy) -> Result<bool, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let updated = db_conn
+            .execute(
+                r#"
+                    INSERT INTO room_policies (
+                        room, user_id, timezone, start_time, end_time, working_days
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (room)
+                    DO UPDATE SET timezone = EXCLUDED.timezone, start_time = EXCLUDED.start_time,
+                        end_time = EXCLUDED.end_time, working_days = EXCLUDED.working_days
+                    WHERE room_policies.user_id = EXCLUDED.user_id
+                "#,
+                &[
+                    &policy.room,
+                    &policy.user_id,
+                    &policy.timezone,
+                    &policy.start_time,
+                    &policy.end_time,
+                    &policy.working_days,
+                ],
+            )
+            .await?;
+
+        Ok(updated > 0)
+    }
+
+    /// Delete the working hours of a room, if they were set by the given
+    /// user.
+    ///
+    /// Returns false if there were none to delete.
+    pub async fn delete_room_policy(&self, user_id: i64, room: &str) -> Result<bool, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let deleted = db_conn
+            .execute(
+                "DELETE FROM room_policies WHERE room = $1 AND user_id = $2",
+                &[&room, &user_id],
+            )
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
     /// Get the stored mappings from email to matrix ID.
     pub async fn get_user_mappings(&self) -> Result<BTreeMap<String, String>, Error> {
         let db_conn = self.db_pool.get().await?;
@@ -1072,6 +3115,48 @@ impl Database {
         Ok(ret.is_some())
     }
 
+    /// Get the email of the given user.
+    pub async fn get_user_email(&self, user_id: i64) -> Result<Option<String>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_opt("SELECT email FROM users WHERE user_id = $1", &[&user_id])
+            .await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Get the DM room we've previously created with the given Matrix user.
+    pub async fn get_direct_room(&self, matrix_id: &str) -> Result<Option<String>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let row = db_conn
+            .query_opt(
+                "SELECT room_id FROM direct_rooms WHERE matrix_id = $1",
+                &[&matrix_id],
+            )
+            .await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Record the DM room we've created with the given Matrix user.
+    pub async fn set_direct_room(&self, matrix_id: &str, room_id: &str) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        db_conn
+            .execute(
+                r#"
+                    INSERT INTO direct_rooms (matrix_id, room_id) VALUES ($1, $2)
+                    ON CONFLICT (matrix_id) DO UPDATE SET room_id = EXCLUDED.room_id
+                "#,
+                &[&matrix_id, &room_id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
     /// Record a new in flight SSO session.
     pub async fn add_sso_session(
         &self,
@@ -1122,6 +3207,62 @@ impl Database {
         Ok(None)
     }
 
+    /// Record the credentials used to discover calendars, until the user picks
+    /// which calendars to add. Also clears out any expired sessions.
+    pub async fn add_discovery_session(
+        &self,
+        token: &str,
+        user_id: i64,
+        user_name: Option<&str>,
+        password: Option<&str>,
+        expiry: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        db_conn
+            .execute("DELETE FROM discovery_sessions WHERE expiry <= NOW()", &[])
+            .await?;
+
+        db_conn
+            .execute(
+                r#"
+                INSERT INTO discovery_sessions (token, user_id, user_name, password, expiry)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+                &[&token, &user_id, &user_name, &password, &expiry],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Fetch (and delete) the user's discovery session with the given token,
+    /// returning the stored user name and password.
+    pub async fn claim_discovery_session(
+        &self,
+        token: &str,
+        user_id: i64,
+    ) -> Result<Option<(Option<String>, Option<String>)>, Error> {
+        let db_conn = self.db_pool.get().await?;
+
+        let ret = db_conn
+            .query_opt(
+                r#"
+                DELETE FROM discovery_sessions
+                WHERE token = $1 AND user_id = $2 AND expiry > NOW()
+                RETURNING user_name, password
+                "#,
+                &[&token, &user_id],
+            )
+            .await?;
+
+        if let Some(row) = ret {
+            return Ok(Some((row.try_get(0)?, row.try_get(1)?)));
+        }
+
+        Ok(None)
+    }
+
     /// Creates a new account if one doesn't exist for the email. Returns the
     /// user ID.
     pub async fn upsert_account(&self, email: &str) -> Result<i64, Error> {
@@ -1152,3 +3293,19 @@ impl Database {
         Ok(user_id)
     }
 }
+
+/// Work out when to send a reminder for an all-day event that should go out at
+/// a given local time, some number of days before the event.
+fn all_day_reminder_time(
+    date: DateTime<Utc>,
+    timezone: Tz,
+    days_before: i64,
+    time_of_day: NaiveTime,
+) -> Option<DateTime<Utc>> {
+    let day = date.with_timezone(&timezone).date().naive_local() - Duration::days(days_before);
+
+    timezone
+        .from_local_datetime(&day.and_time(time_of_day))
+        .earliest()
+        .map(|d| d.with_timezone(&Utc))
+}
@@ -0,0 +1,419 @@
+//! Detecting events that have been replaced by new ones.
+//!
+//! Some calendar systems (read: FastMail) create new events when people edit
+//! the times for future events, ending the old series. Since we want the
+//! reminders to apply to the new event we use some heuristics to detect this
+//! case, so that the reminders can be copied across.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use chrono::{Datelike, Timelike};
+use chrono_tz::Tz;
+use tracing::info;
+
+use crate::{
+    calendar::{ParsedCalendar, Recurrence},
+    database::{Calendar, Event, EventInstance},
+};
+
+/// What must match, other than the summary and organizer, for an event to be
+/// treated as replacing a previous one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupCriteria {
+    /// The events must start at the same local time of day.
+    pub same_time_of_day: bool,
+    /// The events must start on the same weekday.
+    pub same_weekday: bool,
+}
+
+impl DedupCriteria {
+    /// The criteria configured for the calendar.
+    pub fn for_calendar(calendar: &Calendar) -> Self {
+        DedupCriteria {
+            same_time_of_day: calendar.dedup_same_time_of_day,
+            same_weekday: calendar.dedup_same_weekday,
+        }
+    }
+
+    /// Whether the instances start at the same time of day and/or weekday,
+    /// as required.
+    fn start_times_match(
+        &self,
+        previous: Option<&EventInstance>,
+        new: Option<&EventInstance>,
+        timezone: Tz,
+    ) -> bool {
+        if !self.same_time_of_day && !self.same_weekday {
+            return true;
+        }
+
+        // If we don't know when the events happen we can't tell if they match.
+        let (previous, new) = match (previous, new) {
+            (Some(previous), Some(new)) => (
+                previous.date.with_timezone(&timezone),
+                new.date.with_timezone(&timezone),
+            ),
+            _ => return false,
+        };
+
+        if self.same_time_of_day
+            && (previous.hour(), previous.minute()) != (new.hour(), new.minute())
+        {
+            return false;
+        }
+
+        if self.same_weekday && previous.weekday() != new.weekday() {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// A previous event that seems to have been replaced by a new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplacedEvent {
+    pub previous_event_id: String,
+    pub new_event_id: String,
+}
+
+/// Find the previously stored events that have been replaced by new events in
+/// the freshly parsed calendar.
+///
+/// A previous event is a candidate if it has either disappeared or been given
+/// an end date, and is replaced by new events with the same summary and
+/// organizer that we haven't seen before.
+pub fn find_replaced_events(
+    previous_events: &[(Event, Vec<EventInstance>)],
+    parsed: &ParsedCalendar,
+    criteria: DedupCriteria,
+    timezone: Tz,
+) -> Vec<ReplacedEvent> {
+    let mut previous_events_by_id = HashMap::new();
+    for (previous_event, instances) in previous_events {
+        previous_events_by_id.insert(&previous_event.event_id, instances);
+    }
+
+    let mut events_by_summary: HashMap<_, Vec<_>> = HashMap::new();
+    for event in &parsed.events {
+        events_by_summary
+            .entry((&event.summary, &event.organizer))
+            .or_default()
+            .push(event);
+    }
+
+    let mut first_instances = HashMap::new();
+    for instance in &parsed.instances {
+        let first = first_instances
+            .entry(&instance.event_id)
+            .or_insert(instance);
+        if instance.date < first.date {
+            *first = instance;
+        }
+    }
+
+    let mut replaced = Vec::new();
+
+    for (previous_event, previous_instances) in previous_events {
+        // Figure out if we should attempt to deduplicate based on this event.
+        // We're either expecting it to not appear in the calendar or for it
+        // to be a recurring event that has an end date.
+        match parsed.recurrence.get(&previous_event.event_id) {
+            Some(Recurrence::Ongoing) => {
+                // The previous event hasn't been stopped, so we don't deduplicate.
+                info!(
+                    calendar_id = previous_event.calendar_id,
+                    event_id = previous_event.event_id.deref(),
+                    "Found existing event that hasn't expired."
+                );
+                continue;
+            }
+            Some(Recurrence::Ended) => {
+                // The previous event has been stopped, so we deduplicate.
+                info!(
+                    calendar_id = previous_event.calendar_id,
+                    event_id = previous_event.event_id.deref(),
+                    "Existing event has an expiry time, checking if we should deduplicate."
+                );
+            }
+            Some(Recurrence::Single) => {
+                // Not a recurring event, so don't need to deduplicate.
+                info!(
+                    calendar_id = previous_event.calendar_id,
+                    event_id = previous_event.event_id.deref(),
+                    "Found existing non-recurring event."
+                );
+                continue;
+            }
+            None => {}
+        }
+
+        for new_event in events_by_summary
+            .get(&(&previous_event.summary, &previous_event.organizer))
+            .map(|v| v.deref())
+            .unwrap_or_else(|| &[])
+        {
+            if previous_event.event_id == new_event.event_id {
+                // This is just an event that we already have.
+                continue;
+            }
+
+            if previous_events_by_id.contains_key(&new_event.event_id) {
+                // We've already processed the new event.
+                info!(
+                    calendar_id = previous_event.calendar_id,
+                    prev_event = previous_event.event_id.deref(),
+                    new_event = new_event.event_id.deref(),
+                    "Ignoring 'new_event' as its not new."
+                );
+                continue;
+            }
+
+            if !criteria.start_times_match(
+                previous_instances.first(),
+                first_instances.get(&new_event.event_id).copied(),
+                timezone,
+            ) {
+                info!(
+                    calendar_id = previous_event.calendar_id,
+                    prev_event = previous_event.event_id.deref(),
+                    new_event = new_event.event_id.deref(),
+                    "Ignoring 'new_event' as it happens at a different time."
+                );
+                continue;
+            }
+
+            replaced.push(ReplacedEvent {
+                previous_event_id: previous_event.event_id.clone(),
+                new_event_id: new_event.event_id.clone(),
+            });
+        }
+    }
+
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset};
+
+    use super::*;
+
+    fn event(event_id: &str, summary: &str) -> Event {
+        Event {
+            calendar_id: 1,
+            event_id: event_id.to_string(),
+            summary: Some(summary.to_string()),
+            description: None,
+            description_html: None,
+            description_from_html: false,
+            location: None,
+            organizer: None,
+            attendees: Vec::new(),
+            all_day: false,
+            etag: None,
+            join_url: None,
+            categories: Vec::new(),
+        }
+    }
+
+    fn instance(event_id: &str, date: &str) -> EventInstance {
+        EventInstance {
+            event_id: event_id.to_string(),
+            date: DateTime::<FixedOffset>::parse_from_rfc3339(date).unwrap(),
+            end: None,
+            attendees: Vec::new(),
+            cancelled: false,
+            join_url: None,
+        }
+    }
+
+    /// A previously stored "Standup" event, starting on a Monday at 09:00 in
+    /// London.
+    fn previous(event_id: &str) -> (Event, Vec<EventInstance>) {
+        (
+            event(event_id, "Standup"),
+            vec![instance(event_id, "2021-06-07T09:00:00+01:00")],
+        )
+    }
+
+    /// A parsed calendar with the given previous event recurrence and new
+    /// events, each with a single instance.
+    fn parsed(
+        previous: Option<(&str, Recurrence)>,
+        new_events: &[(&str, &str, &str)],
+    ) -> ParsedCalendar {
+        let mut parsed = ParsedCalendar::default();
+
+        if let Some((event_id, recurrence)) = previous {
+            parsed.events.push(event(event_id, "Standup"));
+            parsed.recurrence.insert(event_id.to_string(), recurrence);
+        }
+
+        for (event_id, summary, date) in new_events {
+            parsed.events.push(event(event_id, summary));
+            parsed.instances.push(instance(event_id, date));
+            parsed
+                .recurrence
+                .insert(event_id.to_string(), Recurrence::Ongoing);
+        }
+
+        parsed
+    }
+
+    fn replaced(previous_event_id: &str, new_event_id: &str) -> ReplacedEvent {
+        ReplacedEvent {
+            previous_event_id: previous_event_id.to_string(),
+            new_event_id: new_event_id.to_string(),
+        }
+    }
+
+    fn london() -> Tz {
+        "Europe/London".parse().unwrap()
+    }
+
+    #[test]
+    fn ended_or_removed_events_are_replaced() {
+        let new = [("new", "Standup", "2021-06-14T09:00:00+01:00")];
+
+        let parsed_ended = parsed(Some(("old", Recurrence::Ended)), &new);
+        assert_eq!(
+            find_replaced_events(
+                &[previous("old")],
+                &parsed_ended,
+                DedupCriteria::default(),
+                london()
+            ),
+            vec![replaced("old", "new")]
+        );
+
+        let parsed_removed = parsed(None, &new);
+        assert_eq!(
+            find_replaced_events(
+                &[previous("old")],
+                &parsed_removed,
+                DedupCriteria::default(),
+                london()
+            ),
+            vec![replaced("old", "new")]
+        );
+    }
+
+    #[test]
+    fn ongoing_and_single_events_are_not_replaced() {
+        let new = [("new", "Standup", "2021-06-14T09:00:00+01:00")];
+
+        for recurrence in &[Recurrence::Ongoing, Recurrence::Single] {
+            let parsed = parsed(Some(("old", *recurrence)), &new);
+            assert_eq!(
+                find_replaced_events(
+                    &[previous("old")],
+                    &parsed,
+                    DedupCriteria::default(),
+                    london()
+                ),
+                vec![],
+                "{:?}",
+                recurrence
+            );
+        }
+    }
+
+    #[test]
+    fn known_or_different_events_are_not_replacements() {
+        // The new event was already stored, so isn't new.
+        let parsed_known = parsed(
+            Some(("old", Recurrence::Ended)),
+            &[("other", "Standup", "2021-06-14T09:00:00+01:00")],
+        );
+        assert_eq!(
+            find_replaced_events(
+                &[previous("old"), previous("other")],
+                &parsed_known,
+                DedupCriteria::default(),
+                london()
+            ),
+            vec![]
+        );
+
+        // The new event has a different summary.
+        let parsed_different = parsed(
+            Some(("old", Recurrence::Ended)),
+            &[("new", "Retro", "2021-06-14T09:00:00+01:00")],
+        );
+        assert_eq!(
+            find_replaced_events(
+                &[previous("old")],
+                &parsed_different,
+                DedupCriteria::default(),
+                london()
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn same_time_of_day() {
+        let criteria = DedupCriteria {
+            same_time_of_day: true,
+            same_weekday: false,
+        };
+
+        // 09:00 on a Thursday in winter is the same local time of day, even
+        // though the UTC offset has changed.
+        let parsed_same = parsed(
+            Some(("old", Recurrence::Ended)),
+            &[("new", "Standup", "2021-12-02T09:00:00+00:00")],
+        );
+        assert_eq!(
+            find_replaced_events(&[previous("old")], &parsed_same, criteria, london()),
+            vec![replaced("old", "new")]
+        );
+
+        let parsed_moved = parsed(
+            Some(("old", Recurrence::Ended)),
+            &[("new", "Standup", "2021-06-14T10:30:00+01:00")],
+        );
+        assert_eq!(
+            find_replaced_events(&[previous("old")], &parsed_moved, criteria, london()),
+            vec![]
+        );
+
+        // Without any instances we can't tell when the new event is.
+        let mut parsed_no_instances = parsed_same.clone();
+        parsed_no_instances.instances.clear();
+        assert_eq!(
+            find_replaced_events(&[previous("old")], &parsed_no_instances, criteria, london()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn same_weekday() {
+        let criteria = DedupCriteria {
+            same_time_of_day: false,
+            same_weekday: true,
+        };
+
+        // A Monday, at a different time.
+        let parsed_same = parsed(
+            Some(("old", Recurrence::Ended)),
+            &[("new", "Standup", "2021-06-14T14:00:00+01:00")],
+        );
+        assert_eq!(
+            find_replaced_events(&[previous("old")], &parsed_same, criteria, london()),
+            vec![replaced("old", "new")]
+        );
+
+        // A Tuesday.
+        let parsed_moved = parsed(
+            Some(("old", Recurrence::Ended)),
+            &[("new", "Standup", "2021-06-15T09:00:00+01:00")],
+        );
+        assert_eq!(
+            find_replaced_events(&[previous("old")], &parsed_moved, criteria, london()),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,993 @@
+//! Fetching calendars from the Microsoft Graph API, i.e. Microsoft 365 and
+//! Exchange Online.
+
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Error};
+use chrono::{
+    DateTime, Duration, FixedOffset, NaiveDateTime, Offset, SecondsFormat, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use tracing::{info, instrument, warn, Span};
+
+use crate::{
+    calendar::{find_join_url, HttpStatusError, ParsedCalendar, Recurrence},
+    config::GraphConfig,
+    database::{Attendee, Calendar, Event, EventInstance, ParticipantRole, ParticipationStatus},
+    html::Description,
+};
+
+/// The Graph API endpoint to use if one isn't configured.
+const DEFAULT_BASE_URL: &str = "https://graph.microsoft.com/v1.0";
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/// A page of results from the `calendarView` endpoint.
+#[derive(Debug, Deserialize)]
+struct EventPage {
+    value: Vec<GraphEvent>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphEvent {
+    id: String,
+    series_master_id: Option<String>,
+    change_key: Option<String>,
+    subject: Option<String>,
+    body_preview: Option<String>,
+    body: Option<ItemBody>,
+    location: Option<Location>,
+    online_meeting: Option<OnlineMeeting>,
+    start: GraphDateTime,
+    end: Option<GraphDateTime>,
+    #[serde(default)]
+    is_all_day: bool,
+    #[serde(default)]
+    is_cancelled: bool,
+    #[serde(default)]
+    is_reminder_on: bool,
+    reminder_minutes_before_start: Option<i64>,
+    organizer: Option<Recipient>,
+    #[serde(default)]
+    attendees: Vec<GraphAttendee>,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ItemBody {
+    content_type: String,
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    display_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OnlineMeeting {
+    join_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphDateTime {
+    date_time: String,
+    time_zone: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Recipient {
+    email_address: EmailAddress,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailAddress {
+    name: Option<String>,
+    address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphAttendee {
+    email_address: EmailAddress,
+    status: Option<ResponseStatus>,
+    #[serde(rename = "type")]
+    attendee_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseStatus {
+    response: String,
+}
+
+/// An app-only access token.
+#[derive(Debug, Clone)]
+struct AppToken {
+    token: String,
+    expiry: DateTime<Utc>,
+}
+
+/// A client for the Graph API.
+///
+/// Calendars are read with the app's own credentials from the `[graph]`
+/// config, so we only ever read the calendars of the mailbox matching the
+/// calendar owner's email.
+#[derive(Debug, Clone)]
+pub struct GraphClient {
+    http_client: reqwest::Client,
+    config: Option<GraphConfig>,
+    app_token: Arc<Mutex<Option<AppToken>>>,
+}
+
+impl GraphClient {
+    pub fn new(http_client: reqwest::Client, config: Option<GraphConfig>) -> Self {
+        GraphClient {
+            http_client,
+            config,
+            app_token: Default::default(),
+        }
+    }
+
+    /// Get an app-only access token via the client credentials flow, reusing
+    /// the previous one until it is about to expire.
+    #[instrument(skip(self), fields(status))]
+    async fn get_app_token(&self) -> Result<String, Error> {
+        if let Some(app_token) = &*self.app_token.lock().expect("poisoned") {
+            if app_token.expiry > Utc::now() {
+                return Ok(app_token.token.clone());
+            }
+        }
+
+        let config = self
+            .config
+            .as_ref()
+            .context("Graph client credentials not configured")?;
+
+        let token_url = config.token_url.clone().unwrap_or_else(|| {
+            format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                config.tenant_id
+            )
+        });
+
+        let resp = self
+            .http_client
+            .post(&token_url)
+            .form(&[
+                ("client_id", config.client_id.as_str()),
+                ("client_secret", config.client_secret.as_str()),
+                ("scope", "https://graph.microsoft.com/.default"),
+                ("grant_type", "client_credentials"),
+            ])
+            .send()
+            .await
+            .with_context(|| "Sending HTTP token request")?;
+
+        let status = resp.status();
+        Span::current().record("status", &status.as_u16());
+
+        if !status.is_success() {
+            return Err(HttpStatusError {
+                status: status.as_u16(),
+                server: "Microsoft identity platform",
+            }
+            .into());
+        }
+
+        let body: TokenResponse = resp.json().await?;
+
+        // Refresh the token a bit before it actually expires.
+        let expiry = Utc::now() + Duration::seconds(body.expires_in - 60);
+        *self.app_token.lock().expect("poisoned") = Some(AppToken {
+            token: body.access_token.clone(),
+            expiry,
+        });
+
+        Ok(body.access_token)
+    }
+
+    /// The Graph API endpoint, without a trailing slash.
+    fn base_url(&self) -> &str {
+        self.config
+            .as_ref()
+            .and_then(|c| c.base_url.as_deref())
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+    }
+
+    /// Fetch the upcoming events in a calendar owned by the user with the given
+    /// email.
+    ///
+    /// The calendar's URL is the path of the calendar in the Graph API, e.g.
+    /// `users/alice@example.com/calendar`, and must be in the owner's mailbox.
+    #[instrument(skip(self, calendar), fields(calendar_id = calendar.calendar_id, status))]
+    pub async fn fetch_events(
+        &self,
+        calendar: &Calendar,
+        owner_email: &str,
+        timezone: Tz,
+        look_ahead: Duration,
+        keep_after: Duration,
+    ) -> Result<ParsedCalendar, Error> {
+        let path = calendar_path(&calendar.url, owner_email)?;
+        let base_url = self.base_url();
+        let calendar_url = format!("{}/{}", base_url, path);
+
+        let token = self.get_app_token().await?;
+
+        // We include the last day so that we pick up all-day events that are
+        // happening today, or longer if we're keeping events after they end.
+        let now = Utc::now();
+        let start =
+            (now - Duration::days(1).max(keep_after)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let end = (now + look_ahead).to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        let mut request = self
+            .http_client
+            .get(format!("{}/calendarView", calendar_url))
+            .query(&[
+                ("startDateTime", start.as_str()),
+                ("endDateTime", end.as_str()),
+                ("$top", "100"),
+            ]);
+
+        let mut graph_events = Vec::new();
+        loop {
+            let resp = request
+                .bearer_auth(&token)
+                .header(
+                    "Prefer",
+                    format!("outlook.timezone=\"{}\"", timezone.name()),
+                )
+                .send()
+                .await
+                .with_context(|| "Sending HTTP calendarView request")?;
+
+            let status = resp.status();
+            Span::current().record("status", &status.as_u16());
+
+            if !status.is_success() {
+                return Err(HttpStatusError {
+                    status: status.as_u16(),
+                    server: "Microsoft Graph",
+                }
+                .into());
+            }
+
+            let page: EventPage = resp.json().await?;
+            graph_events.extend(page.value);
+
+            match page.next_link {
+                // We only send the token to the configured endpoint.
+                Some(next_link) if next_link.starts_with(&format!("{}/", base_url)) => {
+                    request = self.http_client.get(&next_link)
+                }
+                Some(next_link) => bail!("Unexpected next link {}", next_link),
+                None => break,
+            }
+        }
+
+        info!(num = graph_events.len(), "Fetched Graph events");
+
+        Ok(parse_graph_events(
+            calendar.calendar_id,
+            graph_events,
+            look_ahead,
+            keep_after,
+        ))
+    }
+}
+
+/// Check the calendar's Graph path, returning it without any surrounding
+/// slashes.
+///
+/// The path must be of a calendar in the owner's own mailbox, i.e.
+/// `users/<owner email>/calendar` or `users/<owner email>/calendars/<id>`.
+fn calendar_path(url: &str, owner_email: &str) -> Result<String, Error> {
+    let path = url.trim_matches('/');
+    let segments: Vec<_> = path.split('/').collect();
+
+    let (mailbox, calendar_id) = match segments.as_slice() {
+        ["users", mailbox, "calendar"] => (*mailbox, None),
+        ["users", mailbox, "calendars", calendar_id] => (*mailbox, Some(*calendar_id)),
+        _ => bail!(
+            "Invalid Graph calendar path {}, expected e.g. users/{}/calendar",
+            url,
+            owner_email
+        ),
+    };
+
+    if !mailbox.eq_ignore_ascii_case(owner_email) {
+        bail!("Graph calendars can only be read from your own mailbox");
+    }
+
+    if let Some(calendar_id) = calendar_id {
+        let valid = !calendar_id.is_empty()
+            && calendar_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_=".contains(c));
+        if !valid {
+            bail!("Invalid Graph calendar ID {}", calendar_id);
+        }
+    }
+
+    Ok(path.to_string())
+}
+
+/// Turn the occurrences returned by `calendarView` into events and event
+/// instances.
+///
+/// The occurrences of a recurring event are grouped into a single event keyed
+/// by the ID of the series.
+fn parse_graph_events(
+    calendar_id: i64,
+    graph_events: Vec<GraphEvent>,
+    look_ahead: Duration,
+    keep_after: Duration,
+) -> ParsedCalendar {
+    let now = Utc::now();
+
+    let mut series: BTreeMap<String, Vec<GraphEvent>> = BTreeMap::new();
+    for graph_event in graph_events {
+        let event_id = graph_event
+            .series_master_id
+            .clone()
+            .unwrap_or_else(|| graph_event.id.clone());
+
+        series.entry(event_id).or_default().push(graph_event);
+    }
+
+    let mut parsed = ParsedCalendar::default();
+    for (event_id, occurrences) in series {
+        // We take the details of the event from the first occurrence, though
+        // the attendees are tracked per instance.
+        let first = &occurrences[0];
+
+        // `calendarView` doesn't tell us if a series has been given an end
+        // date, so we assume it hasn't.
+        let recurrence = if first.series_master_id.is_some() {
+            Recurrence::Ongoing
+        } else {
+            Recurrence::Single
+        };
+        parsed.recurrence.insert(event_id.clone(), recurrence);
+
+        if let (true, Some(minutes_before)) =
+            (first.is_reminder_on, first.reminder_minutes_before_start)
+        {
+            parsed.alarms.insert(event_id.clone(), vec![minutes_before]);
+        }
+
+        // The change keys of the occurrences change whenever they are edited,
+        // so we can use them as the ETag.
+        let etag = occurrences
+            .iter()
+            .map(|o| o.change_key.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let description = get_description(first);
+
+        parsed.events.push(Event {
+            calendar_id,
+            event_id: event_id.clone(),
+            summary: first.subject.clone(),
+            description: description.text,
+            description_html: description.html,
+            description_from_html: description.from_html,
+            location: first
+                .location
+                .as_ref()
+                .and_then(|l| l.display_name.clone())
+                .filter(|l| !l.is_empty()),
+            organizer: first
+                .organizer
+                .as_ref()
+                .and_then(|o| to_attendee(&o.email_address)),
+            attendees: get_attendees(first),
+            all_day: first.is_all_day,
+            etag: Some(etag),
+            join_url: get_join_url(first),
+            categories: first.categories.clone(),
+        });
+
+        let earliest = if first.is_all_day {
+            now - Duration::days(1)
+        } else {
+            now
+        };
+
+        for occurrence in &occurrences {
+            let date = match parse_date_time(&occurrence.start) {
+                Ok(date) => date,
+                Err(e) => {
+                    warn!(
+                        error = e.deref() as &dyn std::error::Error,
+                        event_id = occurrence.id.as_str(),
+                        "Failed to parse start of Graph event"
+                    );
+                    continue;
+                }
+            };
+
+            let end = occurrence
+                .end
+                .as_ref()
+                .and_then(|end| parse_date_time(end).ok());
+
+            // Instances that have started are kept until `keep_after` past
+            // their end, so that reminders after the end can still be sent.
+            if date < earliest && end.unwrap_or(date) + keep_after < now {
+                continue;
+            }
+
+            if date >= now + look_ahead {
+                continue;
+            }
+
+            parsed.instances.push(EventInstance {
+                event_id: event_id.clone(),
+                date,
+                end,
+                attendees: get_attendees(occurrence),
+                cancelled: occurrence.is_cancelled,
+                join_url: get_join_url(occurrence),
+            });
+        }
+    }
+
+    parsed
+}
+
+/// Parse a Graph date and time, which is a local time and a timezone name.
+///
+/// We ask for times in the calendar's timezone, so that all-day events start
+/// at midnight in that timezone.
+fn parse_date_time(date_time: &GraphDateTime) -> Result<DateTime<FixedOffset>, Error> {
+    let naive = NaiveDateTime::parse_from_str(&date_time.date_time, "%Y-%m-%dT%H:%M:%S%.f")
+        .with_context(|| format!("Invalid date time {}", date_time.date_time))?;
+
+    let timezone: Tz = match date_time.time_zone.parse() {
+        Ok(timezone) => timezone,
+        Err(_) => bail!("Unknown timezone {}", date_time.time_zone),
+    };
+
+    let local = timezone
+        .from_local_datetime(&naive)
+        .earliest()
+        .with_context(|| format!("Invalid local time {}", date_time.date_time))?;
+    let offset = local.offset().fix();
+
+    Ok(local.with_timezone(&offset))
+}
+
+/// Get the event's description as Markdown, along with the original HTML if
+/// the body is HTML.
+fn get_description(event: &GraphEvent) -> Description {
+    let body = match &event.body {
+        Some(body) if !body.content.trim().is_empty() => body,
+        _ => {
+            return match event.body_preview.clone() {
+                Some(preview) if !preview.trim().is_empty() => Description::plain(preview, None),
+                _ => Description::default(),
+            };
+        }
+    };
+
+    if body.content_type.eq_ignore_ascii_case("html") {
+        Description::from_html(body.content.clone())
+    } else {
+        Description::plain(body.content.clone(), None)
+    }
+}
+
+/// Get the link to join the event's online meeting, falling back to looking
+/// for one in its location or body.
+fn get_join_url(event: &GraphEvent) -> Option<String> {
+    if let Some(join_url) = event
+        .online_meeting
+        .as_ref()
+        .and_then(|m| m.join_url.clone())
+    {
+        return Some(join_url);
+    }
+
+    let location = event
+        .location
+        .as_ref()
+        .and_then(|l| l.display_name.as_deref());
+    let body = event.body.as_ref().map(|b| b.content.as_str());
+
+    location
+        .into_iter()
+        .chain(body)
+        .chain(event.body_preview.as_deref())
+        .find_map(find_join_url)
+}
+
+/// Get the attendees of the event, along with their responses.
+fn get_attendees(event: &GraphEvent) -> Vec<Attendee> {
+    event
+        .attendees
+        .iter()
+        .filter_map(|a| {
+            let mut attendee = to_attendee(&a.email_address)?;
+
+            attendee.status = a.status.as_ref().and_then(|s| match s.response.as_str() {
+                "accepted" | "organizer" => Some(ParticipationStatus::Accepted),
+                "tentativelyAccepted" => Some(ParticipationStatus::Tentative),
+                "declined" => Some(ParticipationStatus::Declined),
+                "notResponded" => Some(ParticipationStatus::NeedsAction),
+                _ => None,
+            });
+            attendee.role = match a.attendee_type.as_deref() {
+                Some("required") => Some(ParticipantRole::Required),
+                Some("optional") => Some(ParticipantRole::Optional),
+                Some("resource") => Some(ParticipantRole::NonParticipant),
+                _ => None,
+            };
+
+            Some(attendee)
+        })
+        .collect()
+}
+
+fn to_attendee(email_address: &EmailAddress) -> Option<Attendee> {
+    Some(Attendee {
+        email: email_address.address.clone()?,
+        common_name: email_address.name.clone(),
+        status: None,
+        role: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::database::{CalendarKind, OutsideHours};
+
+    use super::*;
+
+    /// The requests received by the mock Graph server, as the request line and
+    /// the `Authorization` header.
+    type Requests = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Start a mock Graph server that hands out an app token, and serves the
+    /// given `calendarView` pages in order.
+    ///
+    /// `{base}` in the pages is replaced with the server's Graph endpoint.
+    async fn mock_graph_server(pages: Vec<serde_json::Value>) -> (SocketAddr, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Requests::default();
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            let mut pages = pages.into_iter();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (request_line, authorization) = read_request(&mut stream).await;
+
+                let body = if request_line.starts_with("POST /token ") {
+                    json!({ "access_token": "app-token", "expires_in": 3600 })
+                } else {
+                    pages.next().unwrap_or_else(|| json!({ "value": [] }))
+                };
+
+                received.lock().unwrap().push((request_line, authorization));
+
+                let body = body
+                    .to_string()
+                    .replace("{base}", &format!("http://{}/v1.0", addr));
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (addr, requests)
+    }
+
+    /// Read a HTTP request, returning its request line and `Authorization`
+    /// header.
+    async fn read_request(stream: &mut TcpStream) -> (String, Option<String>) {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        let header_end = loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..read]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+
+        let headers = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+        let mut lines = headers.lines();
+        let request_line = lines.next().unwrap().to_string();
+
+        let mut authorization = None;
+        let mut content_length = 0;
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                match name.to_ascii_lowercase().as_str() {
+                    "authorization" => authorization = Some(value.trim().to_string()),
+                    "content-length" => content_length = value.trim().parse().unwrap(),
+                    _ => {}
+                }
+            }
+        }
+
+        while buf.len() < header_end + content_length {
+            let read = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..read]);
+        }
+
+        (request_line, authorization)
+    }
+
+    fn graph_client(addr: SocketAddr) -> GraphClient {
+        GraphClient::new(
+            reqwest::Client::new(),
+            Some(GraphConfig {
+                tenant_id: "tenant".to_string(),
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                base_url: Some(format!("http://{}/v1.0", addr)),
+                token_url: Some(format!("http://{}/token", addr)),
+            }),
+        )
+    }
+
+    fn calendar(url: &str) -> Calendar {
+        Calendar {
+            user_id: 1,
+            calendar_id: 1,
+            name: "Work".to_string(),
+            kind: CalendarKind::Graph,
+            url: url.to_string(),
+            user_name: None,
+            password: None,
+            timezone: None,
+            look_ahead_days: None,
+            poll_interval_minutes: None,
+            mirror_alarms: false,
+            alarm_room: None,
+            alarm_outside_hours: OutsideHours::Send,
+            dedup_same_time_of_day: false,
+            dedup_same_weekday: false,
+        }
+    }
+
+    /// Format a time the way Graph does, in UTC.
+    fn graph_time(time: DateTime<Utc>) -> serde_json::Value {
+        json!({
+            "dateTime": time.format("%Y-%m-%dT%H:%M:%S.0000000").to_string(),
+            "timeZone": "UTC",
+        })
+    }
+
+    /// An occurrence of a weekly meeting, starting the given number of days
+    /// from now.
+    fn occurrence(days: i64) -> serde_json::Value {
+        let start = Utc::now() + Duration::days(days);
+        json!({
+            "id": format!("occurrence-{}", days),
+            "seriesMasterId": "series",
+            "changeKey": format!("key-{}", days),
+            "subject": "Weekly sync",
+            "body": { "contentType": "text", "content": "Agenda" },
+            "location": { "displayName": "Room 1" },
+            "start": graph_time(start),
+            "end": graph_time(start + Duration::minutes(30)),
+            "isReminderOn": true,
+            "reminderMinutesBeforeStart": 15,
+            "organizer": { "emailAddress": { "name": "Alice", "address": "alice@example.com" } },
+            "attendees": [
+                {
+                    "emailAddress": { "name": "Bob", "address": "bob@example.com" },
+                    "status": { "response": "accepted" },
+                    "type": "required",
+                },
+            ],
+        })
+    }
+
+    fn parse_event(value: serde_json::Value) -> GraphEvent {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_events_from_mock_server() {
+        let (addr, requests) = mock_graph_server(vec![
+            json!({
+                "value": [occurrence(1)],
+                "@odata.nextLink": "{base}/users/alice@example.com/calendar/calendarView?page=2",
+            }),
+            json!({ "value": [occurrence(8)] }),
+        ])
+        .await;
+
+        let parsed = graph_client(addr)
+            .fetch_events(
+                &calendar("/users/alice@example.com/calendar/"),
+                "Alice@Example.com",
+                Tz::UTC,
+                Duration::days(30),
+                Duration::zero(),
+            )
+            .await
+            .unwrap();
+
+        // Both pages are fetched, and the occurrences grouped into one event.
+        assert_eq!(parsed.events.len(), 1);
+        assert_eq!(parsed.events[0].event_id, "series");
+        assert_eq!(parsed.events[0].summary.as_deref(), Some("Weekly sync"));
+        assert_eq!(parsed.instances.len(), 2);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].0.starts_with("POST /token "));
+        assert!(requests[1]
+            .0
+            .starts_with("GET /v1.0/users/alice@example.com/calendar/calendarView?"));
+        assert!(requests[2]
+            .0
+            .starts_with("GET /v1.0/users/alice@example.com/calendar/calendarView?page=2 "));
+        for (_, authorization) in &requests[1..] {
+            assert_eq!(authorization.as_deref(), Some("Bearer app-token"));
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_events_rejects_foreign_next_link() {
+        let (addr, requests) = mock_graph_server(vec![json!({
+            "value": [occurrence(1)],
+            "@odata.nextLink": "https://attacker.example.com/v1.0/calendarView",
+        })])
+        .await;
+
+        let result = graph_client(addr)
+            .fetch_events(
+                &calendar("users/alice@example.com/calendar"),
+                "alice@example.com",
+                Tz::UTC,
+                Duration::days(30),
+                Duration::zero(),
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_events_rejects_other_mailboxes() {
+        let (addr, requests) = mock_graph_server(vec![]).await;
+        let client = graph_client(addr);
+
+        for url in &[
+            "users/bob@example.com/calendar",
+            "https://attacker.example.com/users/alice@example.com/calendar",
+            "me/calendar",
+        ] {
+            let result = client
+                .fetch_events(
+                    &calendar(url),
+                    "alice@example.com",
+                    Tz::UTC,
+                    Duration::days(30),
+                    Duration::zero(),
+                )
+                .await;
+            assert!(result.is_err(), "{}", url);
+        }
+
+        // We don't even ask for a token.
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn calendar_path_only_allows_owner_mailbox() {
+        let owner = "alice@example.com";
+
+        assert_eq!(
+            calendar_path("users/alice@example.com/calendar", owner).unwrap(),
+            "users/alice@example.com/calendar"
+        );
+        assert_eq!(
+            calendar_path("/users/ALICE@example.com/calendars/AAMkAD-_=/", owner).unwrap(),
+            "users/ALICE@example.com/calendars/AAMkAD-_="
+        );
+
+        for url in &[
+            "users/bob@example.com/calendar",
+            "users/alice@example.com/calendar/../../bob@example.com/calendar",
+            "users/alice@example.com/calendars/../events",
+            "users/alice@example.com/calendars/id?$expand=x",
+            "http://graph.example.com/users/alice@example.com/calendar",
+            "//attacker.example.com/users/alice@example.com/calendar",
+            "me/calendar",
+            "",
+        ] {
+            assert!(calendar_path(url, owner).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn parse_graph_events_groups_series() {
+        let mut cancelled = occurrence(8);
+        cancelled["isCancelled"] = json!(true);
+
+        let single = json!({
+            "id": "single",
+            "changeKey": "single-key",
+            "subject": "One off",
+            "bodyPreview": "Join at https://zoom.us/j/123456",
+            "start": graph_time(Utc::now() + Duration::hours(2)),
+            "isAllDay": false,
+            "categories": ["Blue"],
+        });
+
+        let parsed = parse_graph_events(
+            1,
+            vec![
+                parse_event(occurrence(-1)),
+                parse_event(occurrence(1)),
+                parse_event(cancelled),
+                parse_event(occurrence(40)),
+                parse_event(single),
+            ],
+            Duration::days(30),
+            Duration::zero(),
+        );
+
+        assert_eq!(parsed.events.len(), 2);
+
+        let series = parsed
+            .events
+            .iter()
+            .find(|e| e.event_id == "series")
+            .unwrap();
+        assert_eq!(series.location.as_deref(), Some("Room 1"));
+        assert_eq!(series.description.as_deref(), Some("Agenda"));
+        assert_eq!(
+            series.organizer.as_ref().map(|o| o.email.as_str()),
+            Some("alice@example.com")
+        );
+        assert_eq!(series.etag.as_deref(), Some("key--1,key-1,key-8,key-40"));
+        assert_eq!(parsed.recurrence["series"], Recurrence::Ongoing);
+        assert_eq!(parsed.alarms["series"], vec![15]);
+
+        // Past and too distant occurrences are skipped.
+        let instances: Vec<_> = parsed
+            .instances
+            .iter()
+            .filter(|i| i.event_id == "series")
+            .collect();
+        assert_eq!(instances.len(), 2);
+        assert!(!instances[0].cancelled);
+        assert!(instances[1].cancelled);
+        assert_eq!(
+            instances[0].end.unwrap() - instances[0].date,
+            Duration::minutes(30)
+        );
+
+        let single = parsed
+            .events
+            .iter()
+            .find(|e| e.event_id == "single")
+            .unwrap();
+        assert_eq!(single.categories, vec!["Blue"]);
+        assert_eq!(single.join_url.as_deref(), Some("https://zoom.us/j/123456"));
+        assert_eq!(parsed.recurrence["single"], Recurrence::Single);
+        assert!(!parsed.alarms.contains_key("single"));
+    }
+
+    #[test]
+    fn attendee_responses_are_mapped() {
+        let mut event = occurrence(1);
+        event["attendees"] = json!([
+            { "emailAddress": { "address": "a@example.com" }, "status": { "response": "accepted" }, "type": "required" },
+            { "emailAddress": { "address": "b@example.com" }, "status": { "response": "organizer" } },
+            { "emailAddress": { "address": "c@example.com" }, "status": { "response": "tentativelyAccepted" }, "type": "optional" },
+            { "emailAddress": { "address": "d@example.com" }, "status": { "response": "declined" } },
+            { "emailAddress": { "address": "e@example.com" }, "status": { "response": "notResponded" }, "type": "resource" },
+            { "emailAddress": { "address": "f@example.com" }, "status": { "response": "none" } },
+            { "emailAddress": { "name": "No address" } },
+        ]);
+
+        let attendees = get_attendees(&parse_event(event));
+
+        let statuses: Vec<_> = attendees
+            .iter()
+            .map(|a| (a.email.as_str(), a.status, a.role))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (
+                    "a@example.com",
+                    Some(ParticipationStatus::Accepted),
+                    Some(ParticipantRole::Required)
+                ),
+                ("b@example.com", Some(ParticipationStatus::Accepted), None),
+                (
+                    "c@example.com",
+                    Some(ParticipationStatus::Tentative),
+                    Some(ParticipantRole::Optional)
+                ),
+                ("d@example.com", Some(ParticipationStatus::Declined), None),
+                (
+                    "e@example.com",
+                    Some(ParticipationStatus::NeedsAction),
+                    Some(ParticipantRole::NonParticipant)
+                ),
+                ("f@example.com", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_graph_events_keeps_instances_until_after_the_end() {
+        // A 60 minute meeting that started 70 minutes ago, so ended 10 minutes
+        // ago, with a reminder 15 minutes after the end.
+        let start = Utc::now() - Duration::minutes(70);
+        let meeting = json!({
+            "id": "retro",
+            "changeKey": "retro-key",
+            "subject": "Retro",
+            "start": graph_time(start),
+            "end": graph_time(start + Duration::hours(1)),
+            "isAllDay": false,
+        });
+
+        let parsed = parse_graph_events(
+            1,
+            vec![parse_event(meeting.clone())],
+            Duration::days(30),
+            Duration::minutes(15),
+        );
+        assert_eq!(parsed.instances.len(), 1);
+        assert_eq!(
+            parsed.instances[0].end.unwrap() - parsed.instances[0].date,
+            Duration::hours(1)
+        );
+
+        // Without any reminders after the end it's dropped.
+        let parsed = parse_graph_events(
+            1,
+            vec![parse_event(meeting)],
+            Duration::days(30),
+            Duration::zero(),
+        );
+        assert!(parsed.instances.is_empty());
+    }
+}
@@ -0,0 +1,479 @@
+//! Dealing with HTML, both the HTML that some calendar servers put in event
+//! descriptions and the HTML we send to Matrix.
+//!
+//! Both are cleaned with ammonia, and descriptions are then converted to
+//! Markdown with html2md.
+
+use std::collections::HashSet;
+
+use ammonia::UrlRelative;
+
+/// The tags we look for when deciding if some text is HTML.
+const HTML_TAGS: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "body",
+    "br",
+    "div",
+    "em",
+    "font",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "html",
+    "i",
+    "img",
+    "li",
+    "meta",
+    "ol",
+    "p",
+    "pre",
+    "span",
+    "strong",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "u",
+    "ul",
+];
+
+/// Tags whose content should be dropped entirely.
+const SKIPPED_TAGS: &[&str] = &["head", "script", "style", "title"];
+
+/// The tags we keep when converting descriptions to Markdown.
+const DESCRIPTION_TAGS: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "code",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "ul",
+];
+
+/// The tags that may be sent to Matrix, as suggested by the spec.
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "b",
+    "blockquote",
+    "br",
+    "caption",
+    "code",
+    "del",
+    "div",
+    "em",
+    "font",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "s",
+    "span",
+    "strike",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+
+/// The URL schemes that links may use.
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Whether the text looks like it's HTML, rather than plain text that happens
+/// to contain a `<`.
+pub fn looks_like_html(text: &str) -> bool {
+    text.match_indices('<').any(|(index, _)| {
+        let rest = &text[index + 1..];
+        let tag = rest.find('>').and_then(|end| parse_tag(&rest[..end]));
+
+        match tag {
+            Some(tag) => HTML_TAGS.contains(&tag.as_str()),
+            None => false,
+        }
+    })
+}
+
+/// Convert HTML into Markdown, keeping paragraphs, line breaks, lists,
+/// headings, emphasis and links. Everything else is reduced to its text.
+///
+/// The HTML is cleaned first, so the Markdown only has links with one of our
+/// allowed schemes and no raw HTML.
+pub fn html_to_markdown(html: &str) -> String {
+    let cleaned = ammonia::Builder::default()
+        .tags(DESCRIPTION_TAGS.iter().copied().collect())
+        .clean_content_tags(SKIPPED_TAGS.iter().copied().collect())
+        .generic_attributes(HashSet::new())
+        .tag_attributes(std::iter::once(("a", std::iter::once("href").collect())).collect())
+        .url_schemes(ALLOWED_SCHEMES.iter().copied().collect())
+        .url_relative(UrlRelative::Deny)
+        .link_rel(None)
+        .clean(html)
+        .to_string();
+
+    html2md::parse_html(&cleaned).trim().to_string()
+}
+
+/// An event's description, as we store it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Description {
+    /// The description as Markdown, or as the plain text it was written in.
+    pub text: Option<String>,
+    /// The original HTML, if there was any.
+    pub html: Option<String>,
+    /// Whether `text` was converted from `html`, and so is already cleaned
+    /// Markdown rather than plain text that needs escaping.
+    pub from_html: bool,
+}
+
+impl Description {
+    /// A plain text description, optionally with an HTML alternative that is
+    /// kept but not used for the text.
+    pub fn plain(text: String, html: Option<String>) -> Description {
+        Description {
+            text: Some(text),
+            html,
+            from_html: false,
+        }
+    }
+
+    /// A description converted from HTML.
+    pub fn from_html(html: String) -> Description {
+        Description {
+            text: Some(html_to_markdown(&html)).filter(|text| !text.is_empty()),
+            html: Some(html),
+            from_html: true,
+        }
+    }
+}
+
+/// Strip everything from the HTML that isn't on our allow list of tags,
+/// attributes and link schemes, so that it's safe to send to Matrix.
+///
+/// The content of disallowed tags is kept, except for e.g. scripts.
+pub fn sanitise_html(html: &str) -> String {
+    let attributes = [
+        ("a", std::iter::once("href").collect()),
+        ("ol", std::iter::once("start").collect()),
+        ("code", std::iter::once("class").collect()),
+    ];
+
+    ammonia::Builder::default()
+        .tags(ALLOWED_TAGS.iter().copied().collect())
+        .clean_content_tags(SKIPPED_TAGS.iter().copied().collect())
+        .generic_attributes(HashSet::new())
+        .tag_attributes(attributes.iter().cloned().collect())
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("ol", "start") => value.parse::<u32>().ok().map(|_| value.into()),
+            ("code", "class") => {
+                let language = value.strip_prefix("language-")?;
+                language
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    .then(|| value.into())
+            }
+            _ => Some(value.into()),
+        })
+        .url_schemes(ALLOWED_SCHEMES.iter().copied().collect())
+        .url_relative(UrlRelative::Deny)
+        .link_rel(None)
+        .clean(html)
+        .to_string()
+}
+
+/// Escape text so that it's shown as is when rendered as Markdown, rather
+/// than being interpreted as formatting, links or HTML.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    // Some characters only have a meaning at the start of a line, e.g. list
+    // markers and headings.
+    let mut at_line_start = true;
+    let mut in_leading_digits = false;
+
+    for c in text.chars() {
+        if c == '\n' {
+            escaped.push(c);
+            at_line_start = true;
+            in_leading_digits = false;
+            continue;
+        }
+
+        if at_line_start && (c == ' ' || c == '\t') {
+            escaped.push(c);
+            continue;
+        }
+
+        let needs_escape = "\\`*_[]()<>&~#".contains(c)
+            || (at_line_start && "-+=".contains(c))
+            || (in_leading_digits && (c == '.' || c == ')'));
+
+        in_leading_digits = (at_line_start || in_leading_digits) && c.is_ascii_digit();
+        at_line_start = false;
+
+        if needs_escape {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Parse the contents of a tag, i.e. the bit between the `<` and `>`,
+/// returning the tag's name.
+fn parse_tag(contents: &str) -> Option<String> {
+    let contents = contents.strip_prefix('/').unwrap_or(contents);
+
+    let name_len = contents
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(contents.len());
+    if name_len == 0 || !contents.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let (name, attributes) = contents.split_at(name_len);
+    if !attributes.is_empty() && !attributes.starts_with(|c: char| c.is_whitespace() || c == '/') {
+        return None;
+    }
+
+    Some(name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use comrak::{markdown_to_html, ComrakOptions};
+
+    use super::*;
+
+    /// Payloads that try to get HTML or links into the formatted message.
+    const PAYLOADS: &[&str] = &[
+        "<script>alert(1)</script>",
+        "<img src=x onerror=alert(1)>",
+        "<a href=\"javascript:alert(1)\">click</a>",
+        "<a href=\"https://example.com\" onclick=\"alert(1)\">click</a>",
+        "[click](javascript:alert(1))",
+        "![img](https://example.com/x.png)",
+        "&lt;script&gt;alert(1)&lt;/script&gt;",
+        "&#60;img src=x onerror=alert(1)&#62;",
+        "<a href=\"x>y\">broken</a>",
+    ];
+
+    /// Render Markdown the same way as messages sent to Matrix.
+    fn render(markdown: &str) -> String {
+        sanitise_html(&markdown_to_html(markdown, &ComrakOptions::default()))
+    }
+
+    #[test]
+    fn looks_like_html_needs_known_tags() {
+        assert!(looks_like_html("<p>Hello</p>"));
+        assert!(looks_like_html("Line one<br/>Line two"));
+        assert!(looks_like_html("<A HREF=\"https://example.com\">link</A>"));
+
+        assert!(!looks_like_html("Budget < 100 and > 50"));
+        assert!(!looks_like_html("Email <alice@example.com>"));
+        assert!(!looks_like_html("Plain text"));
+    }
+
+    #[test]
+    fn html_to_markdown_keeps_formatting() {
+        assert_eq!(
+            html_to_markdown("<p>Hello <b>world</b></p><p>Second<br>line</p>"),
+            "Hello **world**\n\nSecond  \nline"
+        );
+        assert_eq!(
+            html_to_markdown("<ul><li>one</li><li>two</li></ul>"),
+            "* one\n* two"
+        );
+        assert_eq!(
+            html_to_markdown(
+                "<p>Join at <a href=\"https://meet.example.com/abc\">the meeting</a></p>"
+            ),
+            "Join at [the meeting](https://meet.example.com/abc)"
+        );
+    }
+
+    #[test]
+    fn html_to_markdown_decodes_and_escapes_text() {
+        assert_eq!(
+            html_to_markdown("<p>Fish &amp; chips, &lt;b&gt;not bold&lt;/b&gt; *or this*</p>"),
+            "Fish & chips, \\<b\\>not bold\\</b\\> \\*or this\\*"
+        );
+    }
+
+    #[test]
+    fn html_to_markdown_drops_unsafe_content() {
+        let markdown = html_to_markdown(
+            "<html><head><title>Invite</title><style>p { color: red }</style></head>\
+             <body><script>alert(1)</script><p onclick=\"alert(1)\">Hi</p>\
+             <img src=\"https://tracker.example.com/x.png\" onerror=\"alert(1)\">\
+             <a href=\"javascript:alert(1)\">click</a> <a href=\"/relative\">here</a>\
+             <iframe src=\"https://example.com\"></iframe></body></html>",
+        );
+
+        assert!(!markdown.contains("alert"), "{}", markdown);
+        assert!(!markdown.contains("Invite"), "{}", markdown);
+        assert!(!markdown.contains("color"), "{}", markdown);
+        assert!(!markdown.contains("tracker"), "{}", markdown);
+        assert!(!markdown.contains("/relative"), "{}", markdown);
+        assert!(!markdown.contains('<'), "{}", markdown);
+        assert!(markdown.contains("Hi"), "{}", markdown);
+    }
+
+    #[test]
+    fn escape_markdown_keeps_text_as_written() {
+        assert_eq!(escape_markdown("Plain text"), "Plain text");
+        assert_eq!(
+            escape_markdown("*bold* and _em_"),
+            "\\*bold\\* and \\_em\\_"
+        );
+        assert_eq!(escape_markdown("[x](y)"), "\\[x\\]\\(y\\)");
+        assert_eq!(
+            escape_markdown("# Heading\n- item"),
+            "\\# Heading\n\\- item"
+        );
+        assert_eq!(escape_markdown("1. First"), "1\\. First");
+        assert_eq!(escape_markdown("a - b"), "a - b");
+
+        assert_eq!(
+            render(&escape_markdown("*bold* <b>")),
+            "<p>*bold* &lt;b&gt;</p>\n"
+        );
+        assert_eq!(
+            render(&escape_markdown("Fish &amp; chips")),
+            "<p>Fish &amp;amp; chips</p>\n"
+        );
+    }
+
+    #[test]
+    fn escaped_fields_cant_inject_markup() {
+        for payload in PAYLOADS {
+            let escaped = escape_markdown(payload);
+
+            // The places fields end up in the default template.
+            let templates = [
+                escaped.clone(),
+                format!("**Description:** {}", escaped),
+                format!("[Join meeting]({})", escaped),
+                format!("[{}](https://matrix.to/#/@alice:example.com)", escaped),
+            ];
+
+            for template in &templates {
+                let html = render(template);
+
+                // Only the template's own markup makes it through.
+                let tags = [
+                    "<p>",
+                    "</p>",
+                    "<strong>",
+                    "</strong>",
+                    "<a>",
+                    "<a href=\"",
+                    "</a>",
+                ];
+                for (index, _) in html.match_indices('<') {
+                    assert!(
+                        tags.iter().any(|tag| html[index..].starts_with(tag)),
+                        "{} -> {}",
+                        payload,
+                        html
+                    );
+                }
+                for (index, _) in html.match_indices("<a href=\"") {
+                    assert!(
+                        html[index + 9..].starts_with("https://"),
+                        "{} -> {}",
+                        payload,
+                        html
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sanitise_html_drops_unsafe_markup() {
+        assert_eq!(sanitise_html("<script>alert(1)</script>Hi"), "Hi");
+        assert_eq!(sanitise_html("<img src=x onerror=alert(1)>Hi"), "Hi");
+        assert_eq!(
+            sanitise_html("<a href=\"javascript:alert(1)\">click</a>"),
+            "<a>click</a>"
+        );
+        assert_eq!(
+            sanitise_html("<a href=\"JaVaScRiPt:alert(1)\">click</a>"),
+            "<a>click</a>"
+        );
+        assert_eq!(
+            sanitise_html("<a href=\"&#106;avascript:alert(1)\">click</a>"),
+            "<a>click</a>"
+        );
+        assert_eq!(
+            sanitise_html(
+                "<a href=\"https://example.com\" onclick=\"alert(1)\" style=\"x\" target=\"_blank\">click</a>"
+            ),
+            "<a href=\"https://example.com\">click</a>"
+        );
+        assert_eq!(sanitise_html("<a href=\"x>y\">broken</a>"), "<a>broken</a>");
+        assert_eq!(
+            sanitise_html("&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+        assert_eq!(
+            sanitise_html("<p onmouseover=\"alert(1)\">Hi</p>"),
+            "<p>Hi</p>"
+        );
+        assert_eq!(
+            sanitise_html("<iframe src=\"https://example.com\"></iframe>Hi"),
+            "Hi"
+        );
+    }
+
+    #[test]
+    fn sanitise_html_keeps_allowed_markup() {
+        assert_eq!(
+            sanitise_html("<p><strong>Hi</strong> <a href=\"mailto:a@example.com\">mail</a></p>"),
+            "<p><strong>Hi</strong> <a href=\"mailto:a@example.com\">mail</a></p>"
+        );
+        assert_eq!(
+            sanitise_html("<ol start=\"3\"><li>x</li></ol><ol start=\"x\"><li>y</li></ol>"),
+            "<ol start=\"3\"><li>x</li></ol><ol><li>y</li></ol>"
+        );
+        assert_eq!(
+            sanitise_html("<code class=\"language-rust\">x</code><code class=\"evil\">y</code>"),
+            "<code class=\"language-rust\">x</code><code>y</code>"
+        );
+    }
+}
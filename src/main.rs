@@ -1,7 +1,8 @@
 //! # Calendar Bot
 //!
-//! Calendar Bot is an app that connects to online calendars (via CalDAV) and
-//! allows scheduling reminders for them, which are sent to Matrix rooms.
+//! Calendar Bot is an app that connects to calendars (via CalDAV, ICS feeds,
+//! Microsoft Graph or local files) and allows scheduling reminders for them,
+//! which are sent to Matrix rooms.
 //! Updates to events are correctly handled by the associated reminders.
 
 use std::{fs, path::Path};
@@ -21,7 +22,14 @@ mod auth;
 mod calendar;
 mod config;
 mod database;
+mod dedup;
+mod graph;
+mod html;
+mod policy;
+mod rules;
+mod scheduler;
 mod site;
+mod source;
 
 use app::App;
 use database::Database;
@@ -30,7 +38,9 @@ use tokio::task::spawn_local;
 
 /// Default markdown template used for generating reminder events.
 const DEFAULT_TEMPLATE: &str = r#"
-**{{ summary }}** {{#if (gt minutes_before 0) }}starts in {{ minutes_before }} minutes {{/if}}{{#if location}}at {{ location }} {{/if}}{{#if attendees}} ─ {{ attendees }}{{/if}}{{#if description}}
+**{{ summary }}** {{#if when }}{{ when }} {{/if}}{{#if location}}at {{ location }} {{/if}}{{#if attendees}} ─ {{ attendees }}{{/if}}{{#if join_url}}
+
+[Join meeting]({{ join_url }}){{/if}}{{#if description}}
 
 **Description:** {{ description }}
 {{/if}}
@@ -93,7 +103,10 @@ async fn start(config: Config) -> Result<(), Error> {
 
     let resource_directory = Path::new(config.app.resource_directory.as_deref().unwrap_or("res"));
 
-    let templates = Tera::new(&resource_directory.join("*").to_string_lossy())?;
+    let mut templates = Tera::new(&resource_directory.join("*").to_string_lossy())?;
+
+    // Tera only escapes templates ending in e.g. `.html` by default.
+    templates.autoescape_on(vec![".html.j2"]);
 
     let app = App::new(config, database, templates).await?;
 
@@ -0,0 +1,133 @@
+//! Working hours for rooms.
+//!
+//! Reminders with large offsets, or for events in other timezones, can end up
+//! being due in the middle of the night. Users can set the working hours of
+//! the rooms they send to, and choose per reminder what happens to it if it's
+//! due outside of them.
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc, Weekday};
+
+use crate::database::{OutsideHours, RoomPolicy};
+
+/// Work out when a reminder that is due at `time` should be sent, given the
+/// working hours of the room it's sent to.
+///
+/// Reminders aren't deferred past `latest`, and are dropped instead.
+///
+/// Returns `None` if the reminder should be dropped.
+pub fn apply_room_policy(
+    policy: &RoomPolicy,
+    outside_hours: OutsideHours,
+    time: DateTime<Utc>,
+    latest: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    if is_working_time(policy, time) {
+        return Some(time);
+    }
+
+    match outside_hours {
+        OutsideHours::Send => Some(time),
+        OutsideHours::Defer => next_working_time(policy, time).filter(|next| *next <= latest),
+        OutsideHours::Drop => None,
+    }
+}
+
+/// Whether the time is within the room's working hours.
+pub fn is_working_time(policy: &RoomPolicy, time: DateTime<Utc>) -> bool {
+    let local = time.with_timezone(&policy.tz());
+
+    is_working_day(policy, local.weekday())
+        && policy.start_time <= local.time()
+        && local.time() < policy.end_time
+}
+
+/// Get the first time at or after the given one that is within the room's
+/// working hours, if there is one in the next week.
+pub fn next_working_time(policy: &RoomPolicy, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if is_working_time(policy, time) {
+        return Some(time);
+    }
+
+    let timezone = policy.tz();
+    let local = time.with_timezone(&timezone);
+
+    for days in 0..=7 {
+        let date = local.date().naive_local() + Duration::days(days);
+        if !is_working_day(policy, date.weekday()) {
+            continue;
+        }
+
+        // If the start of the day's working hours doesn't exist (due to DST)
+        // we just skip to the next day.
+        let start = match timezone
+            .from_local_datetime(&date.and_time(policy.start_time))
+            .earliest()
+        {
+            Some(start) => start,
+            None => continue,
+        };
+
+        if start > local {
+            return Some(start.with_timezone(&Utc));
+        }
+    }
+
+    None
+}
+
+/// Whether the weekday is one of the room's working days.
+fn is_working_day(policy: &RoomPolicy, weekday: Weekday) -> bool {
+    policy
+        .working_days
+        .contains(&i64::from(weekday.number_from_monday()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use super::*;
+
+    fn policy() -> RoomPolicy {
+        RoomPolicy {
+            room: "#room:example.com".to_string(),
+            user_id: 1,
+            timezone: "Europe/London".to_string(),
+            start_time: NaiveTime::from_hms(9, 0, 0),
+            end_time: NaiveTime::from_hms(17, 30, 0),
+            working_days: vec![1, 2, 3, 4, 5],
+        }
+    }
+
+    #[test]
+    fn defer_to_next_working_time() {
+        // A Tuesday in winter, so London is UTC.
+        let time = Utc.ymd(2021, 11, 2).and_hms(7, 0, 0);
+        let start = Utc.ymd(2021, 11, 2).and_hms(10, 0, 0);
+
+        assert_eq!(
+            apply_room_policy(&policy(), OutsideHours::Defer, time, start),
+            Some(Utc.ymd(2021, 11, 2).and_hms(9, 0, 0))
+        );
+        assert_eq!(
+            apply_room_policy(&policy(), OutsideHours::Send, time, start),
+            Some(time)
+        );
+        assert_eq!(
+            apply_room_policy(&policy(), OutsideHours::Drop, time, start),
+            None
+        );
+    }
+
+    #[test]
+    fn defer_never_past_latest() {
+        // "Starts at 08:00" must not be sent at 09:00.
+        let time = Utc.ymd(2021, 11, 2).and_hms(7, 45, 0);
+        let start = Utc.ymd(2021, 11, 2).and_hms(8, 0, 0);
+
+        assert_eq!(
+            apply_room_policy(&policy(), OutsideHours::Defer, time, start),
+            None
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Rules that automatically add reminders to the matching events in a
+//! calendar.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use regex::{Regex, RegexBuilder};
+use tracing::warn;
+
+use crate::database::{Event, OutsideHours, ReminderAnchor, ReminderRule};
+
+/// Compile the regex that a rule matches event summaries against.
+pub fn compile_summary_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(1 << 20)
+        .build()
+}
+
+/// A reminder rule that is ready to be matched against events.
+#[derive(Debug, Clone)]
+struct RuleMatcher<'a> {
+    rule: &'a ReminderRule,
+    summary_regex: Option<Regex>,
+}
+
+impl<'a> RuleMatcher<'a> {
+    fn new(rule: &'a ReminderRule) -> Result<Self, regex::Error> {
+        let summary_regex = rule
+            .summary_regex
+            .as_deref()
+            .map(compile_summary_regex)
+            .transpose()?;
+
+        Ok(RuleMatcher {
+            rule,
+            summary_regex,
+        })
+    }
+
+    /// Whether the event matches all of the rule's criteria.
+    fn matches(&self, event: &Event) -> bool {
+        if let Some(summary_regex) = &self.summary_regex {
+            if !summary_regex.is_match(event.summary.as_deref().unwrap_or_default()) {
+                return false;
+            }
+        }
+
+        if let Some(organizer) = &self.rule.organizer {
+            match &event.organizer {
+                Some(o) if o.email.eq_ignore_ascii_case(organizer) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(attendee) = &self.rule.attendee {
+            if !event
+                .attendees
+                .iter()
+                .any(|a| !a.has_declined() && a.email.eq_ignore_ascii_case(attendee))
+            {
+                return false;
+            }
+        }
+
+        if let Some(category) = &self.rule.category {
+            if !event
+                .categories
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(category))
+            {
+                return false;
+            }
+        }
+
+        if let Some(location) = &self.rule.location {
+            let event_location = event.location.as_deref().unwrap_or_default();
+            if !event_location
+                .to_lowercase()
+                .contains(&location.to_lowercase())
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Match the rules against the events, returning the pairs of rule ID and
+/// event ID that match.
+pub fn match_rules(rules: &[ReminderRule], events: &[Event]) -> BTreeSet<(i64, String)> {
+    let mut matches = BTreeSet::new();
+
+    for rule in rules {
+        let matcher = match RuleMatcher::new(rule) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                warn!(
+                    error = &e as &dyn std::error::Error,
+                    rule_id = rule.rule_id,
+                    "Invalid reminder rule"
+                );
+                continue;
+            }
+        };
+
+        for event in events {
+            if matcher.matches(event) {
+                matches.insert((rule.rule_id, event.event_id.clone()));
+            }
+        }
+    }
+
+    matches
+}
+
+/// A reminder that a rule added to an event.
+#[derive(Debug, Clone)]
+pub struct RuleReminder {
+    pub reminder_id: i64,
+    pub rule_id: i64,
+    pub event_id: String,
+    pub room: String,
+    pub minutes_before: i64,
+    pub anchor: ReminderAnchor,
+    pub template: Option<String>,
+    pub outside_hours: OutsideHours,
+}
+
+impl RuleReminder {
+    /// Whether the reminder has the rule's current settings.
+    fn is_up_to_date(&self, rule: &ReminderRule) -> bool {
+        self.room == rule.room
+            && self.minutes_before == rule.minutes_before
+            && self.anchor == rule.anchor
+            && self.template == rule.template
+            && self.outside_hours == rule.outside_hours
+    }
+}
+
+/// A change needed to make the reminders that rules added match the rules.
+#[derive(Debug, Clone)]
+pub enum RuleReminderChange<'a> {
+    /// Add a reminder for the rule to the event with the given ID.
+    Add(&'a ReminderRule, String),
+    /// Give the reminder with the given ID the rule's current settings.
+    Update(i64, &'a ReminderRule),
+    /// Delete the reminder with the given ID, as its rule no longer matches.
+    Delete(i64),
+}
+
+/// Work out how to make the reminders that rules added match the rules and
+/// the pairs of rule ID and event ID that match them.
+///
+/// Reminders are updated in place when their rule is edited, as sends are
+/// recorded against the reminder. Replacing them would send them again.
+pub fn plan_rule_reminders<'a>(
+    rules: &'a [ReminderRule],
+    existing: &[RuleReminder],
+    matches: &BTreeSet<(i64, String)>,
+) -> Vec<RuleReminderChange<'a>> {
+    let rules_by_id: BTreeMap<_, _> = rules.iter().map(|rule| (rule.rule_id, rule)).collect();
+
+    let mut changes = Vec::new();
+    let mut kept = BTreeSet::new();
+    for reminder in existing {
+        let key = (reminder.rule_id, reminder.event_id.clone());
+        match rules_by_id.get(&reminder.rule_id) {
+            Some(rule) if matches.contains(&key) => {
+                if !reminder.is_up_to_date(rule) {
+                    changes.push(RuleReminderChange::Update(reminder.reminder_id, rule));
+                }
+                kept.insert(key);
+            }
+            _ => changes.push(RuleReminderChange::Delete(reminder.reminder_id)),
+        }
+    }
+
+    for (rule_id, event_id) in matches.difference(&kept) {
+        if let Some(rule) = rules_by_id.get(rule_id) {
+            changes.push(RuleReminderChange::Add(rule, event_id.clone()));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule_id: i64, room: &str, minutes_before: i64) -> ReminderRule {
+        ReminderRule {
+            rule_id,
+            user_id: 1,
+            calendar_id: 1,
+            summary_regex: Some("^Standup".to_string()),
+            organizer: None,
+            attendee: None,
+            category: None,
+            location: None,
+            room: room.to_string(),
+            minutes_before,
+            anchor: ReminderAnchor::Start,
+            template: None,
+            outside_hours: OutsideHours::Send,
+        }
+    }
+
+    fn reminder(reminder_id: i64, rule: &ReminderRule, event_id: &str) -> RuleReminder {
+        RuleReminder {
+            reminder_id,
+            rule_id: rule.rule_id,
+            event_id: event_id.to_string(),
+            room: rule.room.clone(),
+            minutes_before: rule.minutes_before,
+            anchor: rule.anchor,
+            template: rule.template.clone(),
+            outside_hours: rule.outside_hours,
+        }
+    }
+
+    fn matches(pairs: &[(i64, &str)]) -> BTreeSet<(i64, String)> {
+        pairs
+            .iter()
+            .map(|(rule_id, event_id)| (*rule_id, event_id.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_rules_leave_their_reminders() {
+        let rules = [rule(1, "#team:example.com", 15)];
+        let existing = [reminder(10, &rules[0], "standup")];
+
+        let changes = plan_rule_reminders(&rules, &existing, &matches(&[(1, "standup")]));
+
+        assert!(changes.is_empty(), "{:?}", changes);
+    }
+
+    #[test]
+    fn editing_a_rule_after_a_send_keeps_the_reminder() {
+        // Reminder 10 has been sent for the next standup, then the rule is
+        // moved to another room and an earlier time.
+        let sent = rule(1, "#team:example.com", 15);
+        let existing = [reminder(10, &sent, "standup")];
+        let rules = [rule(1, "#other:example.com", 30)];
+
+        let changes = plan_rule_reminders(&rules, &existing, &matches(&[(1, "standup")]));
+
+        // The reminder keeps its ID, and so the record of the send.
+        assert_eq!(changes.len(), 1, "{:?}", changes);
+        assert!(
+            matches!(changes[0], RuleReminderChange::Update(10, rule) if rule.room == "#other:example.com"),
+            "{:?}",
+            changes
+        );
+    }
+
+    #[test]
+    fn rules_add_and_delete_reminders_as_matches_change() {
+        let rules = [rule(1, "#team:example.com", 15)];
+        let existing = [reminder(10, &rules[0], "standup")];
+
+        let changes = plan_rule_reminders(&rules, &existing, &matches(&[(1, "planning")]));
+
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(matches!(changes[0], RuleReminderChange::Delete(10)));
+        assert!(
+            matches!(&changes[1], RuleReminderChange::Add(rule, event_id) if rule.rule_id == 1 && event_id == "planning")
+        );
+
+        // Reminders of deleted rules are removed.
+        let changes = plan_rule_reminders(&[], &existing, &matches(&[]));
+        assert!(matches!(changes[..], [RuleReminderChange::Delete(10)]));
+    }
+}
@@ -0,0 +1,265 @@
+//! Scheduling of calendar updates.
+//!
+//! Each calendar is polled on its own interval, backing off exponentially
+//! while updating it keeps failing.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+
+/// The longest we'll wait between attempts to update a failing calendar,
+/// unless its poll interval is longer.
+const MAX_BACKOFF_MINUTES: i64 = 6 * 60;
+
+/// When a calendar should next be updated.
+#[derive(Debug, Clone)]
+struct ScheduleEntry {
+    interval: Duration,
+    next_update: DateTime<Utc>,
+    consecutive_failures: u32,
+    in_progress: bool,
+}
+
+/// Keeps track of when each calendar is next due to be updated.
+///
+/// This doesn't do any updating itself, the caller asks for the calendars
+/// that are due and then reports back whether updating them succeeded.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    default_interval: Duration,
+    entries: BTreeMap<i64, ScheduleEntry>,
+}
+
+impl Scheduler {
+    /// Create a scheduler that polls calendars every `default_interval`,
+    /// unless they have their own poll interval.
+    pub fn new(default_interval: Duration) -> Self {
+        Scheduler {
+            default_interval,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Set the calendars to schedule, given as their ID and poll interval (if
+    /// they have one).
+    ///
+    /// Calendars we haven't seen before are due immediately, and calendars
+    /// that are missing are no longer scheduled.
+    pub fn set_calendars(
+        &mut self,
+        calendars: impl IntoIterator<Item = (i64, Option<Duration>)>,
+        now: DateTime<Utc>,
+    ) {
+        let mut entries = BTreeMap::new();
+
+        for (calendar_id, interval) in calendars {
+            let interval = interval.unwrap_or(self.default_interval);
+
+            let entry = match self.entries.remove(&calendar_id) {
+                Some(mut entry) => {
+                    entry.interval = interval;
+                    entry
+                }
+                None => ScheduleEntry {
+                    interval,
+                    next_update: now,
+                    consecutive_failures: 0,
+                    in_progress: false,
+                },
+            };
+
+            entries.insert(calendar_id, entry);
+        }
+
+        self.entries = entries;
+    }
+
+    /// Take up to `limit` of the calendars that are due to be updated, most
+    /// overdue first.
+    ///
+    /// The calendars are marked as in progress until their result is
+    /// recorded, so they won't be returned again in the meantime.
+    pub fn take_due(&mut self, now: DateTime<Utc>, limit: usize) -> Vec<i64> {
+        let mut due: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.in_progress && entry.next_update <= now)
+            .map(|(calendar_id, entry)| (entry.next_update, *calendar_id))
+            .collect();
+
+        due.sort();
+        due.truncate(limit);
+
+        for (_, calendar_id) in &due {
+            if let Some(entry) = self.entries.get_mut(calendar_id) {
+                entry.in_progress = true;
+            }
+        }
+
+        due.into_iter()
+            .map(|(_, calendar_id)| calendar_id)
+            .collect()
+    }
+
+    /// Record that a calendar was updated, scheduling its next update after
+    /// its poll interval.
+    pub fn record_success(&mut self, calendar_id: i64, now: DateTime<Utc>) {
+        if let Some(entry) = self.entries.get_mut(&calendar_id) {
+            entry.in_progress = false;
+            entry.consecutive_failures = 0;
+            entry.next_update = now + entry.interval;
+        }
+    }
+
+    /// Record that updating a calendar failed, returning how long we'll wait
+    /// before trying again.
+    pub fn record_failure(&mut self, calendar_id: i64, now: DateTime<Utc>) -> Duration {
+        if let Some(entry) = self.entries.get_mut(&calendar_id) {
+            entry.in_progress = false;
+            entry.consecutive_failures = entry.consecutive_failures.saturating_add(1);
+
+            let backoff = backoff(entry.interval, entry.consecutive_failures);
+            entry.next_update = now + backoff;
+
+            backoff
+        } else {
+            self.default_interval
+        }
+    }
+
+    /// The time until the next calendar that isn't already being updated is
+    /// due, if there are any.
+    pub fn time_to_next(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.entries
+            .values()
+            .filter(|entry| !entry.in_progress)
+            .map(|entry| entry.next_update)
+            .min()
+            .map(|next_update| (next_update - now).max(Duration::zero()))
+    }
+}
+
+/// How long to wait after the given number of consecutive failures, doubling
+/// the interval each time up to a maximum.
+fn backoff(interval: Duration, consecutive_failures: u32) -> Duration {
+    let max_backoff = Duration::minutes(MAX_BACKOFF_MINUTES);
+    if interval >= max_backoff {
+        return interval;
+    }
+
+    // Cap the exponent so that we don't overflow, by which point we'll have
+    // hit the maximum anyway.
+    let factor = 2i32.pow(consecutive_failures.min(16));
+
+    (interval * factor).min(max_backoff)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 6, 1).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn take_due_returns_new_calendars_most_overdue_first() {
+        let now = now();
+        let mut scheduler = Scheduler::new(Duration::minutes(5));
+        scheduler.set_calendars(vec![(1, None), (2, None), (3, None)], now);
+
+        scheduler.record_success(1, now - Duration::minutes(2));
+        scheduler.record_success(2, now - Duration::minutes(4));
+
+        // Calendar 3 is new so due now, while 1 and 2 are due in 3 and 1
+        // minutes.
+        assert_eq!(scheduler.take_due(now, 10), vec![3]);
+        assert_eq!(
+            scheduler.take_due(now + Duration::minutes(3), 10),
+            vec![2, 1]
+        );
+    }
+
+    #[test]
+    fn take_due_respects_limit_and_in_progress() {
+        let now = now();
+        let mut scheduler = Scheduler::new(Duration::minutes(5));
+        scheduler.set_calendars(vec![(1, None), (2, None), (3, None)], now);
+
+        assert_eq!(scheduler.take_due(now, 2), vec![1, 2]);
+
+        // The first two are in progress so aren't returned again.
+        assert_eq!(scheduler.take_due(now, 2), vec![3]);
+        assert_eq!(scheduler.take_due(now, 2), Vec::<i64>::new());
+
+        scheduler.record_success(1, now);
+        assert_eq!(scheduler.take_due(now + Duration::minutes(5), 2), vec![1]);
+    }
+
+    #[test]
+    fn record_failure_backs_off_up_to_cap() {
+        let now = now();
+        let mut scheduler = Scheduler::new(Duration::minutes(5));
+        scheduler.set_calendars(vec![(1, None)], now);
+
+        let backoffs: Vec<_> = (0..10)
+            .map(|_| {
+                scheduler.take_due(now + Duration::days(1), 1);
+                scheduler.record_failure(1, now).num_minutes()
+            })
+            .collect();
+
+        assert_eq!(backoffs, vec![10, 20, 40, 80, 160, 320, 360, 360, 360, 360]);
+
+        // A success resets the backoff.
+        scheduler.take_due(now + Duration::days(1), 1);
+        scheduler.record_success(1, now);
+        scheduler.take_due(now + Duration::days(1), 1);
+        assert_eq!(scheduler.record_failure(1, now), Duration::minutes(10));
+    }
+
+    #[test]
+    fn record_failure_keeps_long_intervals() {
+        let now = now();
+        let mut scheduler = Scheduler::new(Duration::minutes(5));
+        scheduler.set_calendars(vec![(1, Some(Duration::hours(12)))], now);
+
+        scheduler.take_due(now, 1);
+        assert_eq!(scheduler.record_failure(1, now), Duration::hours(12));
+
+        // Lots of failures don't overflow.
+        for _ in 0..100 {
+            scheduler.take_due(now + Duration::days(1), 1);
+            scheduler.record_failure(1, now);
+        }
+        assert_eq!(backoff(Duration::minutes(5), u32::MAX), Duration::hours(6));
+    }
+
+    #[test]
+    fn time_to_next_skips_in_progress() {
+        let now = now();
+        let mut scheduler = Scheduler::new(Duration::minutes(5));
+        assert_eq!(scheduler.time_to_next(now), None);
+
+        scheduler.set_calendars(vec![(1, None), (2, Some(Duration::minutes(30)))], now);
+        assert_eq!(scheduler.time_to_next(now), Some(Duration::zero()));
+
+        assert_eq!(scheduler.take_due(now, 10), vec![1, 2]);
+        assert_eq!(scheduler.time_to_next(now), None);
+
+        scheduler.record_success(2, now);
+        assert_eq!(
+            scheduler.time_to_next(now + Duration::minutes(10)),
+            Some(Duration::minutes(20))
+        );
+
+        // Overdue calendars are due now, rather than in the past.
+        scheduler.record_success(1, now);
+        assert_eq!(
+            scheduler.time_to_next(now + Duration::hours(1)),
+            Some(Duration::zero())
+        );
+    }
+}
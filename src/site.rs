@@ -2,14 +2,15 @@
 
 use actix_web::{
     cookie::{Cookie, SameSite},
-    error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound},
+    error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound},
     get,
     middleware::Logger,
     post,
-    web::{Data, Form, Path, Query},
+    web::{Bytes, Data, Form, Path, Query},
     HttpResponse, HttpServer, Responder,
 };
 use anyhow::Error;
+use chrono::{DateTime, NaiveTime, Utc};
 
 use itertools::Itertools;
 
@@ -19,7 +20,12 @@ use tracing_actix_web::TracingLogger;
 
 use crate::app::App;
 use crate::auth::AuthedUser;
-use crate::database::Reminder;
+use crate::calendar::discover_calendars;
+use crate::database::{
+    Calendar, CalendarKind, EventInstance, OutsideHours, Reminder, ReminderAnchor, ReminderOffset,
+    ReminderOverride, ReminderRule, RoomPolicy, MAX_POLL_INTERVAL_MINUTES,
+};
+use crate::rules::compile_summary_regex;
 
 /// Root handler.
 #[get("/")]
@@ -68,6 +74,30 @@ async fn assert_user_can_edit_reminder(
     }
 }
 
+/// Asserts that the reminder wasn't created from one of the event's alarms or
+/// a reminder rule, as those are kept in sync automatically.
+async fn assert_reminder_not_managed(
+    app: &App,
+    calendar_id: i64,
+    reminder_id: i64,
+) -> Result<(), actix_web::Error> {
+    let reminder = app
+        .database
+        .get_reminder_in_calendar(calendar_id, reminder_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    match reminder {
+        Some(reminder) if reminder.from_alarm => Err(ErrorBadRequest(
+            "Reminders from calendar alarms can't be changed, edit the alarm in your calendar instead",
+        )),
+        Some(reminder) if reminder.rule_id.is_some() => Err(ErrorBadRequest(
+            "Reminders from rules can't be changed, edit the rule instead",
+        )),
+        _ => Ok(()),
+    }
+}
+
 /// List all events in a calendar
 #[get("/events/{calendar_id}")]
 async fn list_events_calendar_html(
@@ -93,7 +123,12 @@ async fn list_events_calendar_html(
                 "summary": &event.summary,
                 "description": &event.description,
                 "location": &event.location,
+                "all_day": event.all_day,
                 "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec(),
+                "next_end_dates": instances.iter().map(|i| i.end.map(|e| e.to_rfc3339())).collect_vec(),
+                "next_cancelled": instances.iter().map(|i| i.cancelled).collect_vec(),
+                "join_url": instances.first().and_then(|i| i.join_url.as_ref()).or(event.join_url.as_ref()),
+                "duration_minutes": instances.first().and_then(EventInstance::duration).map(|d| d.num_minutes()),
             })
         }).collect_vec(),
         "calendar_id": calendar_id,
@@ -134,7 +169,12 @@ async fn list_events_html(
                 "summary": &event.summary,
                 "description": &event.description,
                 "location": &event.location,
+                "all_day": event.all_day,
                 "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec(),
+                "next_end_dates": instances.iter().map(|i| i.end.map(|e| e.to_rfc3339())).collect_vec(),
+                "next_cancelled": instances.iter().map(|i| i.cancelled).collect_vec(),
+                "join_url": instances.first().and_then(|i| i.join_url.as_ref()).or(event.join_url.as_ref()),
+                "duration_minutes": instances.first().and_then(EventInstance::duration).map(|d| d.num_minutes()),
             })
         }).collect_vec(),
     });
@@ -174,7 +214,12 @@ async fn list_events_wit_reminders_html(
                 "summary": &event.summary,
                 "description": &event.description,
                 "location": &event.location,
+                "all_day": event.all_day,
                 "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec(),
+                "next_end_dates": instances.iter().map(|i| i.end.map(|e| e.to_rfc3339())).collect_vec(),
+                "next_cancelled": instances.iter().map(|i| i.cancelled).collect_vec(),
+                "join_url": instances.first().and_then(|i| i.join_url.as_ref()).or(event.join_url.as_ref()),
+                "duration_minutes": instances.first().and_then(EventInstance::duration).map(|d| d.num_minutes()),
             })
         }).collect_vec(),
     });
@@ -206,8 +251,20 @@ async fn list_calendars_html(
         .await
         .map_err(ErrorInternalServerError)?;
 
+    let mut statuses = app
+        .database
+        .get_calendar_statuses_for_user(*user)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
     let context = json!({
-        "calendars": calendars,
+        "calendars": calendars
+            .into_iter()
+            .map(|calendar| {
+                let status = statuses.remove(&calendar.calendar_id);
+                json!({ "calendar": calendar, "status": status })
+            })
+            .collect_vec(),
     });
 
     let result = app
@@ -267,9 +324,15 @@ async fn new_reminder_html(
             "summary": &event.summary,
             "description": &event.description,
             "location": &event.location,
-            "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec()
+            "all_day": event.all_day,
+            "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec(),
+            "next_end_dates": instances.iter().map(|i| i.end.map(|e| e.to_rfc3339())).collect_vec(),
+            "next_cancelled": instances.iter().map(|i| i.cancelled).collect_vec(),
+            "join_url": instances.first().and_then(|i| i.join_url.as_ref()).or(event.join_url.as_ref()),
+            "duration_minutes": instances.first().and_then(EventInstance::duration).map(|d| d.num_minutes()),
         },
         "calendar_id": calendar_id,
+        "offsets": [],
         "default_template": crate::DEFAULT_TEMPLATE,
         "form_state": state,
     });
@@ -331,16 +394,28 @@ async fn get_reminder_html(
         return Err(actix_web::error::ErrorNotFound("Couldn't find reminder"));
     };
 
+    let offsets = app
+        .database
+        .get_reminder_offsets(reminder_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
     let context = json!({
         "event": {
             "event_id": &event.event_id,
             "summary": &event.summary,
             "description": &event.description,
             "location": &event.location,
-            "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec()
+            "all_day": event.all_day,
+            "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec(),
+            "next_end_dates": instances.iter().map(|i| i.end.map(|e| e.to_rfc3339())).collect_vec(),
+            "next_cancelled": instances.iter().map(|i| i.cancelled).collect_vec(),
+            "join_url": instances.first().and_then(|i| i.join_url.as_ref()).or(event.join_url.as_ref()),
+            "duration_minutes": instances.first().and_then(EventInstance::duration).map(|d| d.num_minutes()),
         },
         "calendar_id": calendar_id,
         "reminder": reminder,
+        "offsets": offsets,
         "default_template": crate::DEFAULT_TEMPLATE,
         "form_state": state,
     });
@@ -396,16 +471,53 @@ async fn get_event_html(
         .await
         .map_err(ErrorInternalServerError)?;
 
+    let ports = app
+        .database
+        .get_reminder_ports_to_event(calendar_id, &event_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let overrides = app
+        .database
+        .get_reminder_overrides(&reminders.iter().map(|r| r.reminder_id).collect_vec())
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let sends = app
+        .database
+        .get_reminder_sends(&reminders.iter().map(|r| r.reminder_id).collect_vec())
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let has_source = app
+        .database
+        .has_event_ics(*user, calendar_id, &event_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
     let context = json!({
         "event": {
             "event_id": &event.event_id,
             "summary": &event.summary,
             "description": &event.description,
             "location": &event.location,
-            "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec()
+            "all_day": event.all_day,
+            "next_dates": instances.iter().map(|i| i.date.to_rfc3339()).collect_vec(),
+            "next_end_dates": instances.iter().map(|i| i.end.map(|e| e.to_rfc3339())).collect_vec(),
+            "next_cancelled": instances.iter().map(|i| i.cancelled).collect_vec(),
+            "join_url": instances.first().and_then(|i| i.join_url.as_ref()).or(event.join_url.as_ref()),
+            "duration_minutes": instances.first().and_then(EventInstance::duration).map(|d| d.num_minutes()),
         },
         "calendar_id": calendar_id,
         "reminders": reminders,
+        "instances": instances.iter().map(|i| json!({
+            "date": i.date.to_rfc3339(),
+            "cancelled": i.cancelled,
+            "overrides": overrides.iter().filter(|o| o.instance == i.date).collect_vec(),
+        })).collect_vec(),
+        "ports": ports,
+        "sends": sends,
+        "has_source": has_source,
         "default_template": crate::DEFAULT_TEMPLATE,
         "form_state": state,
     });
@@ -425,6 +537,60 @@ async fn get_event_html(
     Ok(response)
 }
 
+/// Get the raw ICS of an event that the calendar server sent us.
+async fn get_owned_event_ics(
+    app: &App,
+    user: AuthedUser,
+    calendar_id: i64,
+    event_id: &str,
+) -> Result<String, actix_web::Error> {
+    assert_user_owns_calendar(app, user, calendar_id).await?;
+
+    app.database
+        .get_event_ics(calendar_id, event_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No source for event"))
+}
+
+/// View the raw ICS of an event.
+#[get("/event/{calendar_id}/{event_id}/source")]
+async fn get_event_source_html(
+    app: Data<App>,
+    path: Path<(i64, String)>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, event_id) = path.into_inner();
+
+    let ics = get_owned_event_ics(&app, user, calendar_id, &event_id).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("Content-Type", "text/plain; charset=utf-8"));
+    builder.insert_header(("X-Content-Type-Options", "nosniff"));
+    let response = builder.body(ics);
+
+    Ok(response)
+}
+
+/// Download the raw ICS of an event.
+#[get("/event/{calendar_id}/{event_id}/event.ics")]
+async fn download_event_ics(
+    app: Data<App>,
+    path: Path<(i64, String)>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, event_id) = path.into_inner();
+
+    let ics = get_owned_event_ics(&app, user, calendar_id, &event_id).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("Content-Type", "text/calendar; charset=utf-8"));
+    builder.insert_header(("Content-Disposition", "attachment; filename=\"event.ics\""));
+    let response = builder.body(ics);
+
+    Ok(response)
+}
+
 /// Delete a reminder
 #[post("/event/{calendar_id}/{event_id}/delete_reminder")]
 async fn delete_reminder_html(
@@ -442,6 +608,7 @@ async fn delete_reminder_html(
     };
 
     assert_user_can_edit_reminder(&app, user, reminder_id).await?;
+    assert_reminder_not_managed(&app, calendar_id, reminder_id).await?;
 
     app.database
         .delete_reminder_in_calendar(calendar_id, reminder_id)
@@ -458,6 +625,40 @@ async fn delete_reminder_html(
     Ok(response)
 }
 
+/// Form body for undoing the copying of reminders from a replaced event.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UndoPortForm {
+    pub from_event_id: String,
+}
+
+/// Undo copying reminders to an event from an event it replaced.
+#[post("/event/{calendar_id}/{event_id}/undo_port")]
+async fn undo_port_html(
+    app: Data<App>,
+    path: Path<(i64, String)>,
+    data: Form<UndoPortForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, event_id) = path.into_inner();
+
+    assert_user_owns_calendar(&app, user, calendar_id).await?;
+
+    app.database
+        .undo_reminder_ports(calendar_id, &data.from_event_id, &event_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header(("Location", format!("/event/{}/{}", calendar_id, event_id)));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
 /// Form body for updating/adding a reminder
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UpdateReminderForm {
@@ -465,8 +666,86 @@ pub struct UpdateReminderForm {
     pub use_default: Option<String>, // A checkbox, so `Some()` if checked, `None` if not.
     pub template: Option<String>,
     pub minutes_before: i64,
+    #[serde(default)]
+    pub anchor: ReminderAnchor,
     pub room: String,
     pub attendee_editable: Option<String>, // A checkbox, so `Some()` if checked, `None` if not.
+    pub time_of_day: Option<String>,       // A time input, so empty if not set.
+    pub days_before: Option<i64>,
+    #[serde(default)]
+    pub outside_hours: OutsideHours,
+    /// The additional times to send the reminder at. These are repeated
+    /// fields, so are filled in by [`parse_reminder_form`] rather than serde.
+    #[serde(skip)]
+    pub offsets: Vec<ReminderOffsetInput>,
+}
+
+/// An additional time to send a reminder at, from the reminder form.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReminderOffsetInput {
+    pub minutes_before: i64,
+    pub anchor: ReminderAnchor,
+    pub template: Option<String>, // Blank to use the reminder's template.
+}
+
+/// Parse the body of the reminder form.
+///
+/// Each additional time is sent as an `offset_minutes_before`,
+/// `offset_anchor` and `offset_template` field, in that order. Ones with the
+/// minutes left blank are ignored, so they can be removed by clearing them.
+fn parse_reminder_form(body: &[u8]) -> Result<UpdateReminderForm, actix_web::Error> {
+    let mut form: UpdateReminderForm =
+        serde_urlencoded::from_bytes(body).map_err(|_| ErrorBadRequest("Invalid reminder"))?;
+
+    let mut minutes = Vec::new();
+    let mut anchors = Vec::new();
+    let mut templates = Vec::new();
+    for (key, value) in url::form_urlencoded::parse(body) {
+        match key.as_ref() {
+            "offset_minutes_before" => minutes.push(value.into_owned()),
+            "offset_anchor" => anchors.push(value.into_owned()),
+            "offset_template" => templates.push(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    if minutes.len() != anchors.len() || minutes.len() != templates.len() {
+        return Err(ErrorBadRequest("Invalid additional times"));
+    }
+
+    for ((minutes_before, anchor), template) in minutes.into_iter().zip(anchors).zip(templates) {
+        let minutes_before = match parse_text_input(Some(minutes_before)) {
+            Some(minutes_before) => minutes_before
+                .parse()
+                .map_err(|_| ErrorBadRequest("Invalid minutes before"))?,
+            None => continue,
+        };
+
+        let anchor = match anchor.as_str() {
+            "start" => ReminderAnchor::Start,
+            "end" => ReminderAnchor::End,
+            _ => return Err(ErrorBadRequest("Invalid anchor")),
+        };
+
+        form.offsets.push(ReminderOffsetInput {
+            minutes_before,
+            anchor,
+            template: parse_text_input(Some(template)),
+        });
+    }
+
+    Ok(form)
+}
+
+/// Parse the `HH:MM` value of a time input, treating an empty value as unset.
+fn parse_time_input(value: Option<&str>) -> Result<Option<NaiveTime>, actix_web::Error> {
+    match value {
+        None | Some("") => Ok(None),
+        Some(value) => NaiveTime::parse_from_str(value, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+            .map(Some)
+            .map_err(|_| ErrorBadRequest("Invalid time")),
+    }
 }
 
 /// Add or update a reminder.
@@ -474,47 +753,152 @@ pub struct UpdateReminderForm {
 async fn upsert_reminder_html(
     app: Data<App>,
     path: Path<(i64, String)>,
-    data: Form<UpdateReminderForm>,
+    body: Bytes,
     user: AuthedUser,
 ) -> Result<impl Responder, actix_web::Error> {
     let (calendar_id, event_id) = path.into_inner();
 
-    let data = data.into_inner();
+    let data = parse_reminder_form(&body)?;
 
     let template = if data.use_default.is_some() {
         None
     } else {
-        data.template.as_deref()
+        data.template
+    };
+
+    let reminder = Reminder {
+        reminder_id: data.reminder_id.unwrap_or(-1), // If we're inserting we use a fake ID
+        user_id: *user,
+        calendar_id,
+        event_id: event_id.clone(),
+        room: data.room,
+        minutes_before: data.minutes_before,
+        anchor: data.anchor,
+        template,
+        attendee_editable: data.attendee_editable.is_some(),
+        time_of_day: parse_time_input(data.time_of_day.as_deref())?,
+        days_before: data.days_before.unwrap_or(0),
+        from_alarm: false,
+        rule_id: None,
+        outside_hours: data.outside_hours,
     };
 
-    if let Some(reminder_id) = data.reminder_id {
+    // The reminder's own time doesn't need to be sent again, and we only
+    // keep the last of any duplicates.
+    let mut offsets: Vec<ReminderOffsetInput> = Vec::with_capacity(data.offsets.len());
+    for offset in data.offsets {
+        if offset.minutes_before == reminder.minutes_before && offset.anchor == reminder.anchor {
+            continue;
+        }
+
+        offsets.retain(|o| o.minutes_before != offset.minutes_before || o.anchor != offset.anchor);
+        offsets.push(offset);
+    }
+
+    let reminder_id = if let Some(reminder_id) = data.reminder_id {
         assert_user_can_edit_reminder(&app, user, reminder_id).await?;
+        assert_reminder_not_managed(&app, calendar_id, reminder_id).await?;
 
         app.database
-            .update_reminder(
-                calendar_id,
-                reminder_id,
-                &data.room,
-                data.minutes_before,
-                template,
-                data.attendee_editable.is_some(),
-            )
+            .update_reminder(&reminder)
             .await
             .map_err(ErrorInternalServerError)?;
+
+        reminder_id
     } else {
         assert_user_owns_calendar(&app, user, calendar_id).await?;
 
         app.database
-            .add_reminder(Reminder {
-                reminder_id: -1, // We're inserting so we use a fake ID
-                user_id: *user,
-                calendar_id,
-                event_id: event_id.clone(),
-                room: data.room,
-                minutes_before: data.minutes_before,
-                template: template.map(ToOwned::to_owned),
-                attendee_editable: data.attendee_editable.is_some(),
-            })
+            .add_reminder(reminder)
+            .await
+            .map_err(ErrorInternalServerError)?
+    };
+
+    let offsets = offsets
+        .into_iter()
+        .map(|offset| ReminderOffset {
+            reminder_id,
+            minutes_before: offset.minutes_before,
+            anchor: offset.anchor,
+            template: offset.template,
+        })
+        .collect_vec();
+
+    app.database
+        .set_reminder_offsets(reminder_id, &offsets)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header((
+        "Location",
+        format!("/event/{}/{}?state=saved", calendar_id, event_id),
+    ));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
+/// Form body for overriding/resetting a reminder for a single instance.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpdateReminderOverrideForm {
+    pub instance: String,     // The RFC 3339 start of the instance.
+    pub skip: Option<String>, // A checkbox, so `Some()` if checked, `None` if not.
+    pub room: Option<String>,
+    pub minutes_before: Option<String>, // A number input, so empty if not set.
+}
+
+/// Parse the instance a reminder override is for.
+fn parse_instance_input(value: &str) -> Result<DateTime<Utc>, actix_web::Error> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|instance| instance.with_timezone(&Utc))
+        .map_err(|_| ErrorBadRequest("Invalid instance"))
+}
+
+/// Skip or change a reminder for a single instance of its event.
+#[post("/event/{calendar_id}/{event_id}/reminder/{reminder_id}/override")]
+async fn upsert_reminder_override_html(
+    app: Data<App>,
+    path: Path<(i64, String, i64)>,
+    data: Form<UpdateReminderOverrideForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, event_id, reminder_id) = path.into_inner();
+
+    assert_user_can_edit_reminder(&app, user, reminder_id).await?;
+
+    let data = data.into_inner();
+
+    let instance = parse_instance_input(&data.instance)?;
+    let minutes_before = parse_text_input(data.minutes_before)
+        .map(|value| value.parse())
+        .transpose()
+        .map_err(|_| ErrorBadRequest("Invalid minutes before"))?;
+
+    let reminder_override = ReminderOverride {
+        reminder_id,
+        instance,
+        skip: data.skip.is_some(),
+        room: parse_text_input(data.room),
+        minutes_before,
+    };
+
+    // An override that doesn't change anything is the same as none.
+    if !reminder_override.skip
+        && reminder_override.room.is_none()
+        && reminder_override.minutes_before.is_none()
+    {
+        app.database
+            .delete_reminder_override(reminder_id, instance)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    } else {
+        app.database
+            .upsert_reminder_override(&reminder_override)
             .await
             .map_err(ErrorInternalServerError)?;
     }
@@ -533,6 +917,96 @@ async fn upsert_reminder_html(
     Ok(response)
 }
 
+/// Go back to sending a reminder as normal for a single instance.
+#[post("/event/{calendar_id}/{event_id}/reminder/{reminder_id}/delete_override")]
+async fn delete_reminder_override_html(
+    app: Data<App>,
+    path: Path<(i64, String, i64)>,
+    data: Form<UpdateReminderOverrideForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, event_id, reminder_id) = path.into_inner();
+
+    assert_user_can_edit_reminder(&app, user, reminder_id).await?;
+
+    let instance = parse_instance_input(&data.instance)?;
+
+    app.database
+        .delete_reminder_override(reminder_id, instance)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header((
+        "Location",
+        format!("/event/{}/{}?state=saved", calendar_id, event_id),
+    ));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
+/// Form body for resolving a reminder send that was interrupted.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResolveSendForm {
+    pub instance: String, // The RFC 3339 start of the instance.
+    pub anchor: ReminderAnchor,
+    pub minutes_before: i64,
+    pub is_primary: bool,
+    pub sent: String, // Which button was pressed, either "Yes" or "No".
+}
+
+/// Record whether a reminder send that was interrupted actually went out.
+#[post("/event/{calendar_id}/{event_id}/reminder/{reminder_id}/resolve_send")]
+async fn resolve_send_html(
+    app: Data<App>,
+    path: Path<(i64, String, i64)>,
+    data: Form<ResolveSendForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, event_id, reminder_id) = path.into_inner();
+
+    assert_user_can_edit_reminder(&app, user, reminder_id).await?;
+
+    let data = data.into_inner();
+
+    let instance = parse_instance_input(&data.instance)?;
+    let sent = match data.sent.as_str() {
+        "Yes" => true,
+        "No" => false,
+        _ => return Err(ErrorBadRequest("Invalid sent")),
+    };
+
+    app.database
+        .resolve_interrupted_send(
+            reminder_id,
+            instance,
+            data.anchor,
+            data.minutes_before,
+            data.is_primary,
+            sent,
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header((
+        "Location",
+        format!("/event/{}/{}?state=saved", calendar_id, event_id),
+    ));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
 /// Get calendar info
 #[get("/calendar/{calendar_id}")]
 async fn get_calendar_html(
@@ -549,8 +1023,22 @@ async fn get_calendar_html(
         .await
         .map_err(ErrorInternalServerError)?;
 
+    let status = app
+        .database
+        .get_calendar_status(calendar_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let rules = app
+        .database
+        .get_reminder_rules(calendar_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
     let context = json!({
         "calendar": calendar,
+        "status": status,
+        "rules": rules,
     });
 
     let result = app
@@ -595,9 +1083,46 @@ async fn new_calendar_html(
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UpdateCalendarForm {
     pub name: String,
+    #[serde(default)]
+    pub kind: CalendarKind,
     pub url: String,
     pub user_name: Option<String>,
     pub password: Option<String>,
+    pub timezone: Option<String>,
+    pub look_ahead_days: Option<String>, // A number input, so empty if not set.
+    pub poll_interval_minutes: Option<String>, // A number input, so empty if not set.
+    pub mirror_alarms: Option<String>,   // A checkbox, so `Some()` if checked, `None` if not.
+    pub alarm_room: Option<String>,
+    #[serde(default)]
+    pub alarm_outside_hours: OutsideHours,
+    pub dedup_same_time_of_day: Option<String>, // A checkbox
+    pub dedup_same_weekday: Option<String>,     // A checkbox
+}
+
+/// Check the timezone given in a form is valid, treating an empty value as
+/// unset.
+fn parse_timezone_input(timezone: Option<String>) -> Result<Option<String>, actix_web::Error> {
+    match timezone.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(tz) if tz.parse::<chrono_tz::Tz>().is_ok() => Ok(Some(tz.to_string())),
+        Some(_) => Err(ErrorBadRequest("Unknown timezone")),
+    }
+}
+
+/// Parse a number between 1 and `max` given in a form, treating an empty
+/// value as unset.
+fn parse_positive_input(
+    value: Option<String>,
+    max: i64,
+    error: &'static str,
+) -> Result<Option<i64>, actix_web::Error> {
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => match value.parse() {
+            Ok(value) if value > 0 && value <= max => Ok(Some(value)),
+            _ => Err(ErrorBadRequest(error)),
+        },
+    }
 }
 
 /// Edit a calendar's config.
@@ -621,9 +1146,18 @@ async fn edit_calendar_html(
 
     let UpdateCalendarForm {
         name,
+        kind,
         url,
         mut user_name,
         mut password,
+        timezone,
+        look_ahead_days,
+        poll_interval_minutes,
+        mirror_alarms,
+        mut alarm_room,
+        alarm_outside_hours,
+        dedup_same_time_of_day,
+        dedup_same_weekday,
     } = data.into_inner();
 
     if user_name.as_deref() == Some("") {
@@ -632,6 +1166,12 @@ async fn edit_calendar_html(
     if password.as_deref() == Some("") {
         password = None;
     }
+    if alarm_room.as_deref().map(str::trim) == Some("") {
+        alarm_room = None;
+    }
+    if mirror_alarms.is_some() && alarm_room.is_none() {
+        return Err(ErrorBadRequest("A room is needed to mirror alarms to"));
+    }
 
     // Awful hack to keep password unchanged if left blank, but still using
     // basic auth.
@@ -640,7 +1180,27 @@ async fn edit_calendar_html(
     }
 
     app.database
-        .update_calendar(calendar_id, name, url, user_name, password)
+        .update_calendar(&Calendar {
+            user_id: existing_calendar.user_id,
+            calendar_id,
+            name,
+            kind,
+            url,
+            user_name,
+            password,
+            timezone: parse_timezone_input(timezone)?,
+            look_ahead_days: parse_positive_input(look_ahead_days, 3650, "Invalid look ahead")?,
+            poll_interval_minutes: parse_positive_input(
+                poll_interval_minutes,
+                MAX_POLL_INTERVAL_MINUTES,
+                "Invalid poll interval",
+            )?,
+            mirror_alarms: mirror_alarms.is_some(),
+            alarm_room,
+            alarm_outside_hours,
+            dedup_same_time_of_day: dedup_same_time_of_day.is_some(),
+            dedup_same_weekday: dedup_same_weekday.is_some(),
+        })
         .await
         .map_err(ErrorInternalServerError)?;
 
@@ -694,9 +1254,18 @@ async fn add_new_calendar_html(
 ) -> Result<impl Responder, actix_web::Error> {
     let UpdateCalendarForm {
         name,
+        kind,
         url,
         mut user_name,
         mut password,
+        timezone,
+        look_ahead_days,
+        poll_interval_minutes,
+        mirror_alarms,
+        mut alarm_room,
+        alarm_outside_hours,
+        dedup_same_time_of_day,
+        dedup_same_weekday,
     } = data.into_inner();
 
     if user_name.as_deref() == Some("") {
@@ -705,10 +1274,36 @@ async fn add_new_calendar_html(
     if password.as_deref() == Some("") {
         password = None;
     }
+    if alarm_room.as_deref().map(str::trim) == Some("") {
+        alarm_room = None;
+    }
+    if mirror_alarms.is_some() && alarm_room.is_none() {
+        return Err(ErrorBadRequest("A room is needed to mirror alarms to"));
+    }
 
     let calendar_id = app
         .database
-        .add_calendar(*user, name, url, user_name, password)
+        .add_calendar(&Calendar {
+            user_id: *user,
+            calendar_id: -1, // We're inserting so we use a fake ID
+            name,
+            kind,
+            url,
+            user_name,
+            password,
+            timezone: parse_timezone_input(timezone)?,
+            look_ahead_days: parse_positive_input(look_ahead_days, 3650, "Invalid look ahead")?,
+            poll_interval_minutes: parse_positive_input(
+                poll_interval_minutes,
+                MAX_POLL_INTERVAL_MINUTES,
+                "Invalid poll interval",
+            )?,
+            mirror_alarms: mirror_alarms.is_some(),
+            alarm_room,
+            alarm_outside_hours,
+            dedup_same_time_of_day: dedup_same_time_of_day.is_some(),
+            dedup_same_weekday: dedup_same_weekday.is_some(),
+        })
         .await
         .map_err(ErrorInternalServerError)?;
 
@@ -730,13 +1325,411 @@ async fn add_new_calendar_html(
     Ok(response)
 }
 
-/// Login page
-#[get("/login")]
-async fn login_get_html(app: Data<App>) -> Result<impl Responder, actix_web::Error> {
-    let sso_name = app.config.sso.as_ref().map(|s| &s.display_name);
-    let context = json!({ "sso_name": sso_name });
-
-    let result = app
+/// Form body for updating/adding a reminder rule.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpdateRuleForm {
+    pub rule_id: Option<i64>,
+    pub summary_regex: Option<String>,
+    pub organizer: Option<String>,
+    pub attendee: Option<String>,
+    pub category: Option<String>,
+    pub location: Option<String>,
+    pub room: String,
+    pub minutes_before: i64,
+    #[serde(default)]
+    pub anchor: ReminderAnchor,
+    pub use_default: Option<String>, // A checkbox, so `Some()` if checked, `None` if not.
+    pub template: Option<String>,
+    #[serde(default)]
+    pub outside_hours: OutsideHours,
+}
+
+/// Treat an empty or blank text input as unset.
+fn parse_text_input(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Render the page for a new or existing reminder rule.
+async fn render_rule_page(
+    app: &App,
+    calendar_id: i64,
+    rule: Option<ReminderRule>,
+    state: Option<&str>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let matched_events = match &rule {
+        Some(rule) => app
+            .database
+            .get_events_matching_rule(calendar_id, rule.rule_id)
+            .await
+            .map_err(ErrorInternalServerError)?,
+        None => Vec::new(),
+    };
+
+    let context = json!({
+        "calendar_id": calendar_id,
+        "rule": rule,
+        "matched_events": matched_events.iter().map(|(event_id, summary)| {
+            json!({
+                "event_id": event_id,
+                "summary": summary,
+            })
+        }).collect_vec(),
+        "default_template": crate::DEFAULT_TEMPLATE,
+        "form_state": state,
+    });
+
+    let result = app
+        .templates
+        .render(
+            "rule.html.j2",
+            &tera::Context::from_serialize(&context).map_err(ErrorInternalServerError)?,
+        )
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("Content-Type", "text/html; charset=utf-8"));
+    let response = builder.body(result);
+
+    Ok(response)
+}
+
+/// Add a new reminder rule page.
+#[get("/calendar/{calendar_id}/rule/new")]
+async fn new_rule_html(
+    app: Data<App>,
+    path: Path<(i64,)>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id,) = path.into_inner();
+
+    assert_user_owns_calendar(&app, user, calendar_id).await?;
+
+    render_rule_page(&app, calendar_id, None, None).await
+}
+
+/// Get a reminder rule, along with the events it matched.
+#[get("/calendar/{calendar_id}/rule/{rule_id}")]
+async fn get_rule_html(
+    app: Data<App>,
+    path: Path<(i64, i64)>,
+    query: Query<EventFormState>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id, rule_id) = path.into_inner();
+
+    assert_user_owns_calendar(&app, user, calendar_id).await?;
+
+    let state = match query.into_inner().state.as_deref() {
+        Some("saved") => Some("saved"),
+        _ => None,
+    };
+
+    let rule = app
+        .database
+        .get_reminder_rule(calendar_id, rule_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("Couldn't find rule"))?;
+
+    render_rule_page(&app, calendar_id, Some(rule), state).await
+}
+
+/// Add or update a reminder rule, and apply it to the calendar's events.
+#[post("/calendar/{calendar_id}/rule")]
+async fn upsert_rule_html(
+    app: Data<App>,
+    path: Path<(i64,)>,
+    data: Form<UpdateRuleForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id,) = path.into_inner();
+
+    assert_user_owns_calendar(&app, user, calendar_id).await?;
+
+    let data = data.into_inner();
+
+    let summary_regex = parse_text_input(data.summary_regex);
+    if let Some(summary_regex) = &summary_regex {
+        compile_summary_regex(summary_regex)
+            .map_err(|_| ErrorBadRequest("Invalid summary regex"))?;
+    }
+
+    let template = if data.use_default.is_some() {
+        None
+    } else {
+        data.template
+    };
+
+    let mut rule = ReminderRule {
+        rule_id: data.rule_id.unwrap_or(-1), // If we're inserting we use a fake ID
+        user_id: *user,
+        calendar_id,
+        summary_regex,
+        organizer: parse_text_input(data.organizer),
+        attendee: parse_text_input(data.attendee),
+        category: parse_text_input(data.category),
+        location: parse_text_input(data.location),
+        room: data.room,
+        minutes_before: data.minutes_before,
+        anchor: data.anchor,
+        template,
+        outside_hours: data.outside_hours,
+    };
+
+    // A rule without any criteria would match every event in the calendar.
+    if rule.summary_regex.is_none()
+        && rule.organizer.is_none()
+        && rule.attendee.is_none()
+        && rule.category.is_none()
+        && rule.location.is_none()
+    {
+        return Err(ErrorBadRequest("A rule needs at least one criterion"));
+    }
+
+    if data.rule_id.is_some() {
+        app.database
+            .update_reminder_rule(&rule)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    } else {
+        rule.rule_id = app
+            .database
+            .add_reminder_rule(&rule)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+
+    // Apply the rule to the events we already have, rather than waiting for
+    // the calendar to next be updated.
+    let calendar = app
+        .database
+        .get_calendar(calendar_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("No such calendar"))?;
+
+    app.apply_reminder_rules(&calendar)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header((
+        "Location",
+        format!(
+            "/calendar/{}/rule/{}?state=saved",
+            calendar_id, rule.rule_id
+        ),
+    ));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
+/// Delete a reminder rule, along with its reminders.
+#[post("/calendar/{calendar_id}/delete_rule")]
+async fn delete_rule_html(
+    app: Data<App>,
+    path: Path<(i64,)>,
+    data: Form<UpdateRuleForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let (calendar_id,) = path.into_inner();
+
+    assert_user_owns_calendar(&app, user, calendar_id).await?;
+
+    let rule_id = data
+        .rule_id
+        .ok_or_else(|| ErrorNotFound("Couldn't find rule"))?;
+
+    app.database
+        .delete_reminder_rule(calendar_id, rule_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header(("Location", format!("/calendar/{}", calendar_id)));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
+/// Discover calendars on a CalDAV server page.
+#[get("/calendar/discover")]
+async fn discover_calendars_html(
+    app: Data<App>,
+    _user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let context = json!({});
+
+    let result = app
+        .templates
+        .render(
+            "discover.html.j2",
+            &tera::Context::from_serialize(&context).map_err(ErrorInternalServerError)?,
+        )
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("Content-Type", "text/html; charset=utf-8"));
+    let response = builder.body(result);
+
+    Ok(response)
+}
+
+/// Form body for discovering calendars on a CalDAV server.
+#[derive(Debug, Clone, Deserialize)]
+struct DiscoverCalendarsForm {
+    url: String,
+    user_name: Option<String>,
+    password: Option<String>,
+}
+
+/// List the calendars available on a CalDAV server.
+#[post("/calendar/discover")]
+async fn discover_calendars_post_html(
+    app: Data<App>,
+    data: Form<DiscoverCalendarsForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let DiscoverCalendarsForm {
+        url,
+        mut user_name,
+        mut password,
+    } = data.into_inner();
+
+    if user_name.as_deref() == Some("") {
+        user_name = None;
+    }
+    if password.as_deref() == Some("") {
+        password = None;
+    }
+
+    let context = match discover_calendars(&url, user_name.as_deref(), password.as_deref()).await {
+        Ok(calendars) => {
+            // The credentials are kept server side until the user picks which
+            // calendars to add, rather than being sent back in the form.
+            let session = app
+                .add_discovery_session(*user, user_name.as_deref(), password.as_deref())
+                .await
+                .map_err(ErrorInternalServerError)?;
+
+            json!({
+                "url": url,
+                "user_name": user_name,
+                "session": session,
+                "calendars": calendars,
+            })
+        }
+        Err(error) => json!({
+            "url": url,
+            "user_name": user_name,
+            "error": error.to_string(),
+        }),
+    };
+
+    let result = app
+        .templates
+        .render(
+            "discover.html.j2",
+            &tera::Context::from_serialize(&context).map_err(ErrorInternalServerError)?,
+        )
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("Content-Type", "text/html; charset=utf-8"));
+    let response = builder.body(result);
+
+    Ok(response)
+}
+
+/// Add the calendars picked from the list of discovered calendars.
+///
+/// The form has a `session` field with the token of the discovery session, and
+/// a `selected` field for each picked calendar, with the index of the
+/// calendar's `url_<index>` and `name_<index>` fields.
+#[post("/calendar/discover/add")]
+async fn add_discovered_calendars_html(
+    app: Data<App>,
+    data: Form<Vec<(String, String)>>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let fields = data.into_inner();
+
+    let get_field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .filter(|value| !value.is_empty())
+    };
+
+    let session = get_field("session").ok_or_else(|| ErrorBadRequest("Missing session"))?;
+    let (user_name, password) = app
+        .database
+        .claim_discovery_session(&session, *user)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorBadRequest("Discovery session has expired"))?;
+
+    for (_, selected) in fields.iter().filter(|(key, _)| key == "selected") {
+        let url = get_field(&format!("url_{}", selected))
+            .ok_or_else(|| ErrorBadRequest("Missing calendar URL"))?;
+        let name = get_field(&format!("name_{}", selected)).unwrap_or_else(|| url.clone());
+
+        let calendar_id = app
+            .database
+            .add_calendar(&Calendar {
+                user_id: *user,
+                calendar_id: -1, // We're inserting so we use a fake ID
+                name,
+                kind: CalendarKind::CalDav,
+                url,
+                user_name: user_name.clone(),
+                password: password.clone(),
+                timezone: None,
+                look_ahead_days: None,
+                poll_interval_minutes: None,
+                mirror_alarms: false,
+                alarm_room: None,
+                alarm_outside_hours: OutsideHours::Send,
+                dedup_same_time_of_day: false,
+                dedup_same_weekday: false,
+            })
+            .await
+            .map_err(ErrorInternalServerError)?;
+
+        let new_calendar = app
+            .database
+            .get_calendar(calendar_id)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .ok_or_else(|| ErrorNotFound("No such calendar"))?;
+
+        app.update_calendar(new_calendar)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header(("Location", "/calendars"));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
+/// Login page
+#[get("/login")]
+async fn login_get_html(app: Data<App>) -> Result<impl Responder, actix_web::Error> {
+    let sso_name = app.config.sso.as_ref().map(|s| &s.display_name);
+    let context = json!({ "sso_name": sso_name });
+
+    let result = app
         .templates
         .render(
             "login.html.j2",
@@ -795,6 +1788,164 @@ async fn login_post_html(
     Ok(response)
 }
 
+/// List the working hours of the rooms the user sends reminders to.
+#[get("/room_policies")]
+async fn room_policies_html(
+    app: Data<App>,
+    user: AuthedUser,
+    query: Query<EventFormState>,
+) -> Result<impl Responder, actix_web::Error> {
+    let state = match query.into_inner().state.as_deref() {
+        Some("saved") => Some("saved"),
+        Some("deleted") => Some("deleted"),
+        _ => None,
+    };
+
+    let policies = app
+        .database
+        .get_room_policies(*user)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let context = json!({
+        "policies": policies,
+        "user_id": *user,
+        "form_state": state,
+    });
+
+    let result = app
+        .templates
+        .render(
+            "room_policies.html.j2",
+            &tera::Context::from_serialize(&context).map_err(ErrorInternalServerError)?,
+        )
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(("Content-Type", "text/html; charset=utf-8"));
+    let response = builder.body(result);
+
+    Ok(response)
+}
+
+/// Form body for adding/updating the working hours of a room.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpdateRoomPolicyForm {
+    pub room: String,
+    pub timezone: Option<String>,
+    pub start_time: Option<String>, // A time input, so empty if not set.
+    pub end_time: Option<String>,   // A time input, so empty if not set.
+    // Checkboxes, so `Some()` if checked, `None` if not.
+    pub monday: Option<String>,
+    pub tuesday: Option<String>,
+    pub wednesday: Option<String>,
+    pub thursday: Option<String>,
+    pub friday: Option<String>,
+    pub saturday: Option<String>,
+    pub sunday: Option<String>,
+}
+
+/// Add or update the working hours of a room.
+#[post("/room_policy")]
+async fn upsert_room_policy_html(
+    app: Data<App>,
+    data: Form<UpdateRoomPolicyForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let data = data.into_inner();
+
+    let room = parse_text_input(Some(data.room)).ok_or_else(|| ErrorBadRequest("Missing room"))?;
+    let timezone = parse_timezone_input(data.timezone)?.unwrap_or_else(|| "UTC".to_string());
+    let start_time = parse_time_input(data.start_time.as_deref())?
+        .ok_or_else(|| ErrorBadRequest("Missing start time"))?;
+    let end_time = parse_time_input(data.end_time.as_deref())?
+        .ok_or_else(|| ErrorBadRequest("Missing end time"))?;
+
+    if end_time <= start_time {
+        return Err(ErrorBadRequest("Working hours must end after they start"));
+    }
+
+    let days = [
+        data.monday,
+        data.tuesday,
+        data.wednesday,
+        data.thursday,
+        data.friday,
+        data.saturday,
+        data.sunday,
+    ];
+    let working_days = (1..)
+        .zip(&days)
+        .filter(|(_, checked)| checked.is_some())
+        .map(|(day, _)| day)
+        .collect();
+
+    let updated = app
+        .database
+        .upsert_room_policy(&RoomPolicy {
+            room,
+            user_id: *user,
+            timezone,
+            start_time,
+            end_time,
+            working_days,
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    if !updated {
+        return Err(ErrorForbidden(
+            "The room's working hours were set by another user",
+        ));
+    }
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header(("Location", "/room_policies?state=saved"));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
+/// Form body for deleting the working hours of a room.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeleteRoomPolicyForm {
+    pub room: String,
+}
+
+/// Delete the working hours of a room.
+#[post("/room_policy/delete")]
+async fn delete_room_policy_html(
+    app: Data<App>,
+    data: Form<DeleteRoomPolicyForm>,
+    user: AuthedUser,
+) -> Result<impl Responder, actix_web::Error> {
+    let deleted = app
+        .database
+        .delete_room_policy(*user, &data.room)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    if !deleted {
+        return Err(ErrorForbidden(
+            "The room's working hours were set by another user",
+        ));
+    }
+
+    app.update_reminders()
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut builder = HttpResponse::SeeOther();
+    builder.insert_header(("Location", "/room_policies?state=deleted"));
+    let response = builder.finish();
+
+    Ok(response)
+}
+
 /// Change password page
 #[get("/change_password")]
 async fn change_password_html(
@@ -951,14 +2102,30 @@ pub async fn run_server(app: App) -> Result<(), Error> {
             .service(new_reminder_html)
             .service(get_reminder_html)
             .service(get_event_html)
+            .service(get_event_source_html)
+            .service(download_event_ics)
             .service(delete_reminder_html)
+            .service(undo_port_html)
             .service(upsert_reminder_html)
+            .service(upsert_reminder_override_html)
+            .service(delete_reminder_override_html)
+            .service(resolve_send_html)
+            .service(room_policies_html)
+            .service(upsert_room_policy_html)
+            .service(delete_room_policy_html)
             .service(list_calendars_html)
             .service(new_calendar_html)
             .service(add_new_calendar_html)
+            .service(discover_calendars_html)
+            .service(discover_calendars_post_html)
+            .service(add_discovered_calendars_html)
             .service(get_calendar_html)
             .service(edit_calendar_html)
             .service(delete_calendar_html)
+            .service(new_rule_html)
+            .service(get_rule_html)
+            .service(upsert_rule_html)
+            .service(delete_rule_html)
             .service(login_get_html)
             .service(login_post_html)
             .service(change_password_html)
@@ -972,3 +2139,40 @@ pub async fn run_server(app: App) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reminder_form_with_offsets() {
+        let body = "minutes_before=1440&anchor=start&room=%23room%3Aexample.com\
+            &offset_minutes_before=10&offset_anchor=start&offset_template=\
+            &offset_minutes_before=-15&offset_anchor=end&offset_template=Wrap+up%21\
+            &offset_minutes_before=&offset_anchor=start&offset_template=";
+
+        let form = parse_reminder_form(body.as_bytes()).unwrap();
+
+        assert_eq!(form.minutes_before, 1440);
+        assert_eq!(form.room, "#room:example.com");
+        assert_eq!(form.offsets.len(), 2);
+
+        assert_eq!(form.offsets[0].minutes_before, 10);
+        assert_eq!(form.offsets[0].anchor, ReminderAnchor::Start);
+        assert_eq!(form.offsets[0].template, None);
+
+        assert_eq!(form.offsets[1].minutes_before, -15);
+        assert_eq!(form.offsets[1].anchor, ReminderAnchor::End);
+        assert_eq!(form.offsets[1].template.as_deref(), Some("Wrap up!"));
+    }
+
+    #[test]
+    fn parse_reminder_form_rejects_mismatched_offsets() {
+        let body = "minutes_before=10&room=r&offset_minutes_before=5&offset_template=";
+        assert!(parse_reminder_form(body.as_bytes()).is_err());
+
+        let body = "minutes_before=10&room=r\
+            &offset_minutes_before=five&offset_anchor=start&offset_template=";
+        assert!(parse_reminder_form(body.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+//! The backends that calendars can be fetched from.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Context, Error};
+use chrono::Duration;
+use chrono_tz::Tz;
+use futures::{Future, FutureExt};
+use tracing::info;
+
+use crate::{
+    calendar::{fetch_ics_feed, sync_calendar, ParsedCalendar, SyncChanges},
+    database::{Calendar, CalendarResource, Database},
+    graph::GraphClient,
+};
+
+/// What we know about a calendar from previous fetches.
+#[derive(Debug, Clone, Copy)]
+pub struct FetchContext<'a> {
+    pub calendar: &'a Calendar,
+    /// The sync token returned by the last fetch, if any.
+    pub sync_token: Option<&'a str>,
+    /// The resources we currently have stored for the calendar.
+    pub previous: &'a [CalendarResource],
+    /// The timezone to use for all-day and floating events.
+    pub timezone: Tz,
+    /// How far ahead we're interested in events.
+    pub look_ahead: Duration,
+    /// How long after they end we're still interested in events.
+    pub keep_after: Duration,
+}
+
+/// What a source returns when fetching a calendar.
+#[derive(Debug, Clone)]
+pub enum Fetched {
+    /// The ICS resources of the calendar that have changed since we last
+    /// fetched it, which get stored and then parsed.
+    Resources(SyncChanges),
+    /// The upcoming events in the calendar, for sources that don't use ICS.
+    Events(ParsedCalendar),
+}
+
+/// A backend that calendars can be fetched from.
+pub trait CalendarSource {
+    /// Fetch the latest version of the calendar.
+    fn fetch<'a>(
+        &'a self,
+        context: FetchContext<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>>;
+}
+
+/// A CalDAV collection.
+#[derive(Debug, Clone)]
+pub struct CalDavSource {
+    pub http_client: reqwest::Client,
+}
+
+impl CalendarSource for CalDavSource {
+    fn fetch<'a>(
+        &'a self,
+        context: FetchContext<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>> {
+        async move {
+            let changes = sync_calendar(
+                &self.http_client,
+                &context.calendar.url,
+                context.calendar.user_name.as_deref(),
+                context.calendar.password.as_deref(),
+                context.sync_token,
+                context.look_ahead,
+                context.keep_after,
+            )
+            .await?;
+
+            Ok(Fetched::Resources(changes))
+        }
+        .boxed_local()
+    }
+}
+
+/// A plain ICS feed.
+#[derive(Debug, Clone)]
+pub struct IcsFeedSource {
+    pub http_client: reqwest::Client,
+}
+
+impl CalendarSource for IcsFeedSource {
+    fn fetch<'a>(
+        &'a self,
+        context: FetchContext<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>> {
+        async move {
+            let calendar = context.calendar;
+
+            let feed = fetch_ics_feed(
+                &self.http_client,
+                &calendar.url,
+                calendar.user_name.as_deref(),
+                calendar.password.as_deref(),
+                context.previous.iter().find(|r| r.href == calendar.url),
+            )
+            .await?;
+
+            if let Some(feed) = feed {
+                Ok(Fetched::Resources(SyncChanges {
+                    full: true,
+                    changed: vec![feed],
+                    ..Default::default()
+                }))
+            } else {
+                info!(
+                    calendar_id = calendar.calendar_id,
+                    "ICS feed has not been modified"
+                );
+
+                Ok(Fetched::Resources(SyncChanges::default()))
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// An ICS file, or a directory of them, on the local file system.
+///
+/// The calendar's URL is a path relative to `root`, and may not point outside
+/// of it.
+#[derive(Debug, Clone)]
+pub struct FileSource {
+    pub root: PathBuf,
+}
+
+impl FileSource {
+    /// Resolve the calendar's path, checking that it is inside our root.
+    async fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+        let root = tokio::fs::canonicalize(&self.root)
+            .await
+            .with_context(|| "Finding calendar directory")?;
+
+        let full_path = tokio::fs::canonicalize(root.join(path.trim_start_matches('/')))
+            .await
+            .with_context(|| format!("Finding calendar file {}", path))?;
+
+        if !full_path.starts_with(&root) {
+            bail!(
+                "Calendar file {} is outside of the calendar directory",
+                path
+            );
+        }
+
+        Ok(full_path)
+    }
+}
+
+impl CalendarSource for FileSource {
+    fn fetch<'a>(
+        &'a self,
+        context: FetchContext<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>> {
+        async move {
+            let path = self.resolve(&context.calendar.url).await?;
+
+            let mut files = Vec::new();
+            if tokio::fs::metadata(&path).await?.is_dir() {
+                let mut entries = tokio::fs::read_dir(&path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let file_path = entry.path();
+                    if file_path.extension() == Some(OsStr::new("ics")) {
+                        files.push(file_path);
+                    }
+                }
+            } else {
+                files.push(path);
+            }
+
+            let mut resources = Vec::with_capacity(files.len());
+            for file_path in files {
+                resources.push(read_ics_file(&file_path).await?);
+            }
+
+            Ok(Fetched::Resources(SyncChanges {
+                full: true,
+                changed: resources,
+                ..Default::default()
+            }))
+        }
+        .boxed_local()
+    }
+}
+
+/// Read an ICS file into a resource, using its modification time and size as
+/// the ETag.
+async fn read_ics_file(file_path: &Path) -> Result<CalendarResource, Error> {
+    let metadata = tokio::fs::metadata(file_path).await?;
+    let calendar_data = tokio::fs::read_to_string(file_path)
+        .await
+        .with_context(|| format!("Reading {}", file_path.display()))?;
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos());
+
+    Ok(CalendarResource {
+        href: file_path.to_string_lossy().into_owned(),
+        etag: modified.map(|m| format!("{}-{}", m, metadata.len())),
+        last_modified: None,
+        calendar_data,
+    })
+}
+
+/// A Microsoft 365 / Exchange Online calendar.
+#[derive(Debug, Clone)]
+pub struct GraphSource {
+    pub client: GraphClient,
+    pub database: Database,
+}
+
+impl CalendarSource for GraphSource {
+    fn fetch<'a>(
+        &'a self,
+        context: FetchContext<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>> {
+        async move {
+            let owner_email = self
+                .database
+                .get_user_email(context.calendar.user_id)
+                .await?
+                .context("Unknown calendar owner")?;
+
+            let parsed = self
+                .client
+                .fetch_events(
+                    context.calendar,
+                    &owner_email,
+                    context.timezone,
+                    context.look_ahead,
+                    context.keep_after,
+                )
+                .await?;
+
+            Ok(Fetched::Events(parsed))
+        }
+        .boxed_local()
+    }
+}
+
+/// A calendar that can't be fetched as its backend isn't set up.
+#[derive(Debug, Clone)]
+pub struct DisabledSource {
+    pub reason: &'static str,
+}
+
+impl CalendarSource for DisabledSource {
+    fn fetch<'a>(
+        &'a self,
+        _context: FetchContext<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>> {
+        let reason = self.reason;
+        async move { bail!("{}", reason) }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{CalendarKind, OutsideHours};
+
+    use super::*;
+
+    /// A source that returns a fixed set of resources, for tests.
+    #[derive(Debug, Clone, Default)]
+    struct MemorySource {
+        resources: Vec<CalendarResource>,
+    }
+
+    impl CalendarSource for MemorySource {
+        fn fetch<'a>(
+            &'a self,
+            context: FetchContext<'a>,
+        ) -> Pin<Box<dyn Future<Output = Result<Fetched, Error>> + 'a>> {
+            async move {
+                // Only return what has changed since the previous fetch.
+                let changed = self
+                    .resources
+                    .iter()
+                    .filter(|resource| {
+                        !context.previous.iter().any(|previous| {
+                            previous.href == resource.href && previous.etag == resource.etag
+                        })
+                    })
+                    .cloned()
+                    .collect();
+
+                let deleted = context
+                    .previous
+                    .iter()
+                    .filter(|previous| !self.resources.iter().any(|r| r.href == previous.href))
+                    .map(|previous| previous.href.clone())
+                    .collect();
+
+                Ok(Fetched::Resources(SyncChanges {
+                    full: false,
+                    changed,
+                    deleted,
+                    sync_token: None,
+                }))
+            }
+            .boxed_local()
+        }
+    }
+
+    fn calendar(kind: CalendarKind, url: &str) -> Calendar {
+        Calendar {
+            user_id: 1,
+            calendar_id: 1,
+            name: "Test".to_string(),
+            kind,
+            url: url.to_string(),
+            user_name: None,
+            password: None,
+            timezone: None,
+            look_ahead_days: None,
+            poll_interval_minutes: None,
+            mirror_alarms: false,
+            alarm_room: None,
+            alarm_outside_hours: OutsideHours::Send,
+            dedup_same_time_of_day: false,
+            dedup_same_weekday: false,
+        }
+    }
+
+    fn resource(href: &str, etag: &str) -> CalendarResource {
+        CalendarResource {
+            href: href.to_string(),
+            etag: Some(etag.to_string()),
+            last_modified: None,
+            calendar_data: "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n".to_string(),
+        }
+    }
+
+    async fn fetch_resources(
+        source: &dyn CalendarSource,
+        calendar: &Calendar,
+        previous: &[CalendarResource],
+    ) -> Result<SyncChanges, Error> {
+        let fetched = source
+            .fetch(FetchContext {
+                calendar,
+                sync_token: None,
+                previous,
+                timezone: Tz::UTC,
+                look_ahead: Duration::days(30),
+                keep_after: Duration::zero(),
+            })
+            .await?;
+
+        match fetched {
+            Fetched::Resources(changes) => Ok(changes),
+            Fetched::Events(_) => bail!("Expected resources"),
+        }
+    }
+
+    /// Create an empty directory for a test to put calendar files in.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("calendar_bot_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn memory_source_returns_changes() {
+        let calendar = calendar(CalendarKind::CalDav, "memory");
+        let previous = vec![resource("a.ics", "1"), resource("b.ics", "1")];
+        let source = MemorySource {
+            resources: vec![
+                resource("a.ics", "1"),
+                resource("b.ics", "2"),
+                resource("c.ics", "1"),
+            ],
+        };
+
+        let changes = fetch_resources(&source, &calendar, &previous)
+            .await
+            .unwrap();
+
+        let changed: Vec<_> = changes.changed.iter().map(|r| r.href.as_str()).collect();
+        assert_eq!(changed, vec!["b.ics", "c.ics"]);
+        assert!(changes.deleted.is_empty());
+
+        let source = MemorySource::default();
+        let changes = fetch_resources(&source, &calendar, &previous)
+            .await
+            .unwrap();
+        assert!(changes.changed.is_empty());
+        assert_eq!(changes.deleted, vec!["a.ics", "b.ics"]);
+    }
+
+    #[tokio::test]
+    async fn file_source_reads_ics_files_in_directory() {
+        let root = test_dir("directory");
+        std::fs::create_dir(root.join("work")).unwrap();
+        std::fs::write(
+            root.join("work/a.ics"),
+            "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("work/notes.txt"), "not a calendar").unwrap();
+
+        let source = FileSource { root: root.clone() };
+        let changes = fetch_resources(&source, &calendar(CalendarKind::File, "work"), &[])
+            .await
+            .unwrap();
+
+        assert!(changes.full);
+        assert_eq!(changes.changed.len(), 1);
+        assert!(changes.changed[0].href.ends_with("a.ics"));
+        assert!(changes.changed[0].etag.is_some());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_source_rejects_paths_outside_root() {
+        let root = test_dir("outside");
+        std::fs::create_dir(root.join("calendars")).unwrap();
+        std::fs::write(
+            root.join("secret.ics"),
+            "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n",
+        )
+        .unwrap();
+
+        let source = FileSource {
+            root: root.join("calendars"),
+        };
+        let result =
+            fetch_resources(&source, &calendar(CalendarKind::File, "../secret.ics"), &[]).await;
+
+        assert!(result.is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}